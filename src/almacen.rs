@@ -0,0 +1,985 @@
+use rocket::serde::json::Value;
+use rocket::serde::Deserialize;
+use rocket::Config;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use rocket::tokio::runtime::Handle;
+use rocket::tokio::task::block_in_place;
+
+use rusqlite::types::Value as Celda;
+use rusqlite::{params, params_from_iter, Connection, OptionalExtension, Transaction};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use super::documentos::Documento;
+use super::esquema;
+use super::id::Id;
+use super::papelera::Eliminado;
+use super::sesion::Sesión;
+use super::usuarios::Usuario;
+use super::versiones::Versión;
+use super::webhooks::Suscripción;
+
+/*
+ * Almacenamiento persistente de documentos, usuarios, sesiones, suscripciones, papelera y versiones
+ */
+
+// Cualquier forma de guardar los datos debe implementar este rasgo. Los métodos "carga_*"
+// devuelven None cuando todavía no hay datos guardados.
+pub trait Almacén: Send + Sync {
+    fn carga_documentos(&self) -> Result<Option<Vec<Documento>>, String>;
+    fn guarda_documentos(&self, documentos: &[Documento]) -> Result<(), String>;
+    fn carga_usuarios(&self) -> Result<Option<Vec<Usuario>>, String>;
+    fn guarda_usuarios(&self, usuarios: &[Usuario]) -> Result<(), String>;
+    fn carga_sesiones(&self) -> Result<Option<HashMap<String, Sesión>>, String>;
+    fn guarda_sesiones(&self, sesiones: &HashMap<String, Sesión>) -> Result<(), String>;
+    fn carga_contadores(&self) -> Result<Option<HashMap<String, Id>>, String>;
+    fn guarda_contadores(&self, contadores: &HashMap<String, Id>) -> Result<(), String>;
+    fn carga_suscripciones(&self) -> Result<Option<Vec<Suscripción>>, String>;
+    fn guarda_suscripciones(&self, suscripciones: &[Suscripción]) -> Result<(), String>;
+    fn carga_papelera(&self) -> Result<Option<Vec<Eliminado>>, String>;
+    fn guarda_papelera(&self, papelera: &[Eliminado]) -> Result<(), String>;
+    fn carga_versiones(&self) -> Result<Option<Vec<Versión>>, String>;
+    fn guarda_versiones(&self, versiones: &[Versión]) -> Result<(), String>;
+
+    // Huella del contenido guardado ahora mismo de una colección ("documentos" o "usuarios").
+    // Es None si todavía no hay nada guardado.
+    fn huella(&self, colección: &str) -> Result<Option<String>, String>;
+    // Huellas de lo último que el servidor ha leído o escrito
+    fn huellas(&self) -> &Huellas;
+
+    // Versión del formato de cada colección guardada, o None si todavía no hay datos
+    fn versiones(&self) -> Result<Vec<(String, Option<u32>)>, String>;
+    // Lleva los datos guardados a la versión actual del formato, después de copiar los
+    // originales
+    fn migra_esquema(&self) -> Result<(), String>;
+
+    // Apunto la huella actual de una colección, después de leerla o escribirla
+    fn anota_huella(&self, colección: &str) -> Result<(), String> {
+        let huella = self.huella(colección)?;
+        self.huellas().anota(colección, huella);
+        Ok(())
+    }
+
+    // Indica si alguien ha cambiado la colección fuera del servidor desde la última vez que
+    // la leí o escribí
+    fn cambiado_fuera(&self, colección: &str) -> Result<bool, String> {
+        match self.huellas().conocida(colección) {
+            Some(conocida) => Ok(conocida != self.huella(colección)?),
+            None => Ok(false),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Huellas {
+    conocidas: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Huellas {
+    pub fn anota(&self, colección: &str, huella: Option<String>) {
+        self.conocidas
+            .lock()
+            .unwrap()
+            .insert(colección.to_string(), huella);
+    }
+
+    pub fn conocida(&self, colección: &str) -> Option<Option<String>> {
+        self.conocidas.lock().unwrap().get(colección).cloned()
+    }
+}
+
+// Colecciones que guarda el almacén
+pub const COLECCIONES: &[&str] = &[
+    "documentos",
+    "usuarios",
+    "sesiones",
+    "contadores",
+    "suscripciones",
+    "papelera",
+    "versiones",
+];
+
+pub fn calcula_huella(contenido: &[u8]) -> String {
+    let mut sha = Sha256::new();
+    sha.input(contenido);
+    sha.result_str()
+}
+
+// Guardaré el almacén elegido en la configuración como estado de Rocket
+pub type Almacenamiento = Box<dyn Almacén>;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigAlmacén {
+    // "json" o "sqlite"
+    pub tipo: String,
+    // Carpeta de los archivos JSON
+    pub carpeta: Option<String>,
+    // Archivo de la base de datos SQLite
+    pub ruta: Option<String>,
+    // Versiones anteriores de cada archivo JSON que conservo
+    pub generaciones: Option<usize>,
+}
+
+// Interpreta el contenido de un archivo JSON, migrándolo a la versión actual si es antiguo
+fn interpreta<T: rocket::serde::de::DeserializeOwned>(
+    archivo: &str,
+    contenido: &str,
+) -> Result<T, String> {
+    let colección = archivo.trim_end_matches(".json");
+    let (versión, datos) =
+        esquema::desenvuelve(serde_json::from_str::<Value>(contenido).map_err(|e| e.to_string())?);
+    let datos = esquema::migra(colección, versión, datos)?;
+    serde_json::from_value::<T>(datos).map_err(|e| e.to_string())
+}
+
+/*
+ * Almacén en archivos JSON: "documentos.json", "usuarios.json", "sesiones.json" y
+ * "contadores.json"
+ */
+
+// Número de versiones anteriores de cada archivo que conservo por defecto
+pub const GENERACIONES_POR_DEFECTO: usize = 3;
+
+pub struct AlmacénJson {
+    carpeta: PathBuf,
+    generaciones: usize,
+    huellas: Huellas,
+}
+
+impl AlmacénJson {
+    pub fn nuevo(carpeta: &str, generaciones: usize) -> AlmacénJson {
+        AlmacénJson {
+            carpeta: PathBuf::from(carpeta),
+            generaciones,
+            huellas: Huellas::default(),
+        }
+    }
+
+    // Ruta de una generación anterior: "documentos.json.1" es la más reciente
+    fn generación(&self, archivo: &str, n: usize) -> PathBuf {
+        self.carpeta.join(format!("{}.{}", archivo, n))
+    }
+
+    // Leo el archivo principal y, si falta o está dañado, la generación anterior más
+    // reciente que pueda interpretar.
+    fn lee<T: rocket::serde::de::DeserializeOwned>(
+        &self,
+        archivo: &str,
+    ) -> Result<Option<T>, String> {
+        let candidatos: Vec<PathBuf> = std::iter::once(self.carpeta.join(archivo))
+            .chain((1..=self.generaciones).map(|n| self.generación(archivo, n)))
+            .collect();
+        let mut errores: Vec<String> = vec![];
+
+        for ruta in candidatos.iter() {
+            let contenido = match std::fs::read_to_string(ruta) {
+                Ok(contenido) => contenido,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    errores.push(format!("{}: {}", ruta.display(), e));
+                    continue;
+                }
+            };
+            match interpreta::<T>(archivo, &contenido) {
+                Ok(datos) => {
+                    if !errores.is_empty() {
+                        println!(
+                            "He recuperado los datos de {} porque no he podido leer: {}",
+                            ruta.display(),
+                            errores.join("; ")
+                        );
+                    }
+                    return Ok(Some(datos));
+                }
+                Err(e) => errores.push(format!("{}: {}", ruta.display(), e)),
+            }
+        }
+
+        if errores.is_empty() {
+            // No hay ningún archivo: todavía no se ha guardado nada
+            Ok(None)
+        } else {
+            Err(errores.join("; "))
+        }
+    }
+
+    // Escribo primero en un archivo temporal, lo vuelco al disco y sólo entonces lo renombro
+    // sobre el original, así que un corte a mitad de escritura nunca deja un archivo truncado.
+    // Antes de sustituirlo, el archivo actual pasa a ser la generación 1.
+    fn escribe<T: rocket::serde::Serialize + ?Sized>(
+        &self,
+        archivo: &str,
+        datos: &T,
+    ) -> Result<(), String> {
+        let error = |e: std::io::Error| format!("{}: {}", archivo, e);
+        let datos = serde_json::to_value(datos).map_err(|e| e.to_string())?;
+        let j: String =
+            serde_json::to_string_pretty(&esquema::envuelve(datos)).map_err(|e| e.to_string())?;
+        let ruta = self.carpeta.join(archivo);
+        let temporal = self.carpeta.join(format!("{}.tmp", archivo));
+
+        let mut f = File::create(&temporal).map_err(error)?;
+        f.write_all(j.as_bytes()).map_err(error)?;
+        f.sync_all().map_err(error)?;
+        drop(f);
+
+        if self.generaciones > 0 && ruta.exists() {
+            for n in (1..self.generaciones).rev() {
+                let origen = self.generación(archivo, n);
+                if origen.exists() {
+                    std::fs::rename(&origen, self.generación(archivo, n + 1)).map_err(error)?;
+                }
+            }
+            // Un enlace duro conserva el contenido actual sin copiarlo
+            let primera = self.generación(archivo, 1);
+            if primera.exists() {
+                std::fs::remove_file(&primera).map_err(error)?;
+            }
+            std::fs::hard_link(&ruta, &primera)
+                .or_else(|_| std::fs::copy(&ruta, &primera).map(|_| ()))
+                .map_err(error)?;
+        }
+
+        std::fs::rename(&temporal, &ruta).map_err(error)?;
+
+        // Vuelco también la carpeta, para que el cambio de nombre sobreviva a un corte
+        if let Ok(carpeta) = File::open(&self.carpeta) {
+            let _ = carpeta.sync_all();
+        }
+        Ok(())
+    }
+}
+
+impl Almacén for AlmacénJson {
+    fn carga_documentos(&self) -> Result<Option<Vec<Documento>>, String> {
+        self.lee("documentos.json")
+    }
+
+    fn guarda_documentos(&self, documentos: &[Documento]) -> Result<(), String> {
+        self.escribe("documentos.json", documentos)
+    }
+
+    fn carga_usuarios(&self) -> Result<Option<Vec<Usuario>>, String> {
+        self.lee("usuarios.json")
+    }
+
+    fn guarda_usuarios(&self, usuarios: &[Usuario]) -> Result<(), String> {
+        self.escribe("usuarios.json", usuarios)
+    }
+
+    fn carga_sesiones(&self) -> Result<Option<HashMap<String, Sesión>>, String> {
+        self.lee("sesiones.json")
+    }
+
+    fn guarda_sesiones(&self, sesiones: &HashMap<String, Sesión>) -> Result<(), String> {
+        self.escribe("sesiones.json", sesiones)
+    }
+
+    fn carga_contadores(&self) -> Result<Option<HashMap<String, Id>>, String> {
+        self.lee("contadores.json")
+    }
+
+    fn guarda_contadores(&self, contadores: &HashMap<String, Id>) -> Result<(), String> {
+        self.escribe("contadores.json", contadores)
+    }
+
+    fn carga_suscripciones(&self) -> Result<Option<Vec<Suscripción>>, String> {
+        self.lee("suscripciones.json")
+    }
+
+    fn guarda_suscripciones(&self, suscripciones: &[Suscripción]) -> Result<(), String> {
+        self.escribe("suscripciones.json", suscripciones)
+    }
+
+    fn carga_papelera(&self) -> Result<Option<Vec<Eliminado>>, String> {
+        self.lee("papelera.json")
+    }
+
+    fn guarda_papelera(&self, papelera: &[Eliminado]) -> Result<(), String> {
+        self.escribe("papelera.json", papelera)
+    }
+
+    fn carga_versiones(&self) -> Result<Option<Vec<Versión>>, String> {
+        self.lee("versiones.json")
+    }
+
+    fn guarda_versiones(&self, versiones: &[Versión]) -> Result<(), String> {
+        self.escribe("versiones.json", versiones)
+    }
+
+    fn huella(&self, colección: &str) -> Result<Option<String>, String> {
+        let archivo = format!("{}.json", colección);
+        match std::fs::read(self.carpeta.join(&archivo)) {
+            Ok(contenido) => Ok(Some(calcula_huella(&contenido))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("{}: {}", archivo, e)),
+        }
+    }
+
+    fn huellas(&self) -> &Huellas {
+        &self.huellas
+    }
+
+    fn versiones(&self) -> Result<Vec<(String, Option<u32>)>, String> {
+        COLECCIONES
+            .iter()
+            .map(|colección| {
+                let archivo = format!("{}.json", colección);
+                match std::fs::read_to_string(self.carpeta.join(&archivo)) {
+                    Ok(contenido) => serde_json::from_str::<Value>(&contenido)
+                        .map(|v| (colección.to_string(), Some(esquema::desenvuelve(v).0)))
+                        .map_err(|e| format!("{}: {}", archivo, e)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Ok((colección.to_string(), None))
+                    }
+                    Err(e) => Err(format!("{}: {}", archivo, e)),
+                }
+            })
+            .collect()
+    }
+
+    fn migra_esquema(&self) -> Result<(), String> {
+        for (colección, versión) in self.versiones()? {
+            let versión = match versión {
+                Some(v) if v != esquema::VERSIÓN_ACTUAL => v,
+                _ => continue,
+            };
+            let archivo = format!("{}.json", colección);
+            let ruta = self.carpeta.join(&archivo);
+            let contenido = std::fs::read_to_string(&ruta).map_err(|e| e.to_string())?;
+            let (_, datos) = esquema::desenvuelve(
+                serde_json::from_str::<Value>(&contenido).map_err(|e| e.to_string())?,
+            );
+            let datos = esquema::migra(&colección, versión, datos)?;
+
+            // Guardo el archivo original, por si la migración sale mal
+            let copia = self.carpeta.join(format!("{}.v{}", archivo, versión));
+            std::fs::copy(&ruta, &copia).map_err(|e| format!("{}: {}", archivo, e))?;
+            self.escribe(&archivo, &datos)?;
+            println!(
+                "He migrado {} de la versión {} a la {}. El original está en {}",
+                archivo,
+                versión,
+                esquema::VERSIÓN_ACTUAL,
+                copia.display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/*
+ * Almacén en una base de datos SQLite embebida
+ */
+
+// Cada fila guarda el registro completo como JSON en "datos", y copia en columnas propias los
+// campos por los que busco, para poder indexarlos.
+const ESQUEMA_SQLITE: &str = "
+    CREATE TABLE IF NOT EXISTS documentos (
+        id INTEGER PRIMARY KEY,
+        padre INTEGER NOT NULL,
+        orden INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS documentos_padre ON documentos (padre);
+    CREATE TABLE IF NOT EXISTS usuarios (
+        id INTEGER PRIMARY KEY,
+        nombre TEXT NOT NULL,
+        correo TEXT NOT NULL,
+        orden INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS usuarios_nombre ON usuarios (nombre);
+    CREATE INDEX IF NOT EXISTS usuarios_correo ON usuarios (correo);
+    CREATE TABLE IF NOT EXISTS sesiones (
+        simbolo TEXT PRIMARY KEY,
+        usuario INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS sesiones_usuario ON sesiones (usuario);
+    CREATE TABLE IF NOT EXISTS contadores (
+        coleccion TEXT PRIMARY KEY,
+        siguiente INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS suscripciones (
+        id INTEGER PRIMARY KEY,
+        orden INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS papelera (
+        id INTEGER PRIMARY KEY,
+        orden INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS versiones (
+        nombre TEXT PRIMARY KEY,
+        orden INTEGER NOT NULL,
+        datos TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS esquema (
+        coleccion TEXT PRIMARY KEY,
+        version INTEGER NOT NULL
+    );
+";
+
+// Los datos que se escriben siempre tienen el formato actual
+fn anota_versión(conexión: &Connection, colección: &str) -> Result<(), String> {
+    conexión
+        .execute(
+            "INSERT INTO esquema (coleccion, version) VALUES (?1, ?2)
+             ON CONFLICT (coleccion) DO UPDATE SET version = excluded.version",
+            params![colección, esquema::VERSIÓN_ACTUAL],
+        )
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Las escrituras bloquean el hilo mientras esperan al disco. Dentro del servidor, que Rocket
+// siempre ejecuta en varios hilos, aviso a tokio para que mueva las demás tareas a otros hilos
+// mientras tanto. Las órdenes de la línea de comandos no tienen entorno asíncrono.
+fn sin_bloquear_el_servidor<T>(trabajo: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(_) => block_in_place(trabajo),
+        Err(_) => trabajo(),
+    }
+}
+
+fn texto_de_clave(clave: &Celda) -> String {
+    match clave {
+        Celda::Integer(n) => n.to_string(),
+        Celda::Text(t) => t.clone(),
+        otra => format!("{:?}", otra),
+    }
+}
+
+// Deja en la tabla exactamente las filas indicadas, escribiendo sólo las que cambian: inserta
+// las nuevas, actualiza las distintas y borra las que ya no están. La primera columna es la
+// clave primaria.
+fn sincroniza(
+    transacción: &Transaction,
+    tabla: &str,
+    columnas: &[&str],
+    filas: Vec<Vec<Celda>>,
+) -> Result<(), String> {
+    let error = |e: rusqlite::Error| format!("{}: {}", tabla, e);
+
+    let mut actuales: HashMap<String, Vec<Celda>> = HashMap::new();
+    let mut sentencia = transacción
+        .prepare(&format!("SELECT {} FROM {}", columnas.join(", "), tabla))
+        .map_err(error)?;
+    let mut cursor = sentencia.query([]).map_err(error)?;
+    while let Some(fila) = cursor.next().map_err(error)? {
+        let celdas = (0..columnas.len())
+            .map(|i| fila.get::<_, Celda>(i))
+            .collect::<Result<Vec<Celda>, _>>()
+            .map_err(error)?;
+        actuales.insert(texto_de_clave(&celdas[0]), celdas);
+    }
+
+    let mut escribe = transacción
+        .prepare(&format!(
+            "INSERT INTO {} ({}) VALUES ({}) ON CONFLICT ({}) DO UPDATE SET {}",
+            tabla,
+            columnas.join(", "),
+            (1..=columnas.len())
+                .map(|i| format!("?{}", i))
+                .collect::<Vec<String>>()
+                .join(", "),
+            columnas[0],
+            columnas[1..]
+                .iter()
+                .map(|c| format!("{} = excluded.{}", c, c))
+                .collect::<Vec<String>>()
+                .join(", ")
+        ))
+        .map_err(error)?;
+    for fila in filas {
+        match actuales.remove(&texto_de_clave(&fila[0])) {
+            Some(actual) if actual == fila => {}
+            _ => {
+                escribe
+                    .execute(params_from_iter(fila.iter()))
+                    .map_err(error)?;
+            }
+        }
+    }
+
+    let mut borra = transacción
+        .prepare(&format!("DELETE FROM {} WHERE {} = ?1", tabla, columnas[0]))
+        .map_err(error)?;
+    for (_, actual) in actuales {
+        borra.execute([&actual[0]]).map_err(error)?;
+    }
+    Ok(())
+}
+
+// Registro completo de una fila, en la columna "datos"
+fn datos<T: rocket::serde::Serialize>(registro: &T) -> Celda {
+    Celda::Text(serde_json::to_string(registro).unwrap())
+}
+
+pub struct AlmacénSqlite {
+    ruta: String,
+    conexión: Mutex<Connection>,
+    huellas: Huellas,
+}
+
+impl AlmacénSqlite {
+    pub fn nuevo(ruta: &str) -> Result<AlmacénSqlite, String> {
+        let conexión = Connection::open(ruta).map_err(|e| e.to_string())?;
+        conexión
+            .execute_batch(ESQUEMA_SQLITE)
+            .map_err(|e| e.to_string())?;
+        Ok(AlmacénSqlite {
+            ruta: ruta.to_string(),
+            conexión: Mutex::new(conexión),
+            huellas: Huellas::default(),
+        })
+    }
+
+    // Guarda una colección dentro de una transacción: o se guarda todo, o no se guarda nada
+    fn escribe(
+        &self,
+        tabla: &str,
+        columnas: &[&str],
+        filas: Vec<Vec<Celda>>,
+    ) -> Result<(), String> {
+        sin_bloquear_el_servidor(|| {
+            let mut conexión = self.conexión.lock().unwrap();
+            let transacción = conexión.transaction().map_err(|e| e.to_string())?;
+            sincroniza(&transacción, tabla, columnas, filas)?;
+            anota_versión(&transacción, tabla)?;
+            transacción.commit().map_err(|e| e.to_string())
+        })
+    }
+
+    // Lee la columna "datos" de todas las filas de una tabla, en orden
+    fn lee_tabla<T: rocket::serde::de::DeserializeOwned>(
+        &self,
+        consulta: &str,
+    ) -> Result<Option<Vec<T>>, String> {
+        let conexión = self.conexión.lock().unwrap();
+        let mut sentencia = conexión.prepare(consulta).map_err(|e| e.to_string())?;
+        let filas: Vec<String> = sentencia
+            .query_map([], |fila| fila.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        if filas.is_empty() {
+            return Ok(None);
+        }
+        filas
+            .iter()
+            .map(|datos| serde_json::from_str::<T>(datos).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<T>, String>>()
+            .map(Some)
+    }
+}
+
+impl Almacén for AlmacénSqlite {
+    fn carga_documentos(&self) -> Result<Option<Vec<Documento>>, String> {
+        self.lee_tabla("SELECT datos FROM documentos ORDER BY orden")
+    }
+
+    fn guarda_documentos(&self, documentos: &[Documento]) -> Result<(), String> {
+        let filas = documentos
+            .iter()
+            .enumerate()
+            .map(|(orden, doc)| {
+                vec![
+                    Celda::Integer(doc.id as i64),
+                    Celda::Integer(doc.padre as i64),
+                    Celda::Integer(orden as i64),
+                    datos(doc),
+                ]
+            })
+            .collect();
+        self.escribe("documentos", &["id", "padre", "orden", "datos"], filas)
+    }
+
+    fn carga_usuarios(&self) -> Result<Option<Vec<Usuario>>, String> {
+        self.lee_tabla("SELECT datos FROM usuarios ORDER BY orden")
+    }
+
+    fn guarda_usuarios(&self, usuarios: &[Usuario]) -> Result<(), String> {
+        let filas = usuarios
+            .iter()
+            .enumerate()
+            .map(|(orden, usu)| {
+                vec![
+                    Celda::Integer(usu.id as i64),
+                    Celda::Text(usu.nombre.clone()),
+                    Celda::Text(usu.correo.clone()),
+                    Celda::Integer(orden as i64),
+                    datos(usu),
+                ]
+            })
+            .collect();
+        self.escribe(
+            "usuarios",
+            &["id", "nombre", "correo", "orden", "datos"],
+            filas,
+        )
+    }
+
+    fn carga_sesiones(&self) -> Result<Option<HashMap<String, Sesión>>, String> {
+        let conexión = self.conexión.lock().unwrap();
+        let hay_sesiones: Option<i64> = conexión
+            .query_row("SELECT 1 FROM sesiones LIMIT 1", [], |fila| fila.get(0))
+            .optional()
+            .map_err(|e| e.to_string())?;
+        if hay_sesiones.is_none() {
+            return Ok(None);
+        }
+        let mut sentencia = conexión
+            .prepare("SELECT simbolo, datos FROM sesiones")
+            .map_err(|e| e.to_string())?;
+        let filas: Vec<(String, String)> = sentencia
+            .query_map([], |fila| Ok((fila.get(0)?, fila.get(1)?)))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<(String, String)>, _>>()
+            .map_err(|e| e.to_string())?;
+        filas
+            .into_iter()
+            .map(|(símbolo, datos)| {
+                serde_json::from_str::<Sesión>(&datos)
+                    .map(|sesión| (símbolo, sesión))
+                    .map_err(|e| e.to_string())
+            })
+            .collect::<Result<HashMap<String, Sesión>, String>>()
+            .map(Some)
+    }
+
+    fn guarda_sesiones(&self, sesiones: &HashMap<String, Sesión>) -> Result<(), String> {
+        let filas = sesiones
+            .iter()
+            .map(|(símbolo, sesión)| {
+                vec![
+                    Celda::Text(símbolo.clone()),
+                    Celda::Integer(sesión.usuario as i64),
+                    datos(sesión),
+                ]
+            })
+            .collect();
+        self.escribe("sesiones", &["simbolo", "usuario", "datos"], filas)
+    }
+
+    fn carga_contadores(&self) -> Result<Option<HashMap<String, Id>>, String> {
+        let conexión = self.conexión.lock().unwrap();
+        let mut sentencia = conexión
+            .prepare("SELECT coleccion, siguiente FROM contadores")
+            .map_err(|e| e.to_string())?;
+        let contadores: HashMap<String, Id> = sentencia
+            .query_map([], |fila| {
+                Ok((fila.get::<_, String>(0)?, fila.get::<_, i64>(1)? as Id))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<HashMap<String, Id>, _>>()
+            .map_err(|e| e.to_string())?;
+        if contadores.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(contadores))
+    }
+
+    fn guarda_contadores(&self, contadores: &HashMap<String, Id>) -> Result<(), String> {
+        sin_bloquear_el_servidor(|| {
+            let mut conexión = self.conexión.lock().unwrap();
+            let transacción = conexión.transaction().map_err(|e| e.to_string())?;
+            for (colección, siguiente) in contadores.iter() {
+                transacción
+                    .execute(
+                        "INSERT INTO contadores (coleccion, siguiente) VALUES (?1, ?2)
+                         ON CONFLICT (coleccion) DO UPDATE SET siguiente = excluded.siguiente",
+                        params![colección, *siguiente as i64],
+                    )
+                    .map_err(|e| e.to_string())?;
+            }
+            anota_versión(&transacción, "contadores")?;
+            transacción.commit().map_err(|e| e.to_string())
+        })
+    }
+
+    fn carga_suscripciones(&self) -> Result<Option<Vec<Suscripción>>, String> {
+        self.lee_tabla("SELECT datos FROM suscripciones ORDER BY orden")
+    }
+
+    fn guarda_suscripciones(&self, suscripciones: &[Suscripción]) -> Result<(), String> {
+        let filas = suscripciones
+            .iter()
+            .enumerate()
+            .map(|(orden, suscripción)| {
+                vec![
+                    Celda::Integer(suscripción.id as i64),
+                    Celda::Integer(orden as i64),
+                    datos(suscripción),
+                ]
+            })
+            .collect();
+        self.escribe("suscripciones", &["id", "orden", "datos"], filas)
+    }
+
+    fn carga_papelera(&self) -> Result<Option<Vec<Eliminado>>, String> {
+        self.lee_tabla("SELECT datos FROM papelera ORDER BY orden")
+    }
+
+    fn guarda_papelera(&self, papelera: &[Eliminado]) -> Result<(), String> {
+        let filas = papelera
+            .iter()
+            .enumerate()
+            .map(|(orden, eliminado)| {
+                vec![
+                    Celda::Integer(eliminado.id as i64),
+                    Celda::Integer(orden as i64),
+                    datos(eliminado),
+                ]
+            })
+            .collect();
+        self.escribe("papelera", &["id", "orden", "datos"], filas)
+    }
+
+    fn carga_versiones(&self) -> Result<Option<Vec<Versión>>, String> {
+        self.lee_tabla("SELECT datos FROM versiones ORDER BY orden")
+    }
+
+    fn guarda_versiones(&self, versiones: &[Versión]) -> Result<(), String> {
+        let filas = versiones
+            .iter()
+            .enumerate()
+            .map(|(orden, versión)| {
+                vec![
+                    Celda::Text(versión.nombre.clone()),
+                    Celda::Integer(orden as i64),
+                    datos(versión),
+                ]
+            })
+            .collect();
+        self.escribe("versiones", &["nombre", "orden", "datos"], filas)
+    }
+
+    fn huella(&self, colección: &str) -> Result<Option<String>, String> {
+        let consulta = match colección {
+            "documentos" => "SELECT datos FROM documentos ORDER BY orden",
+            "usuarios" => "SELECT datos FROM usuarios ORDER BY orden",
+            otra => return Err(format!("Colección desconocida: {}", otra)),
+        };
+        let conexión = self.conexión.lock().unwrap();
+        let mut sentencia = conexión.prepare(consulta).map_err(|e| e.to_string())?;
+        let filas: Vec<String> = sentencia
+            .query_map([], |fila| fila.get(0))
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<String>, _>>()
+            .map_err(|e| e.to_string())?;
+        if filas.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(calcula_huella(filas.join("\n").as_bytes())))
+    }
+
+    fn huellas(&self) -> &Huellas {
+        &self.huellas
+    }
+
+    fn versiones(&self) -> Result<Vec<(String, Option<u32>)>, String> {
+        let conexión = self.conexión.lock().unwrap();
+        COLECCIONES
+            .iter()
+            .map(|colección| {
+                let versión: Option<u32> = conexión
+                    .query_row(
+                        "SELECT version FROM esquema WHERE coleccion = ?1",
+                        [colección],
+                        |fila| fila.get(0),
+                    )
+                    .optional()
+                    .map_err(|e| e.to_string())?;
+                if versión.is_some() {
+                    return Ok((colección.to_string(), versión));
+                }
+                // Las bases de datos anteriores a las versiones no tienen la tabla "esquema":
+                // si la colección tiene datos, son de la versión 0
+                let hay_datos: Option<i64> = conexión
+                    .query_row(
+                        &format!("SELECT 1 FROM {} LIMIT 1", colección),
+                        [],
+                        |fila| fila.get(0),
+                    )
+                    .optional()
+                    .map_err(|e| e.to_string())?;
+                Ok((colección.to_string(), hay_datos.map(|_| 0)))
+            })
+            .collect()
+    }
+
+    fn migra_esquema(&self) -> Result<(), String> {
+        let antiguas: Vec<(String, u32)> = self
+            .versiones()?
+            .into_iter()
+            .filter_map(|(colección, versión)| Some((colección, versión?)))
+            .filter(|(_, versión)| *versión != esquema::VERSIÓN_ACTUAL)
+            .collect();
+        if antiguas.is_empty() {
+            return Ok(());
+        }
+
+        let mut conexión = self.conexión.lock().unwrap();
+
+        // Guardo una copia de la base de datos original, por si la migración sale mal
+        let mínima = antiguas.iter().map(|(_, v)| *v).min().unwrap();
+        let copia = format!("{}.v{}", self.ruta, mínima);
+        if !std::path::Path::new(&copia).exists() {
+            conexión
+                .execute("VACUUM INTO ?1", [&copia])
+                .map_err(|e| e.to_string())?;
+        }
+
+        let transacción = conexión.transaction().map_err(|e| e.to_string())?;
+        for (colección, versión) in antiguas.iter() {
+            // Los contadores no se guardan como JSON, así que no hay filas que migrar
+            if colección != "contadores" {
+                let filas: Vec<(i64, String)> = {
+                    let mut sentencia = transacción
+                        .prepare(&format!("SELECT rowid, datos FROM {}", colección))
+                        .map_err(|e| e.to_string())?;
+                    let filas = sentencia
+                        .query_map([], |fila| Ok((fila.get(0)?, fila.get(1)?)))
+                        .map_err(|e| e.to_string())?
+                        .collect::<Result<Vec<(i64, String)>, _>>()
+                        .map_err(|e| e.to_string())?;
+                    filas
+                };
+                for (fila, datos) in filas {
+                    // Las migraciones trabajan sobre la colección entera, así que envuelvo
+                    // cada fila en una lista de un único elemento
+                    let datos: Value = serde_json::from_str(&datos).map_err(|e| e.to_string())?;
+                    let migrados = esquema::migra(colección, *versión, Value::Array(vec![datos]))?;
+                    transacción
+                        .execute(
+                            &format!("UPDATE {} SET datos = ?1 WHERE rowid = ?2", colección),
+                            params![migrados[0].to_string(), fila],
+                        )
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+            anota_versión(&transacción, colección)?;
+            println!(
+                "He migrado los {} de la versión {} a la {}. El original está en {}",
+                colección,
+                versión,
+                esquema::VERSIÓN_ACTUAL,
+                copia
+            );
+        }
+        transacción.commit().map_err(|e| e.to_string())
+    }
+}
+
+pub fn lee_configuración() -> ConfigAlmacén {
+    Config::figment()
+        .extract_inner::<ConfigAlmacén>("almacen")
+        .unwrap_or(ConfigAlmacén {
+            tipo: "json".to_string(),
+            carpeta: None,
+            ruta: None,
+            generaciones: None,
+        })
+}
+
+pub fn ruta_sqlite(config: &ConfigAlmacén) -> String {
+    config
+        .ruta
+        .clone()
+        .unwrap_or_else(|| "datos.sqlite".to_string())
+}
+
+pub fn abre(config: &ConfigAlmacén) -> Result<Almacenamiento, String> {
+    match config.tipo.as_str() {
+        "json" => Ok(Box::new(AlmacénJson::nuevo(
+            config.carpeta.as_deref().unwrap_or("."),
+            config.generaciones.unwrap_or(GENERACIONES_POR_DEFECTO),
+        ))),
+        "sqlite" => Ok(Box::new(AlmacénSqlite::nuevo(&ruta_sqlite(config))?)),
+        otro => Err(format!("Tipo de almacén desconocido: {}", otro)),
+    }
+}
+
+// Abre el almacén y lleva sus datos a la versión actual del formato
+pub fn abre_y_migra(config: &ConfigAlmacén) -> Result<Almacenamiento, String> {
+    let almacén = abre(config)?;
+    almacén.migra_esquema()?;
+    Ok(almacén)
+}
+
+pub fn prepara_estado_inicial() -> Almacenamiento {
+    abre_y_migra(&lee_configuración()).unwrap()
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+
+    use std::time::SystemTime;
+
+    fn sesiones(usuarios: &[(&str, Id)]) -> HashMap<String, Sesión> {
+        usuarios
+            .iter()
+            .map(|(símbolo, usuario)| {
+                let sesión = Sesión {
+                    usuario: *usuario,
+                    caducidad: SystemTime::UNIX_EPOCH,
+                };
+                (símbolo.to_string(), sesión)
+            })
+            .collect()
+    }
+
+    fn cambios(almacén: &AlmacénSqlite) -> i64 {
+        let conexión = almacén.conexión.lock().unwrap();
+        conexión
+            .query_row("SELECT total_changes()", [], |fila| fila.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn sólo_escribe_las_filas_que_cambian() {
+        let almacén = AlmacénSqlite::nuevo(":memory:").unwrap();
+        almacén
+            .guarda_sesiones(&sesiones(&[("a", 1), ("b", 2), ("c", 3)]))
+            .unwrap();
+        let antes = cambios(&almacén);
+
+        // Cambio "b", quito "c" y añado "d"; "a" no se toca
+        almacén
+            .guarda_sesiones(&sesiones(&[("a", 1), ("b", 4), ("d", 5)]))
+            .unwrap();
+        // Una fila por cada cambio, más la versión del esquema
+        assert_eq!(cambios(&almacén) - antes, 4);
+
+        let guardadas = almacén.carga_sesiones().unwrap().unwrap();
+        let mut usuarios: Vec<(String, Id)> = guardadas
+            .into_iter()
+            .map(|(símbolo, sesión)| (símbolo, sesión.usuario))
+            .collect();
+        usuarios.sort();
+        let esperados = [("a", 1), ("b", 4), ("d", 5)]
+            .iter()
+            .map(|(s, u)| (s.to_string(), *u))
+            .collect::<Vec<_>>();
+        assert_eq!(usuarios, esperados);
+
+        // Sin cambios, sólo anoto la versión
+        let antes = cambios(&almacén);
+        almacén
+            .guarda_sesiones(&sesiones(&[("a", 1), ("b", 4), ("d", 5)]))
+            .unwrap();
+        assert_eq!(cambios(&almacén) - antes, 1);
+    }
+}
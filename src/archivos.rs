@@ -1,6 +1,13 @@
 use rocket::fs::NamedFile;
+use rocket::response::content::Html;
+use rocket::tokio::fs;
+use rocket::State;
 use std::path::{Path, PathBuf};
 
+use super::documentos::{self, Documentos};
+use super::paginas;
+use super::traducciones::{self, EnIdioma, IdiomasAceptados};
+
 /**
  * Puntos de acceso para los archivos estáticos
  */
@@ -26,18 +33,42 @@ async fn archivo_index_html() -> Option<NamedFile> {
         .ok()
 }
 
+#[derive(Responder)]
+enum Respuesta {
+    Archivo(NamedFile),
+    Aplicación(Html<String>),
+    Página(EnIdioma<Html<String>>),
+}
+
+// Si la ruta no es un archivo, respondo con la aplicación. Si además es la dirección de un
+// documento, le añado el documento.
 #[get("/<archivo..>", rank = 3)]
-async fn archivos(archivo: PathBuf) -> Option<NamedFile> {
-    let arch = NamedFile::open(Path::new("sitio/").join(archivo)).await;
-    let resultado = match arch {
-        Ok(a) => a,
-        Err(_e) => {
-            return NamedFile::open(Path::new("sitio/").join("index.html"))
-                .await
-                .ok()
-        }
+async fn archivos(
+    archivo: PathBuf,
+    lista: &State<Documentos>,
+    aceptados: IdiomasAceptados,
+) -> Option<Respuesta> {
+    if let Ok(arch) = NamedFile::open(Path::new("sitio/").join(&archivo)).await {
+        return Some(Respuesta::Archivo(arch));
+    }
+    // Leo la aplicación antes de bloquear los documentos, para no hacer esperar a quien los
+    // cambia mientras leo el disco
+    let base = fs::read_to_string(Path::new("sitio/").join("index.html"))
+        .await
+        .ok()?;
+    let segmentos: Vec<&str> = archivo.iter().filter_map(|s| s.to_str()).collect();
+    let lista = lista.lock().await;
+    let id = match documentos::busca_por_dirección(&lista, &segmentos) {
+        Some(id) => id,
+        None => return Some(Respuesta::Aplicación(Html(base))),
     };
-    return Some(resultado);
+    let documento = lista.iter().find(|d| d.id == id)?;
+    let idioma = traducciones::elige(documento, None, &aceptados);
+    let página = paginas::genera(&base, &lista, id, &idioma)?;
+    Some(Respuesta::Página(EnIdioma {
+        idioma,
+        respuesta: Html(página),
+    }))
 }
 
 pub fn rutas() -> Vec<rocket::Route> {
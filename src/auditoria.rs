@@ -0,0 +1,191 @@
+use rocket::serde::json::{json, Value};
+use rocket::serde::{Deserialize, Serialize};
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::id::Id;
+use super::novedades::{self, Marcas};
+use super::roles::Administrador;
+use super::usuarios::Usuario;
+
+/*
+ * Registro de auditoría
+ */
+
+// Cada acción privilegiada añade una línea JSON al final de este archivo. Nunca reescribo
+// ni borro líneas anteriores.
+const ARCHIVO_AUDITORÍA: &str = "auditoria.jsonl";
+
+// Tamaño de página por defecto y máximo de las consultas
+const TAMAÑO_PÁGINA: usize = 50;
+const TAMAÑO_PÁGINA_MÁXIMO: usize = 500;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Entrada {
+    // Segundos desde el 1 de enero de 1970
+    pub momento: u64,
+    pub actor: Option<Id>,
+    pub nombre_actor: String,
+    pub acción: String,
+    // Recurso afectado, como "documento/3" o "usuario/2"
+    pub objetivo: String,
+    pub antes: Option<Value>,
+    pub después: Option<Value>,
+    pub ip: Option<String>,
+}
+
+impl Entrada {
+    pub fn nueva(
+        acción: &str,
+        actor: Option<&Usuario>,
+        objetivo: String,
+        ip: Option<IpAddr>,
+    ) -> Entrada {
+        Entrada {
+            momento: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            actor: actor.map(|u| u.id),
+            nombre_actor: actor.map(|u| u.nombre.clone()).unwrap_or_default(),
+            acción: acción.to_string(),
+            objetivo,
+            antes: None,
+            después: None,
+            ip: ip.map(|ip| ip.to_string()),
+        }
+    }
+
+    pub fn antes(mut self, resumen: Value) -> Entrada {
+        self.antes = Some(resumen);
+        self
+    }
+
+    pub fn después(mut self, resumen: Value) -> Entrada {
+        self.después = Some(resumen);
+        self
+    }
+}
+
+pub struct Auditoría {
+    archivo: Mutex<File>,
+    // Fechas de creación y cambio de los documentos, para los canales de novedades y el mapa del
+    // sitio, que así no tienen que volver a leer el registro entero
+    marcas: Mutex<Marcas>,
+}
+
+impl Auditoría {
+    pub fn registra(&self, entrada: Entrada) {
+        let mut línea = serde_json::to_string(&entrada).unwrap();
+        línea.push('\n');
+        let mut archivo = self.archivo.lock().unwrap();
+        // Un fallo al auditar no debe impedir la acción, pero sí quiero enterarme
+        if let Err(e) = archivo
+            .write_all(línea.as_bytes())
+            .and_then(|_| archivo.flush())
+        {
+            println!("No he podido escribir en el registro de auditoría: {}", e);
+        }
+        // Con el archivo aún bloqueado, para que las marcas sigan el orden del registro
+        novedades::anota(&mut self.marcas.lock().unwrap(), &entrada);
+    }
+
+    // Las últimas fechas de cada documento, en desarrollo y en cada versión
+    pub fn marcas(&self) -> std::sync::MutexGuard<'_, Marcas> {
+        self.marcas.lock().unwrap()
+    }
+}
+
+// Todas las entradas del registro, de la más antigua a la más reciente
+pub fn lee_entradas() -> Vec<Entrada> {
+    match File::open(ARCHIVO_AUDITORÍA) {
+        Ok(archivo) => BufReader::new(archivo)
+            .lines()
+            .map_while(Result::ok)
+            .filter_map(|l| serde_json::from_str::<Entrada>(&l).ok())
+            .collect(),
+        Err(_e) => vec![],
+    }
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[allow(clippy::too_many_arguments)]
+#[get(
+    "/auditoría?<actor>&<acción>&<objetivo>&<desde>&<hasta>&<página>&<tamaño>",
+    format = "json"
+)]
+async fn lee_auditoría(
+    actor: Option<Id>,
+    acción: Option<String>,
+    objetivo: Option<String>,
+    desde: Option<u64>,
+    hasta: Option<u64>,
+    página: Option<usize>,
+    tamaño: Option<usize>,
+    _usuario: Usuario,
+    _administrador: Administrador,
+) -> Value {
+    let mut entradas: Vec<Entrada> = lee_entradas()
+        .into_iter()
+        .filter(|e| actor.is_none_or(|a| e.actor == Some(a)))
+        .filter(|e| acción.as_ref().is_none_or(|a| &e.acción == a))
+        // El objetivo puede ser un prefijo, como "documento/"
+        .filter(|e| {
+            objetivo
+                .as_ref()
+                .is_none_or(|o| e.objetivo.starts_with(o.as_str()))
+        })
+        .filter(|e| desde.is_none_or(|d| e.momento >= d))
+        .filter(|e| hasta.is_none_or(|h| e.momento <= h))
+        .collect();
+
+    // Muestro primero las entradas más recientes
+    entradas.reverse();
+
+    let total = entradas.len();
+    let página = página.unwrap_or(0);
+    let tamaño = tamaño
+        .unwrap_or(TAMAÑO_PÁGINA)
+        .clamp(1, TAMAÑO_PÁGINA_MÁXIMO);
+    let entradas: Vec<Entrada> = entradas
+        .into_iter()
+        .skip(página * tamaño)
+        .take(tamaño)
+        .collect();
+
+    json!({
+        "estado": "ok",
+        "total": total,
+        "página": página,
+        "tamaño": tamaño,
+        "entradas": entradas
+    })
+}
+
+pub fn prepara_estado_inicial() -> Auditoría {
+    let archivo = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ARCHIVO_AUDITORÍA)
+        .unwrap();
+    let mut marcas = Marcas::new();
+    for entrada in lee_entradas().iter() {
+        novedades::anota(&mut marcas, entrada);
+    }
+    Auditoría {
+        archivo: Mutex::new(archivo),
+        marcas: Mutex::new(marcas),
+    }
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![lee_auditoría]
+}
@@ -0,0 +1,202 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::tokio::sync::Mutex;
+use rocket::{Config, State};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use super::auditoria::{Auditoría, Entrada};
+use super::documentos::Documentos;
+use super::id::Id;
+use super::roles::Editor;
+use super::usuarios::Usuario;
+
+/*
+ * Bloqueos de edición
+ */
+
+// Un bloqueo avisa a los demás editores de que alguien está trabajando en un documento. Es
+// orientativo: el titular lo renueva periódicamente mientras edita, y si deja de hacerlo caduca
+// solo. Mientras está activo, los cambios de otros usuarios se rechazan con un 423, salvo que
+// los fuercen con ?forzar=true. No los guardo en el almacén: al reiniciar el servidor se pierden.
+pub struct Bloqueo {
+    pub usuario: Id,
+    pub nombre: String,
+    pub caducidad: SystemTime,
+}
+
+impl Bloqueo {
+    fn activo(&self) -> bool {
+        self.caducidad > SystemTime::now()
+    }
+
+    pub fn resumen(&self) -> Value {
+        let caducidad: DateTime<Utc> = self.caducidad.into();
+        json!({
+            "usuario": self.usuario,
+            "nombre": self.nombre,
+            "caducidad": caducidad.to_rfc3339_opts(SecondsFormat::Secs, true)
+        })
+    }
+}
+
+// Bloqueos activos, por documento
+pub type Bloqueos = Mutex<HashMap<Id, Bloqueo>>;
+
+// Segundos que dura un bloqueo sin renovarlo
+fn duración() -> Duration {
+    let segundos = Config::figment()
+        .extract_inner::<u64>("bloqueos.caducidad")
+        .unwrap_or(120);
+    Duration::from_secs(segundos)
+}
+
+fn es_administrador(usuario: &Usuario) -> bool {
+    usuario.roles.iter().any(|r| r == "Administrador")
+}
+
+// Devuelve el bloqueo activo de un documento, y olvida el que haya caducado
+pub fn activo(bloqueos: &mut HashMap<Id, Bloqueo>, documento: Id) -> Option<&Bloqueo> {
+    if bloqueos.get(&documento).is_some_and(|b| !b.activo()) {
+        bloqueos.remove(&documento);
+    }
+    bloqueos.get(&documento)
+}
+
+// Antes de cambiar o borrar un documento, compruebo que nadie más lo tiene bloqueado
+pub async fn comprueba(
+    bloqueos: &Bloqueos,
+    documento: Id,
+    usuario: &Usuario,
+    forzar: bool,
+) -> Result<(), Status> {
+    let mut bloqueos = bloqueos.lock().await;
+    match activo(&mut bloqueos, documento) {
+        Some(bloqueo) if bloqueo.usuario != usuario.id && !forzar => Err(Status::Locked),
+        _ => Ok(()),
+    }
+}
+
+// Cuando se borra un documento, su bloqueo deja de tener sentido
+pub async fn olvida(bloqueos: &Bloqueos, documento: Id) {
+    bloqueos.lock().await.remove(&documento);
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+// Consulta quién tiene bloqueado un documento. La lectura del documento también lo incluye, pero
+// su ETag sólo depende del contenido, así que un 304 no avisa de los cambios en el bloqueo.
+#[get("/documento/<id>/bloqueo", format = "json")]
+async fn lee_bloqueo(
+    id: Id,
+    bloqueos: &State<Bloqueos>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Value {
+    let mut bloqueos = bloqueos.lock().await;
+    let bloqueo = activo(&mut bloqueos, id).map(|b| b.resumen());
+
+    json!({ "estado": "ok", "bloqueo": bloqueo })
+}
+
+// Adquiere el bloqueo, o lo renueva si ya es del usuario
+#[post("/documento/<id>/bloqueo")]
+async fn bloquea_documento(
+    id: Id,
+    documentos: &State<Documentos>,
+    bloqueos: &State<Bloqueos>,
+    usuario: Usuario,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    if !documentos.lock().await.iter().any(|d| d.id == id) {
+        return Err(Status::NotFound);
+    }
+    let mut bloqueos = bloqueos.lock().await;
+    if let Some(bloqueo) = activo(&mut bloqueos, id) {
+        if bloqueo.usuario != usuario.id {
+            return Err(Status::Locked);
+        }
+    }
+    let bloqueo = Bloqueo {
+        usuario: usuario.id,
+        nombre: usuario.nombre.clone(),
+        caducidad: SystemTime::now() + duración(),
+    };
+    let resumen = bloqueo.resumen();
+    bloqueos.insert(id, bloqueo);
+
+    Ok(json!({ "estado": "ok", "bloqueo": resumen }))
+}
+
+// Latido: el titular renueva el bloqueo mientras sigue editando
+#[put("/documento/<id>/bloqueo")]
+async fn renueva_bloqueo(
+    id: Id,
+    bloqueos: &State<Bloqueos>,
+    usuario: Usuario,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    let mut bloqueos = bloqueos.lock().await;
+    match activo(&mut bloqueos, id) {
+        None => return Err(Status::NotFound),
+        Some(bloqueo) if bloqueo.usuario != usuario.id => return Err(Status::Locked),
+        _ => {}
+    }
+    let bloqueo = bloqueos.get_mut(&id).unwrap();
+    bloqueo.caducidad = SystemTime::now() + duración();
+
+    Ok(json!({ "estado": "ok", "bloqueo": bloqueo.resumen() }))
+}
+
+// El titular libera el bloqueo. Un administrador puede romper el de otro usuario.
+#[delete("/documento/<id>/bloqueo")]
+async fn libera_bloqueo(
+    id: Id,
+    bloqueos: &State<Bloqueos>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Status {
+    let mut bloqueos = bloqueos.lock().await;
+    let titular = match activo(&mut bloqueos, id) {
+        Some(bloqueo) => bloqueo.usuario,
+        None => return Status::NotFound,
+    };
+    if titular != usuario.id {
+        if !es_administrador(&usuario) {
+            return Status::Locked;
+        }
+        auditoría.registra(
+            Entrada::nueva(
+                "rompe_bloqueo",
+                Some(&usuario),
+                format!("documento/{}", id),
+                ip,
+            )
+            .antes(bloqueos[&id].resumen()),
+        );
+    }
+    bloqueos.remove(&id);
+
+    Status::Accepted
+}
+
+pub fn prepara_estado_inicial() -> Bloqueos {
+    Mutex::new(HashMap::new())
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![
+        lee_bloqueo,
+        bloquea_documento,
+        renueva_bloqueo,
+        libera_bloqueo
+    ]
+}
@@ -0,0 +1,425 @@
+use rocket::Config;
+
+use std::io::ErrorKind;
+use std::net::TcpListener;
+
+use super::almacen::{self, Almacén, AlmacénJson, AlmacénSqlite};
+use super::condiciones;
+use super::copias;
+use super::documentos;
+use super::esquema;
+use super::exportacion;
+use super::id::Id;
+use super::importacion;
+use super::integridad;
+use super::versiones;
+
+/*
+ * Órdenes de la línea de comandos
+ */
+
+pub fn ejecuta(argumentos: &[String]) -> i32 {
+    let resultado = match argumentos[0].as_str() {
+        "migra" => migra(&argumentos[1..]),
+        "copias" => lista_copias(),
+        "copia" => crea_copia(),
+        "restaura" => restaura(&argumentos[1..]),
+        "comprueba" => comprueba(&argumentos[1..]),
+        "esquema" => muestra_esquema(),
+        "exporta" => exporta(&argumentos[1..]),
+        "importa" => importa(&argumentos[1..]),
+        "ayuda" | "--help" | "-h" => {
+            muestra_ayuda();
+            Ok(())
+        }
+        otra => Err(format!("Orden desconocida: {}", otra)),
+    };
+
+    match resultado {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn muestra_ayuda() {
+    println!("Uso: servidor [orden] [argumentos]");
+    println!();
+    println!("Sin orden, inicia el servidor.");
+    println!();
+    println!("Órdenes:");
+    println!("  migra [carpeta_json] [ruta_sqlite]");
+    println!(
+        "      Copia los documentos, usuarios, sesiones y contadores de los archivos JSON a una"
+    );
+    println!(
+        "      base de datos SQLite. Por defecto lee la carpeta actual y escribe en la ruta de"
+    );
+    println!("      la sección \"almacen\" de Rocket.toml.");
+    println!("  copias");
+    println!(
+        "      Muestra las copias de seguridad guardadas, de la más reciente a la más antigua."
+    );
+    println!("  copia");
+    println!("      Hace una copia de seguridad de los datos del almacén.");
+    println!("  restaura <copia> [--simulacro]");
+    println!(
+        "      Restaura los documentos y usuarios de una copia de seguridad, después de hacer"
+    );
+    println!("      una copia de los datos actuales. Con --simulacro sólo muestra qué cambiaría.");
+    println!("      Detén el servidor antes de restaurar.");
+    println!("  comprueba [--repara]");
+    println!("      Comprueba la integridad del árbol de documentos y muestra los problemas.");
+    println!("      Con --repara, además los repara. Detén el servidor antes de reparar.");
+    println!("  esquema");
+    println!("      Muestra la versión del formato de los datos guardados y las migraciones que");
+    println!("      se aplicarán al iniciar el servidor, sin cambiar nada.");
+    println!("  exporta <destino> [--versión <nombre>] [--idioma <idioma>]");
+    println!("      Exporta la documentación a un sitio estático, con una página HTML por");
+    println!("      documento. Si el destino termina en .zip, lo empaqueta en un zip; si no, lo");
+    println!("      escribe en esa carpeta.");
+    println!("  importa <carpeta> [--bajo <id>] [--simulacro]");
+    println!(
+        "      Importa una carpeta de archivos Markdown bajo el documento <id>, o bajo la raíz."
+    );
+    println!("      Crea, cambia y borra documentos para que coincidan con la carpeta, así que");
+    println!("      importarla otra vez sin cambios no hace nada. Con --simulacro sólo muestra");
+    println!("      los cambios. Detén el servidor antes de importar.");
+    println!("  ayuda");
+    println!("      Muestra este mensaje.");
+}
+
+// Pasa los datos de los archivos JSON a una base de datos SQLite
+fn migra(argumentos: &[String]) -> Result<(), String> {
+    let config = almacen::lee_configuración();
+    let carpeta = argumentos.first().map(|a| a.as_str()).unwrap_or(".");
+    let ruta = argumentos
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| almacen::ruta_sqlite(&config));
+
+    let origen = AlmacénJson::nuevo(
+        carpeta,
+        config
+            .generaciones
+            .unwrap_or(almacen::GENERACIONES_POR_DEFECTO),
+    );
+    let destino = AlmacénSqlite::nuevo(&ruta)?;
+
+    if let Some(documentos) = origen.carga_documentos()? {
+        destino.guarda_documentos(&documentos)?;
+        println!("Documentos copiados: {}", documentos.len());
+    }
+    if let Some(usuarios) = origen.carga_usuarios()? {
+        destino.guarda_usuarios(&usuarios)?;
+        println!("Usuarios copiados: {}", usuarios.len());
+    }
+    if let Some(sesiones) = origen.carga_sesiones()? {
+        destino.guarda_sesiones(&sesiones)?;
+        println!("Sesiones copiadas: {}", sesiones.len());
+    }
+    if let Some(contadores) = origen.carga_contadores()? {
+        destino.guarda_contadores(&contadores)?;
+        println!("Contadores copiados: {}", contadores.len());
+    }
+    if let Some(suscripciones) = origen.carga_suscripciones()? {
+        destino.guarda_suscripciones(&suscripciones)?;
+        println!("Suscripciones copiadas: {}", suscripciones.len());
+    }
+    if let Some(papelera) = origen.carga_papelera()? {
+        destino.guarda_papelera(&papelera)?;
+        println!("Documentos en la papelera copiados: {}", papelera.len());
+    }
+    if let Some(versiones) = origen.carga_versiones()? {
+        destino.guarda_versiones(&versiones)?;
+        println!("Versiones copiadas: {}", versiones.len());
+    }
+
+    println!(
+        "Datos migrados a {}. Para usarlos, pon tipo = \"sqlite\" en la sección \"almacen\" de Rocket.toml.",
+        ruta
+    );
+    Ok(())
+}
+
+fn lista_copias() -> Result<(), String> {
+    for copia in copias::lista_copias(&copias::lee_configuración())? {
+        println!(
+            "{}\t{}\t{} bytes",
+            copia.nombre, copia.momento, copia.tamaño
+        );
+    }
+    Ok(())
+}
+
+fn crea_copia() -> Result<(), String> {
+    let almacén = almacen::abre_y_migra(&almacen::lee_configuración())?;
+    let copia = copias::copia_del_almacén(almacén.as_ref())?;
+    let nombre = copias::guarda_copia(&copias::lee_configuración(), &copia)?;
+    println!("Copia guardada: {}", nombre);
+    Ok(())
+}
+
+fn restaura(argumentos: &[String]) -> Result<(), String> {
+    let nombre = argumentos
+        .iter()
+        .find(|a| !a.starts_with("--"))
+        .ok_or("Falta el nombre de la copia")?;
+    let simulacro = argumentos.iter().any(|a| a == "--simulacro");
+
+    let config = copias::lee_configuración();
+    let almacén = almacen::abre_y_migra(&almacen::lee_configuración())?;
+    let copia = copias::lee_copia(&config, nombre)?;
+    let actual = copias::copia_del_almacén(almacén.as_ref())?;
+
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&copias::diferencias(&actual, &copia)).unwrap()
+    );
+    if simulacro {
+        println!("Simulacro: no he cambiado nada.");
+        return Ok(());
+    }
+
+    let previa = copias::guarda_copia(&config, &actual)?;
+    println!("Copia de los datos actuales: {}", previa);
+
+    // Las revisiones restauradas deben seguir avanzando, para que los clientes noten el cambio
+    let mut copia = copia;
+    condiciones::avanza_revisiones(&actual.documentos, &mut copia.documentos);
+    condiciones::avanza_revisiones(&actual.usuarios, &mut copia.usuarios);
+    almacén.guarda_documentos(&copia.documentos)?;
+    almacén.guarda_usuarios(&copia.usuarios)?;
+    // Las copias anteriores no guardaban la papelera, las versiones ni las suscripciones
+    if let Some(papelera) = &copia.papelera {
+        almacén.guarda_papelera(papelera)?;
+    }
+    if let Some(versiones) = &copia.versiones {
+        almacén.guarda_versiones(versiones)?;
+    }
+    if let Some(suscripciones) = &copia.suscripciones {
+        almacén.guarda_suscripciones(suscripciones)?;
+    }
+
+    // Los contadores nunca retroceden, para no reutilizar identificadores
+    let mut contadores = actual.contadores;
+    for (colección, siguiente) in copia.contadores {
+        let actual = contadores.entry(colección).or_insert(siguiente);
+        *actual = (*actual).max(siguiente);
+    }
+    almacén.guarda_contadores(&contadores)?;
+
+    // Cierro las sesiones de los usuarios que ya no existen
+    if let Some(mut sesiones) = almacén.carga_sesiones()? {
+        sesiones.retain(|_, s| copia.usuarios.iter().any(|u| u.id == s.usuario));
+        almacén.guarda_sesiones(&sesiones)?;
+    }
+
+    println!("Copia {} restaurada.", nombre);
+    Ok(())
+}
+
+fn comprueba(argumentos: &[String]) -> Result<(), String> {
+    let repara = argumentos.iter().any(|a| a == "--repara");
+    let almacén = almacen::abre_y_migra(&almacen::lee_configuración())?;
+    let mut documentos = almacén.carga_documentos()?.unwrap_or_default();
+
+    if !repara {
+        let problemas = integridad::comprueba(&documentos);
+        for problema in problemas.iter() {
+            println!(
+                "documento {}: {} ({})",
+                problema.documento, problema.detalle, problema.tipo
+            );
+        }
+        println!("Problemas encontrados: {}", problemas.len());
+        return Ok(());
+    }
+
+    let mut contadores = almacén.carga_contadores()?.unwrap_or_default();
+    let mut siguiente_id = documentos
+        .iter()
+        .map(|d| d.id + 1)
+        .chain(contadores.get("documentos").cloned())
+        .max()
+        .unwrap_or(1);
+    let problemas = integridad::repara(&mut documentos, &mut siguiente_id);
+    for problema in problemas.iter() {
+        println!(
+            "Reparado en el documento {}: {} ({})",
+            problema.documento, problema.detalle, problema.tipo
+        );
+    }
+    if !problemas.is_empty() {
+        contadores.insert("documentos".to_string(), siguiente_id);
+        almacén.guarda_contadores(&contadores)?;
+        almacén.guarda_documentos(&documentos)?;
+    }
+    println!("Problemas reparados: {}", problemas.len());
+    Ok(())
+}
+
+fn muestra_esquema() -> Result<(), String> {
+    // Abro el almacén sin migrarlo, para no cambiar nada
+    let almacén = almacen::abre(&almacen::lee_configuración())?;
+    println!("Versión actual del formato: {}", esquema::VERSIÓN_ACTUAL);
+
+    let mut pendientes = 0;
+    for (colección, versión) in almacén.versiones()? {
+        let versión = match versión {
+            Some(versión) => versión,
+            None => {
+                println!("{}: sin datos", colección);
+                continue;
+            }
+        };
+        if versión > esquema::VERSIÓN_ACTUAL {
+            println!(
+                "{}: versión {}, más nueva que la de este servidor",
+                colección, versión
+            );
+            continue;
+        }
+        println!("{}: versión {}", colección, versión);
+        for migración in esquema::pendientes(versión) {
+            println!(
+                "  pendiente, a la versión {}: {}",
+                migración.versión, migración.descripción
+            );
+            pendientes += 1;
+        }
+    }
+    println!("Migraciones pendientes: {}", pendientes);
+    Ok(())
+}
+
+// Valor de una opción como "--versión 0.1"
+fn opción<'a>(argumentos: &'a [String], nombre: &str) -> Option<&'a str> {
+    argumentos
+        .iter()
+        .position(|a| a == nombre)
+        .and_then(|i| argumentos.get(i + 1))
+        .map(|valor| valor.as_str())
+}
+
+fn exporta(argumentos: &[String]) -> Result<(), String> {
+    // El destino es el primer argumento que no es una opción ni su valor
+    let destino = argumentos
+        .iter()
+        .enumerate()
+        .find(|(i, a)| !a.starts_with("--") && (*i == 0 || !argumentos[i - 1].starts_with("--")))
+        .map(|(_, a)| a)
+        .ok_or("Falta el destino de la exportación")?;
+    let versión = opción(argumentos, "--versión");
+    let idioma = opción(argumentos, "--idioma");
+
+    let almacén = almacen::abre_y_migra(&almacen::lee_configuración())?;
+    let documentos = if versiones::es_desarrollo(versión) {
+        almacén.carga_documentos()?.unwrap_or_default()
+    } else {
+        let todas = almacén.carga_versiones()?.unwrap_or_default();
+        versiones::busca(&todas, versión.unwrap())
+            .ok_or(format!("No existe la versión {}", versión.unwrap()))?
+            .documentos
+            .clone()
+    };
+
+    let archivos = exportacion::genera(&documentos, idioma, &exportacion::lee_configuración())?;
+    if destino.ends_with(".zip") {
+        std::fs::write(destino, exportacion::empaqueta(&archivos)?)
+            .map_err(|e| format!("{}: {}", destino, e))?;
+    } else {
+        exportacion::escribe_carpeta(std::path::Path::new(destino), &archivos)?;
+    }
+    println!(
+        "Documentación exportada a {}: {} archivos",
+        destino,
+        archivos.len()
+    );
+    Ok(())
+}
+
+// El servidor está en marcha si ya hay alguien escuchando en su dirección y su puerto
+fn servidor_en_marcha() -> bool {
+    let config = match Config::figment().extract::<Config>() {
+        Ok(config) => config,
+        Err(_) => return false,
+    };
+    matches!(
+        TcpListener::bind((config.address, config.port)),
+        Err(e) if e.kind() == ErrorKind::AddrInUse
+    )
+}
+
+fn importa(argumentos: &[String]) -> Result<(), String> {
+    let carpeta = argumentos
+        .iter()
+        .enumerate()
+        .find(|(i, a)| !a.starts_with("--") && (*i == 0 || argumentos[i - 1] != "--bajo"))
+        .map(|(_, a)| a)
+        .ok_or("Falta la carpeta que importar")?;
+    let destino: Id = match opción(argumentos, "--bajo") {
+        Some(id) => id
+            .parse()
+            .map_err(|_| format!("El id del documento no es válido: {}", id))?,
+        None => 0,
+    };
+    let simulacro = argumentos.iter().any(|a| a == "--simulacro");
+    // El servidor tiene en memoria los contadores y la papelera, y los sobrescribiría
+    if !simulacro && servidor_en_marcha() {
+        return Err(
+            "El servidor está en marcha en la dirección de Rocket.toml. Detenlo antes de importar."
+                .to_string(),
+        );
+    }
+
+    let almacén = almacen::abre_y_migra(&almacen::lee_configuración())?;
+    // Sin documentos guardados, importo en un árbol con sólo la raíz, como al arrancar el
+    // servidor
+    let documentos = match almacén.carga_documentos()? {
+        Some(documentos) if !documentos.is_empty() => documentos,
+        _ => vec![documentos::raíz()],
+    };
+    let mut papelera = almacén.carga_papelera()?.unwrap_or_default();
+    let mut contadores = almacén.carga_contadores()?.unwrap_or_default();
+    // Los ids de la papelera siguen ocupados, para poder restaurarlos
+    let mut siguiente_id = documentos
+        .iter()
+        .chain(papelera.iter().flat_map(|e| e.documentos.iter()))
+        .map(|d| d.id + 1)
+        .chain(contadores.get("documentos").cloned())
+        .max()
+        .unwrap_or(1);
+
+    let importación = importacion::importa(
+        std::path::Path::new(carpeta),
+        &documentos,
+        destino,
+        &mut siguiente_id,
+    )?;
+    for cambio in importación.cambios.iter() {
+        println!(
+            "{}\tdocumento {}\t{}\t{}",
+            cambio.acción, cambio.documento, cambio.ruta, cambio.detalle
+        );
+    }
+    if importación.cambios.is_empty() {
+        println!("No hay cambios que importar.");
+        return Ok(());
+    }
+    if simulacro {
+        println!("Simulacro: no he cambiado nada.");
+        return Ok(());
+    }
+
+    // Guardo primero la papelera, para no perder nunca los documentos que borro
+    papelera.extend(importación.eliminados);
+    almacén.guarda_papelera(&papelera)?;
+    contadores.insert("documentos".to_string(), siguiente_id);
+    almacén.guarda_contadores(&contadores)?;
+    almacén.guarda_documentos(&importación.documentos)?;
+    println!("Cambios importados: {}", importación.cambios.len());
+    Ok(())
+}
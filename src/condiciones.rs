@@ -0,0 +1,155 @@
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::Serialize;
+
+use std::collections::HashMap;
+
+use super::id::Id;
+
+/*
+ * Peticiones condicionales con ETag
+ */
+
+// Cada documento y cada usuario lleva un número de revisión, que aumenta con cada cambio. Lo
+// envío como ETag al leerlo, y lo comparo con las cabeceras If-Match e If-None-Match.
+pub fn etiqueta(revisión: u64) -> String {
+    format!("\"{}\"", revisión)
+}
+
+// Las traducciones de un documento comparten su revisión, así que añado el idioma a la etiqueta
+pub fn etiqueta_en(revisión: u64, idioma: &str) -> String {
+    format!("\"{}-{}\"", revisión, idioma)
+}
+
+// Comprueba si una cabecera If-Match o If-None-Match incluye la etiqueta. Acepta listas
+// separadas por comas y "*". If-None-Match usa la comparación débil, que admite etiquetas
+// débiles (W/"..."); If-Match usa la fuerte, en la que una etiqueta débil nunca coincide.
+fn coincide(cabecera: &str, etiqueta: &str, débil: bool) -> bool {
+    cabecera.split(',').map(|e| e.trim()).any(|e| {
+        e == "*"
+            || match e.strip_prefix("W/") {
+                Some(e) => débil && e == etiqueta,
+                None => e == etiqueta,
+            }
+    })
+}
+
+// Cabecera If-Match. Si la petición no la incluye, acepto el cambio sin comprobar nada.
+pub struct SiCoincide(Option<String>);
+
+impl SiCoincide {
+    pub fn comprueba(&self, revisión: u64) -> Result<(), Status> {
+        match &self.0 {
+            Some(cabecera) if !coincide(cabecera, &etiqueta(revisión), false) => {
+                Err(Status::PreconditionFailed)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SiCoincide {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<SiCoincide, Self::Error> {
+        request::Outcome::Success(SiCoincide(
+            request.headers().get_one("If-Match").map(|c| c.to_string()),
+        ))
+    }
+}
+
+// Cabecera If-None-Match
+pub struct SiNoCoincide(Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SiNoCoincide {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<SiNoCoincide, Self::Error> {
+        request::Outcome::Success(SiNoCoincide(
+            request
+                .headers()
+                .get_one("If-None-Match")
+                .map(|c| c.to_string()),
+        ))
+    }
+}
+
+// Respuesta con su ETag. Si el cliente ya tiene esta revisión, respondo 304 sin contenido.
+pub enum Etiquetada<R> {
+    Completa(String, R),
+    SinCambios(String),
+}
+
+impl<R> Etiquetada<R> {
+    pub fn nueva(revisión: u64, si_no_coincide: &SiNoCoincide, respuesta: R) -> Etiquetada<R> {
+        Etiquetada::con_etiqueta(etiqueta(revisión), si_no_coincide, respuesta)
+    }
+
+    pub fn con_etiqueta(
+        etiqueta: String,
+        si_no_coincide: &SiNoCoincide,
+        respuesta: R,
+    ) -> Etiquetada<R> {
+        match &si_no_coincide.0 {
+            Some(cabecera) if coincide(cabecera, &etiqueta, true) => {
+                Etiquetada::SinCambios(etiqueta)
+            }
+            _ => Etiquetada::Completa(etiqueta, respuesta),
+        }
+    }
+
+    // Para las respuestas a un cambio, que siempre llevan el contenido
+    pub fn completa(revisión: u64, respuesta: R) -> Etiquetada<R> {
+        Etiquetada::Completa(etiqueta(revisión), respuesta)
+    }
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for Etiquetada<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        match self {
+            Etiquetada::Completa(etiqueta, respuesta) => {
+                Response::build_from(respuesta.respond_to(request)?)
+                    .raw_header("ETag", etiqueta)
+                    .ok()
+            }
+            Etiquetada::SinCambios(etiqueta) => Response::build()
+                .status(Status::NotModified)
+                .raw_header("ETag", etiqueta)
+                .ok(),
+        }
+    }
+}
+
+// Documentos y usuarios tienen un id y un número de revisión
+pub trait Revisado: Serialize {
+    fn id(&self) -> Id;
+    fn revisión(&self) -> u64;
+    fn cambia_revisión(&mut self, revisión: u64);
+}
+
+// Cuando sustituyo una lista entera, por ejemplo al recargar los datos editados a mano o al
+// restaurar una copia, avanzo la revisión de los elementos que han cambiado. Así nunca
+// reaparece una revisión antigua con un contenido distinto.
+pub fn avanza_revisiones<T: Revisado>(anteriores: &[T], nuevos: &mut [T]) {
+    // Comparo el contenido sin la revisión
+    fn contenido<T: Serialize>(elemento: &T) -> rocket::serde::json::Value {
+        let mut valor = serde_json::to_value(elemento).unwrap();
+        if let Some(objeto) = valor.as_object_mut() {
+            objeto.remove("revisión");
+        }
+        valor
+    }
+
+    let anteriores: HashMap<Id, &T> = anteriores.iter().map(|a| (a.id(), a)).collect();
+    for nuevo in nuevos.iter_mut() {
+        if let Some(anterior) = anteriores.get(&nuevo.id()) {
+            if nuevo.revisión() <= anterior.revisión() && contenido(*anterior) != contenido(nuevo)
+            {
+                nuevo.cambia_revisión(anterior.revisión() + 1);
+            }
+        }
+    }
+}
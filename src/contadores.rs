@@ -0,0 +1,129 @@
+use rocket::http::Status;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::almacen::Almacenamiento;
+use super::id::Id;
+
+/*
+ * Contadores de identificadores
+ */
+
+// Guardo, para cada colección ("documentos", "usuarios"...), el siguiente identificador libre.
+// Lo guardo en el almacén antes de entregar cada identificador, así que un identificador nunca
+// se vuelve a usar, aunque se borre el elemento o se reinicie el servidor.
+pub struct Contadores {
+    siguientes: Mutex<HashMap<String, Id>>,
+}
+
+impl Contadores {
+    pub fn nuevo_id(&self, almacén: &Almacenamiento, colección: &str) -> Result<Id, Status> {
+        let mut siguientes = self.siguientes.lock().unwrap();
+        // El nodo 0 de cada colección se crea al arrancar, así que empiezo en 1
+        let id: Id = *siguientes.get(colección).unwrap_or(&1);
+        siguientes.insert(colección.to_string(), id + 1);
+
+        if let Err(e) = almacén.guarda_contadores(&siguientes) {
+            println!("No he podido guardar los contadores: {}", e);
+            // Si no he podido guardarlo, no entrego el identificador
+            siguientes.insert(colección.to_string(), id);
+            return Err(Status::InternalServerError);
+        }
+
+        Ok(id)
+    }
+
+    pub fn siguientes(&self) -> HashMap<String, Id> {
+        self.siguientes.lock().unwrap().clone()
+    }
+
+    pub fn guarda(&self, almacén: &Almacenamiento) -> Result<(), Status> {
+        almacén
+            .guarda_contadores(&self.siguientes.lock().unwrap())
+            .map_err(|e| {
+                println!("No he podido guardar los contadores: {}", e);
+                Status::InternalServerError
+            })
+    }
+
+    // Adelanto el contador de una colección hasta "mínimo", si estaba por detrás. Nunca lo
+    // retraso, para no repetir identificadores.
+    pub fn asegura(&self, colección: &str, mínimo: Id) {
+        let mut siguientes = self.siguientes.lock().unwrap();
+        let siguiente = siguientes.entry(colección.to_string()).or_insert(1);
+        if *siguiente < mínimo {
+            *siguiente = mínimo;
+        }
+    }
+
+    // Me aseguro de que el contador de una colección quede por encima de sus identificadores,
+    // por ejemplo al cargar datos guardados antes de que existieran los contadores
+    pub fn respeta(&self, colección: &str, ids: impl Iterator<Item = Id>) {
+        self.asegura(colección, ids.max().map(|id| id + 1).unwrap_or(1));
+    }
+}
+
+pub fn prepara_estado_inicial(
+    almacén: &Almacenamiento,
+    ids_documentos: impl Iterator<Item = Id>,
+    ids_usuarios: impl Iterator<Item = Id>,
+) -> Contadores {
+    let guardados = almacén
+        .carga_contadores()
+        .unwrap_or_else(|e| panic!("No he podido cargar los contadores: {}", e));
+
+    let contadores = Contadores {
+        siguientes: Mutex::new(guardados.unwrap_or_default()),
+    };
+    contadores.respeta("documentos", ids_documentos);
+    contadores.respeta("usuarios", ids_usuarios);
+
+    almacén
+        .guarda_contadores(&contadores.siguientes.lock().unwrap())
+        .unwrap_or_else(|e| panic!("No he podido guardar los contadores: {}", e));
+
+    contadores
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+    use crate::almacen::{AlmacénJson, AlmacénSqlite};
+    use crate::temporal::Carpeta;
+
+    // Reinicio el servidor después de crear y borrar dos documentos: al volver a arrancar sólo
+    // quedan los ids 0 a 2, pero el siguiente id sigue siendo el 5
+    fn no_reutiliza_tras_reiniciar(abre: impl Fn() -> Almacenamiento) {
+        let almacén = abre();
+        let contadores = prepara_estado_inicial(&almacén, [0, 1, 2].into_iter(), [0].into_iter());
+        assert_eq!(contadores.nuevo_id(&almacén, "documentos"), Ok(3));
+        assert_eq!(contadores.nuevo_id(&almacén, "documentos"), Ok(4));
+        drop(contadores);
+        drop(almacén);
+
+        let almacén = abre();
+        let contadores = prepara_estado_inicial(&almacén, [0, 1, 2].into_iter(), [0].into_iter());
+        assert_eq!(contadores.nuevo_id(&almacén, "documentos"), Ok(5));
+        assert_eq!(contadores.nuevo_id(&almacén, "usuarios"), Ok(1));
+    }
+
+    #[test]
+    fn los_ids_no_se_reutilizan_tras_reiniciar_con_json() {
+        let carpeta = Carpeta::nueva("contadores-json");
+        let ruta = carpeta.ruta().to_str().unwrap().to_string();
+        no_reutiliza_tras_reiniciar(|| Box::new(AlmacénJson::nuevo(&ruta, 1)));
+    }
+
+    #[test]
+    fn los_ids_no_se_reutilizan_tras_reiniciar_con_sqlite() {
+        let carpeta = Carpeta::nueva("contadores-sqlite");
+        let ruta = carpeta
+            .ruta()
+            .join("datos.sqlite")
+            .to_str()
+            .unwrap()
+            .to_string();
+        no_reutiliza_tras_reiniciar(|| Box::new(AlmacénSqlite::nuevo(&ruta).unwrap()));
+    }
+}
@@ -0,0 +1,530 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Config, Orbit, Rocket, State};
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::almacen::{self, Almacenamiento, Almacén};
+use super::auditoria::{Auditoría, Entrada};
+use super::condiciones::avanza_revisiones;
+use super::contadores::Contadores;
+use super::documentos::{self, Documento, Documentos};
+use super::enlaces::ÍndiceEnlaces;
+use super::esquema;
+use super::eventos::{Evento, Eventos};
+use super::id::Id;
+use super::papelera::{self, Eliminado, Papelera};
+use super::roles::Administrador;
+use super::sesion::{self, SesionesActivas};
+use super::usuarios::{self, Usuario, Usuarios};
+use super::versiones::{self, Versiones, Versión};
+use super::webhooks::{Suscripción, Webhooks};
+
+/*
+ * Copias de seguridad
+ */
+
+// Cada copia es un archivo "copia-<momento>.json.gz" con todos los datos en un único JSON
+const PREFIJO: &str = "copia-";
+const EXTENSIÓN: &str = ".json.gz";
+const FORMATO_MOMENTO: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigCopias {
+    #[serde(default = "carpeta_por_defecto")]
+    pub carpeta: String,
+    // Segundos entre copias automáticas; 0 las desactiva
+    #[serde(default)]
+    pub intervalo: u64,
+    // Número de copias más recientes que conservo
+    #[serde(default = "conservar_por_defecto")]
+    pub conservar: usize,
+    // Además, conservo la última copia de cada uno de estos últimos días
+    #[serde(default)]
+    pub diarias: usize,
+}
+
+fn carpeta_por_defecto() -> String {
+    "copias".to_string()
+}
+
+fn conservar_por_defecto() -> usize {
+    24
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Copia {
+    pub documentos: Vec<Documento>,
+    pub usuarios: Vec<Usuario>,
+    #[serde(default)]
+    pub contadores: HashMap<String, Id>,
+    // Las copias anteriores no guardaban la papelera, las versiones ni las suscripciones. Al
+    // restaurar una de ellas, conservo las actuales.
+    #[serde(default)]
+    pub papelera: Option<Vec<Eliminado>>,
+    #[serde(default)]
+    pub versiones: Option<Vec<Versión>>,
+    #[serde(default)]
+    pub suscripciones: Option<Vec<Suscripción>>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct DatosCopia {
+    pub nombre: String,
+    pub momento: String,
+    pub tamaño: u64,
+}
+
+pub fn lee_configuración() -> ConfigCopias {
+    Config::figment()
+        .extract_inner::<ConfigCopias>("copias")
+        .unwrap_or(ConfigCopias {
+            carpeta: carpeta_por_defecto(),
+            intervalo: 0,
+            conservar: conservar_por_defecto(),
+            diarias: 0,
+        })
+}
+
+// Extraigo el momento del nombre del archivo, y así de paso compruebo que el nombre es válido
+fn momento_de(nombre: &str) -> Option<DateTime<Utc>> {
+    let momento = nombre.strip_prefix(PREFIJO)?.strip_suffix(EXTENSIÓN)?;
+    NaiveDateTime::parse_from_str(momento, FORMATO_MOMENTO)
+        .ok()
+        .map(|m| DateTime::<Utc>::from_naive_utc_and_offset(m, Utc))
+}
+
+// Lista las copias de la carpeta, de la más reciente a la más antigua
+pub fn lista_copias(config: &ConfigCopias) -> Result<Vec<DatosCopia>, String> {
+    let entradas = match std::fs::read_dir(&config.carpeta) {
+        Ok(entradas) => entradas,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(format!("{}: {}", config.carpeta, e)),
+    };
+
+    let mut copias: Vec<(DateTime<Utc>, DatosCopia)> = entradas
+        .map_while(Result::ok)
+        .filter_map(|entrada| {
+            let nombre = entrada.file_name().to_string_lossy().to_string();
+            let momento = momento_de(&nombre)?;
+            let tamaño = entrada.metadata().map(|m| m.len()).unwrap_or(0);
+            Some((
+                momento,
+                DatosCopia {
+                    nombre,
+                    momento: momento.to_rfc3339(),
+                    tamaño,
+                },
+            ))
+        })
+        .collect();
+    copias.sort_by_key(|c| std::cmp::Reverse(c.0));
+
+    Ok(copias.into_iter().map(|(_, datos)| datos).collect())
+}
+
+pub fn guarda_copia(config: &ConfigCopias, copia: &Copia) -> Result<String, String> {
+    std::fs::create_dir_all(&config.carpeta).map_err(|e| format!("{}: {}", config.carpeta, e))?;
+
+    let nombre = format!(
+        "{}{}{}",
+        PREFIJO,
+        Utc::now().format(FORMATO_MOMENTO),
+        EXTENSIÓN
+    );
+    let ruta = Path::new(&config.carpeta).join(&nombre);
+    let temporal = ruta.with_extension("tmp");
+    let error = |e: std::io::Error| format!("{}: {}", nombre, e);
+
+    // Igual que con los datos, escribo en un temporal y lo renombro al terminar
+    let archivo = std::fs::File::create(&temporal).map_err(error)?;
+    let mut compresor = GzEncoder::new(archivo, Compression::default());
+    compresor
+        .write_all(
+            &serde_json::to_vec(&esquema::envuelve(json!(copia))).map_err(|e| e.to_string())?,
+        )
+        .map_err(error)?;
+    compresor
+        .finish()
+        .and_then(|f| f.sync_all())
+        .map_err(error)?;
+    std::fs::rename(&temporal, &ruta).map_err(error)?;
+
+    aplica_retención(config)?;
+
+    Ok(nombre)
+}
+
+pub fn lee_copia(config: &ConfigCopias, nombre: &str) -> Result<Copia, String> {
+    // Sólo acepto nombres de copias, nunca rutas a otros archivos
+    if momento_de(nombre).is_none() || nombre.contains('/') || nombre.contains('\\') {
+        return Err(format!("Nombre de copia no válido: {}", nombre));
+    }
+    let ruta: PathBuf = Path::new(&config.carpeta).join(nombre);
+    let archivo = std::fs::File::open(&ruta).map_err(|e| format!("{}: {}", nombre, e))?;
+    let mut contenido = String::new();
+    GzDecoder::new(archivo)
+        .read_to_string(&mut contenido)
+        .map_err(|e| format!("{}: {}", nombre, e))?;
+
+    // Las copias antiguas pueden tener un formato anterior, así que las migro como los datos
+    let error = |e: String| format!("{}: {}", nombre, e);
+    let (versión, mut datos) = esquema::desenvuelve(
+        serde_json::from_str::<Value>(&contenido).map_err(|e| error(e.to_string()))?,
+    );
+    for colección in ["documentos", "usuarios"] {
+        if let Some(valor) = datos.get_mut(colección) {
+            *valor = esquema::migra(colección, versión, valor.take()).map_err(error)?;
+        }
+    }
+    serde_json::from_value::<Copia>(datos).map_err(|e| error(e.to_string()))
+}
+
+// Conservo las "conservar" copias más recientes y la última de cada uno de los últimos
+// "diarias" días. Borro todas las demás.
+fn aplica_retención(config: &ConfigCopias) -> Result<(), String> {
+    let copias = lista_copias(config)?;
+    let mut días: HashSet<String> = HashSet::new();
+
+    for (posición, copia) in copias.iter().enumerate() {
+        let día = copia.momento[..10].to_string();
+        let es_reciente = posición < config.conservar;
+        let es_diaria = !días.contains(&día) && días.len() < config.diarias;
+        if es_diaria {
+            días.insert(día);
+        }
+        if !es_reciente && !es_diaria {
+            std::fs::remove_file(Path::new(&config.carpeta).join(&copia.nombre))
+                .map_err(|e| format!("{}: {}", copia.nombre, e))?;
+        }
+    }
+
+    Ok(())
+}
+
+// Leo una copia completa de los datos guardados en el almacén
+pub fn copia_del_almacén(almacén: &dyn Almacén) -> Result<Copia, String> {
+    Ok(Copia {
+        documentos: almacén.carga_documentos()?.unwrap_or_default(),
+        usuarios: almacén.carga_usuarios()?.unwrap_or_default(),
+        contadores: almacén.carga_contadores()?.unwrap_or_default(),
+        papelera: Some(almacén.carga_papelera()?.unwrap_or_default()),
+        versiones: Some(almacén.carga_versiones()?.unwrap_or_default()),
+        suscripciones: Some(almacén.carga_suscripciones()?.unwrap_or_default()),
+    })
+}
+
+// Resumen de lo que cambiaría al restaurar la copia sobre los datos actuales
+pub fn diferencias(actual: &Copia, copia: &Copia) -> Value {
+    fn compara<T: Serialize, K: Clone + Eq + Hash + Ord + Serialize>(
+        actuales: &[T],
+        copiados: &[T],
+        id: fn(&T) -> K,
+    ) -> Value {
+        let actuales: HashMap<K, Value> = actuales
+            .iter()
+            .map(|e| (id(e), serde_json::to_value(e).unwrap()))
+            .collect();
+        let copiados: HashMap<K, Value> = copiados
+            .iter()
+            .map(|e| (id(e), serde_json::to_value(e).unwrap()))
+            .collect();
+
+        let mut recuperados: Vec<K> = copiados
+            .keys()
+            .filter(|id| !actuales.contains_key(id))
+            .cloned()
+            .collect();
+        let mut perdidos: Vec<K> = actuales
+            .keys()
+            .filter(|id| !copiados.contains_key(id))
+            .cloned()
+            .collect();
+        let mut cambiados: Vec<K> = copiados
+            .iter()
+            .filter(|(id, v)| actuales.get(id).is_some_and(|a| a != *v))
+            .map(|(id, _)| id.clone())
+            .collect();
+        recuperados.sort_unstable();
+        perdidos.sort_unstable();
+        cambiados.sort_unstable();
+
+        json!({
+            "recuperados": recuperados,
+            "perdidos": perdidos,
+            "cambiados": cambiados
+        })
+    }
+
+    let mut cambios = json!({
+        "documentos": compara(&actual.documentos, &copia.documentos, |d| d.id),
+        "usuarios": compara(&actual.usuarios, &copia.usuarios, |u| u.id),
+    });
+    let vacía = vec![];
+    if let Some(papelera) = &copia.papelera {
+        let actual = actual.papelera.as_ref().unwrap_or(&vacía);
+        cambios["papelera"] = compara(actual, papelera, |e| e.id);
+    }
+    let vacía = vec![];
+    if let Some(versiones) = &copia.versiones {
+        let actual = actual.versiones.as_ref().unwrap_or(&vacía);
+        cambios["versiones"] = compara(actual, versiones, |v| v.nombre.clone());
+    }
+    let vacía = vec![];
+    if let Some(suscripciones) = &copia.suscripciones {
+        let actual = actual.suscripciones.as_ref().unwrap_or(&vacía);
+        cambios["suscripciones"] = compara(actual, suscripciones, |s| s.id);
+    }
+    cambios
+}
+
+/*
+ * Copias automáticas
+ */
+
+// Al arrancar el servidor, lanzo una tarea que hace una copia cada "intervalo" segundos.
+// La tarea lee los datos de su propio acceso al almacén, que siempre está al día porque cada
+// cambio se guarda en el momento.
+pub struct CopiasProgramadas;
+
+#[rocket::async_trait]
+impl Fairing for CopiasProgramadas {
+    fn info(&self) -> Info {
+        Info {
+            name: "Copias de seguridad programadas",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, _rocket: &Rocket<Orbit>) {
+        let config = lee_configuración();
+        if config.intervalo == 0 {
+            return;
+        }
+
+        let almacén = match almacen::abre(&almacen::lee_configuración()) {
+            Ok(almacén) => almacén,
+            Err(e) => {
+                println!("No puedo programar las copias de seguridad: {}", e);
+                return;
+            }
+        };
+
+        rocket::tokio::spawn(async move {
+            let mut reloj = rocket::tokio::time::interval(Duration::from_secs(config.intervalo));
+            // El primer tic es inmediato; no hace falta copiar nada más arrancar
+            reloj.tick().await;
+            loop {
+                reloj.tick().await;
+                match copia_del_almacén(almacén.as_ref())
+                    .and_then(|copia| guarda_copia(&config, &copia))
+                {
+                    Ok(nombre) => println!("Copia de seguridad guardada: {}", nombre),
+                    Err(e) => println!("No he podido hacer la copia de seguridad: {}", e),
+                }
+            }
+        });
+    }
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[get("/copias", format = "json")]
+async fn lee_copias(_usuario: Usuario, _administrador: Administrador) -> Result<Value, Status> {
+    let copias = lista_copias(&lee_configuración()).map_err(|e| {
+        println!("No he podido leer las copias: {}", e);
+        Status::InternalServerError
+    })?;
+
+    Ok(json!({ "estado": "ok", "copias": copias }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/copias")]
+async fn crea_copia(
+    estado_documentos: &State<Documentos>,
+    estado_usuarios: &State<Usuarios>,
+    estado_papelera: &State<Papelera>,
+    estado_versiones: &State<Versiones>,
+    webhooks: &State<Webhooks>,
+    contadores: &State<Contadores>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let copia = Copia {
+        documentos: estado_documentos.lock().await.clone(),
+        usuarios: estado_usuarios.lock().await.clone(),
+        contadores: contadores.siguientes(),
+        papelera: Some(estado_papelera.lock().await.clone()),
+        versiones: Some(estado_versiones.lock().await.clone()),
+        suscripciones: Some(webhooks.suscripciones().await),
+    };
+
+    let nombre = guarda_copia(&lee_configuración(), &copia).map_err(|e| {
+        println!("No he podido hacer la copia de seguridad: {}", e);
+        Status::InternalServerError
+    })?;
+
+    auditoría.registra(Entrada::nueva(
+        "crea_copia",
+        Some(&usuario),
+        format!("copia/{}", nombre),
+        ip,
+    ));
+
+    Ok(json!({ "estado": "ok", "nombre": nombre }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/copias/<nombre>/restauración?<simulacro>")]
+async fn restaura_copia(
+    nombre: String,
+    simulacro: Option<bool>,
+    estado_documentos: &State<Documentos>,
+    estado_usuarios: &State<Usuarios>,
+    estado_sesiones: &State<SesionesActivas>,
+    estado_papelera: &State<Papelera>,
+    estado_versiones: &State<Versiones>,
+    webhooks: &State<Webhooks>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    contadores: &State<Contadores>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let config = lee_configuración();
+    let copia = lee_copia(&config, &nombre).map_err(|e| {
+        println!("No he podido leer la copia: {}", e);
+        Status::NotFound
+    })?;
+
+    let mut documentos = documentos::bloquea_para_cambiar(estado_documentos, almacén).await?;
+    let mut usuarios = usuarios::bloquea_para_cambiar(estado_usuarios, almacén).await?;
+    let mut papelera = estado_papelera.lock().await;
+    let mut versiones = estado_versiones.lock().await;
+    let actual = Copia {
+        documentos: documentos.clone(),
+        usuarios: usuarios.clone(),
+        contadores: contadores.siguientes(),
+        papelera: Some(papelera.clone()),
+        versiones: Some(versiones.clone()),
+        suscripciones: Some(webhooks.suscripciones().await),
+    };
+    let cambios = diferencias(&actual, &copia);
+
+    if simulacro.unwrap_or(false) {
+        return Ok(json!({ "estado": "ok", "simulacro": true, "cambios": cambios }));
+    }
+
+    // Antes de restaurar, guardo una copia del estado actual para poder deshacerlo
+    let previa = guarda_copia(&config, &actual).map_err(|e| {
+        println!(
+            "No he podido hacer la copia previa a la restauración: {}",
+            e
+        );
+        Status::InternalServerError
+    })?;
+
+    // Preparo los datos restaurados y los guardo. Sólo los paso a memoria cuando está todo
+    // guardado; si algo falla, vuelvo a guardar los datos actuales, que siguen en memoria.
+    let mut copia = copia;
+    avanza_revisiones(&documentos, &mut copia.documentos);
+    avanza_revisiones(&usuarios, &mut copia.usuarios);
+    let nueva_papelera = copia.papelera.take().unwrap_or_else(|| papelera.clone());
+    let nuevas_versiones = copia.versiones.take().unwrap_or_else(|| versiones.clone());
+    let guardado = async {
+        documentos::guarda_copia_documentos(almacén, índice, &copia.documentos).await?;
+        usuarios::guarda_copia_usuarios(almacén, &copia.usuarios).await?;
+        papelera::guarda_copia_papelera(almacén, &nueva_papelera).await?;
+        versiones::guarda_copia_versiones(almacén, &nuevas_versiones).await?;
+        // Las suscripciones van las últimas porque ésta ya las pasa a memoria
+        if let Some(suscripciones) = copia.suscripciones.take() {
+            webhooks.restaura(almacén, suscripciones).await?;
+        }
+        Ok::<(), Status>(())
+    }
+    .await;
+    if let Err(estado) = guardado {
+        let _ = documentos::guarda_copia_documentos(almacén, índice, &documentos).await;
+        let _ = usuarios::guarda_copia_usuarios(almacén, &usuarios).await;
+        let _ = papelera::guarda_copia_papelera(almacén, &papelera).await;
+        let _ = versiones::guarda_copia_versiones(almacén, &versiones).await;
+        return Err(estado);
+    }
+    *documentos = copia.documentos;
+    *usuarios = copia.usuarios;
+    *papelera = nueva_papelera;
+    *versiones = nuevas_versiones;
+    eventos.emite(Evento::recarga());
+
+    // Los contadores nunca retroceden, para no reutilizar identificadores. Los documentos de la
+    // papelera conservan sus ids, para poder restaurarlos.
+    contadores.respeta("documentos", documentos.iter().map(|d| d.id));
+    contadores.respeta(
+        "documentos",
+        papelera
+            .iter()
+            .flat_map(|e| e.documentos.iter().map(|d| d.id)),
+    );
+    contadores.respeta("usuarios", usuarios.iter().map(|u| u.id));
+    contadores.respeta("suscripciones", webhooks.ids().await.into_iter());
+    for (colección, siguiente) in copia.contadores {
+        contadores.asegura(&colección, siguiente);
+    }
+    contadores.guarda(almacén)?;
+
+    // Cierro las sesiones de los usuarios que ya no existen. Suelto antes los demás datos,
+    // porque los guardas de acceso bloquean primero las sesiones y después los usuarios.
+    let restaurados: HashSet<Id> = usuarios.iter().map(|u| u.id).collect();
+    drop(versiones);
+    drop(papelera);
+    drop(usuarios);
+    drop(documentos);
+    let mut sesiones = estado_sesiones.lock().await;
+    sesiones.retain(|_, s| restaurados.contains(&s.usuario));
+    sesion::guarda_copia_sesiones(almacén, &sesiones).await?;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "restaura_copia",
+            Some(&usuario),
+            format!("copia/{}", nombre),
+            ip,
+        )
+        .después(json!({ "copia_previa": previa, "cambios": cambios })),
+    );
+
+    Ok(json!({
+        "estado": "ok",
+        "simulacro": false,
+        "cambios": cambios,
+        "copia_previa": previa
+    }))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![lee_copias, crea_copia, restaura_copia]
+}
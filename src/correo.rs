@@ -0,0 +1,155 @@
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+
+use rocket::serde::Deserialize;
+use rocket::Config;
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/*
+ * Correo electrónico
+ */
+
+// Mensaje que quiero enviar, sin importar cómo se entregue
+pub struct Mensaje {
+    pub destinatario: String,
+    pub asunto: String,
+    pub cuerpo: String,
+}
+
+// Cualquier forma de entregar un mensaje debe implementar este rasgo
+pub trait Cartero: Send + Sync {
+    fn envía(&self, mensaje: &Mensaje) -> Result<(), String>;
+}
+
+// Guardaré el cartero elegido en la configuración como estado de Rocket
+pub type Correo = Arc<dyn Cartero>;
+
+// Entregar un mensaje puede tardar varios segundos y los carteros bloquean el hilo, así que
+// lo hago en un hilo aparte y no espero al resultado
+pub fn envía_en_segundo_plano(correo: &Correo, mensaje: Mensaje) {
+    let correo = correo.clone();
+    rocket::tokio::task::spawn_blocking(move || {
+        if let Err(e) = correo.envía(&mensaje) {
+            println!(
+                "No he podido enviar el correo a {}: {}",
+                mensaje.destinatario, e
+            );
+        }
+    });
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigCorreo {
+    // "smtp" o "archivo"
+    tipo: String,
+    remitente: String,
+    // Sólo para el cartero de archivos
+    carpeta: Option<String>,
+    // Sólo para el cartero SMTP
+    servidor: Option<String>,
+    puerto: Option<u16>,
+    usuario: Option<String>,
+    clave: Option<String>,
+}
+
+/*
+ * Cartero SMTP, para producción
+ */
+
+pub struct CarteroSmtp {
+    remitente: Mailbox,
+    transporte: SmtpTransport,
+}
+
+impl CarteroSmtp {
+    pub fn nuevo(config: &ConfigCorreo) -> CarteroSmtp {
+        let servidor = config
+            .servidor
+            .clone()
+            .expect("Falta el servidor SMTP en la configuración del correo");
+        let mut constructor = SmtpTransport::relay(&servidor).unwrap();
+        if let Some(puerto) = config.puerto {
+            constructor = constructor.port(puerto);
+        }
+        if let (Some(usuario), Some(clave)) = (&config.usuario, &config.clave) {
+            constructor = constructor.credentials(Credentials::new(usuario.clone(), clave.clone()));
+        }
+        CarteroSmtp {
+            remitente: config.remitente.parse().unwrap(),
+            transporte: constructor.build(),
+        }
+    }
+}
+
+impl Cartero for CarteroSmtp {
+    fn envía(&self, mensaje: &Mensaje) -> Result<(), String> {
+        let destinatario: Mailbox = mensaje
+            .destinatario
+            .parse()
+            .map_err(|e| format!("Destinatario no válido: {}", e))?;
+        let correo = Message::builder()
+            .from(self.remitente.clone())
+            .to(destinatario)
+            .subject(mensaje.asunto.clone())
+            .body(mensaje.cuerpo.clone())
+            .map_err(|e| e.to_string())?;
+        self.transporte
+            .send(&correo)
+            .map(|_| ())
+            .map_err(|e| e.to_string())
+    }
+}
+
+/*
+ * Cartero de archivos, para desarrollo y pruebas: deja cada mensaje en una carpeta
+ */
+
+pub struct CarteroArchivo {
+    remitente: String,
+    carpeta: PathBuf,
+}
+
+impl CarteroArchivo {
+    pub fn nuevo(config: &ConfigCorreo) -> CarteroArchivo {
+        let carpeta = PathBuf::from(
+            config
+                .carpeta
+                .clone()
+                .unwrap_or_else(|| "correo".to_string()),
+        );
+        CarteroArchivo {
+            remitente: config.remitente.clone(),
+            carpeta,
+        }
+    }
+}
+
+impl Cartero for CarteroArchivo {
+    fn envía(&self, mensaje: &Mensaje) -> Result<(), String> {
+        std::fs::create_dir_all(&self.carpeta).map_err(|e| e.to_string())?;
+        let instante = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let nombre = format!("{}-{:09}.eml", instante.as_secs(), instante.subsec_nanos());
+        let contenido = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n",
+            self.remitente, mensaje.destinatario, mensaje.asunto, mensaje.cuerpo
+        );
+        std::fs::write(self.carpeta.join(nombre), contenido).map_err(|e| e.to_string())
+    }
+}
+
+pub fn prepara_estado_inicial() -> Correo {
+    let config: ConfigCorreo = Config::figment()
+        .extract_inner::<ConfigCorreo>("correo")
+        .unwrap();
+
+    match config.tipo.as_str() {
+        "smtp" => Arc::new(CarteroSmtp::nuevo(&config)),
+        "archivo" => Arc::new(CarteroArchivo::nuevo(&config)),
+        otro => panic!("Tipo de correo desconocido: {}", otro),
+    }
+}
@@ -21,6 +21,7 @@ impl Fairing for CORS {
             "POST, GET, PATCH, OPTIONS",
         ));
         response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
+        response.set_header(Header::new("Access-Control-Expose-Headers", "ETag"));
         response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
     }
 }
@@ -1,35 +1,72 @@
-use rocket::http::Status;
+use rocket::http::{RawStr, Status};
 use rocket::serde::json::{json, Json, Value};
 use rocket::serde::{Deserialize, Serialize};
-use rocket::tokio::sync::Mutex;
+use rocket::tokio::sync::{Mutex, MutexGuard};
 use rocket::State;
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::bloqueos::{self, Bloqueos};
+use super::condiciones::{self, Etiquetada, Revisado, SiCoincide, SiNoCoincide};
+use super::contadores::Contadores;
+use super::enlaces::{self, ÍndiceEnlaces};
+use super::eventos::{Evento, Eventos};
 use super::id::Id;
+use super::integridad;
+use super::papelera::{self, Papelera};
 use super::roles::Editor;
+use super::traducciones::{self, EnIdioma, IdiomasAceptados, Traducción};
 use super::usuarios::Usuario;
+use super::versiones::{self, Versiones};
 
 /**
  * Documentos
  */
 
-// Inicio el contador en 1, porque el nodo 0 ya debería existir
-// El nodo 0 es la raíz del árbol de documentos y debe existir para contener los hijos.
-static mut CONTADOR_IDS: Id = 1;
+// Guardaré los documentos en este vector, respaldado por el almacén elegido en la
+// configuración: archivos JSON o una base de datos SQLite.
+pub type Documentos = Mutex<Vec<Documento>>;
 
-unsafe fn lee_nuevo_id() -> Id {
-    let id: Id = CONTADOR_IDS;
-    CONTADOR_IDS = CONTADOR_IDS + 1;
-    return id;
+// Bloqueo la lista para cambiarla, salvo que alguien haya cambiado los documentos guardados fuera
+// del servidor y todavía no los haya recargado: en ese caso no cambio nada y respondo con un 409
+pub async fn bloquea_para_cambiar<'a>(
+    lista: &'a Documentos,
+    almacén: &Almacenamiento,
+) -> Result<MutexGuard<'a, Vec<Documento>>, Status> {
+    let lista = lista.lock().await;
+    if almacén.cambiado_fuera("documentos").unwrap_or(false) {
+        println!("Los documentos guardados han cambiado fuera del servidor; no los cambio");
+        return Err(Status::Conflict);
+    }
+    Ok(lista)
 }
 
-// Guardaré los documentos en este vector, respaldado por un archivo en el disco duro:
-// "documentos.json", de este modo no preciso usar una BBDD.
-pub type Documentos = Mutex<Vec<Documento>>;
-
-async fn guarda_copia_documentos(documentos: String) {
+pub async fn guarda_copia_documentos(
+    almacén: &Almacenamiento,
+    índice: &ÍndiceEnlaces,
+    documentos: &[Documento],
+) -> Result<(), Status> {
     println!("¡Guardando documentos!");
-    println!("{}", documentos);
-    std::fs::write("documentos.json", documentos).unwrap();
+    // Si alguien ha cambiado los documentos a mano desde la última vez que los leí, no los
+    // sobrescribo: la vigilancia los recargará en cuanto sean válidos
+    if almacén.cambiado_fuera("documentos").unwrap_or(false) {
+        println!("Los documentos guardados han cambiado fuera del servidor; no los sobrescribo");
+        return Err(Status::Conflict);
+    }
+    // Si no puedo guardar, aviso y respondo con un error en lugar de detener el servidor
+    almacén
+        .guarda_documentos(documentos)
+        .and_then(|_| almacén.anota_huella("documentos"))
+        .map_err(|e| {
+            println!("No he podido guardar los documentos: {}", e);
+            Status::InternalServerError
+        })?;
+    // Los documentos guardados son los que quedan en memoria, así que el índice ya puede verlos
+    índice.actualiza(documentos);
+    Ok(())
 }
 
 // Estructuras con el contenido del documento y de la lista de todos los documentos.
@@ -37,11 +74,24 @@ async fn guarda_copia_documentos(documentos: String) {
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 pub struct Documento {
-    id: Id,
-    padre: Id,
-    título: String,
-    contenido: String,
-    hijos: Vec<Id>,
+    pub id: Id,
+    pub padre: Id,
+    pub título: String,
+    pub contenido: String,
+    pub hijos: Vec<Id>,
+    // Aumenta con cada cambio; lo envío como ETag
+    #[serde(default)]
+    pub revisión: u64,
+    // Traducciones del título y el contenido, por idioma
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub traducciones: BTreeMap<String, Traducción>,
+    // Nombre que lo identifica entre sus hermanos al importarlo desde archivos Markdown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slug: Option<String>,
+    // Ruta de la que salió en la última importación, como "/tutorial/instalacion". Al volver a
+    // importar sólo cambio o borro los documentos que la tienen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origen: Option<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -59,162 +109,633 @@ impl Clone for Documento {
             título: self.título.clone(),
             contenido: self.contenido.clone(),
             hijos: self.hijos.clone(),
+            revisión: self.revisión,
+            traducciones: self.traducciones.clone(),
+            slug: self.slug.clone(),
+            origen: self.origen.clone(),
         }
     }
 }
 
+impl Revisado for Documento {
+    fn id(&self) -> Id {
+        self.id
+    }
+    fn revisión(&self) -> u64 {
+        self.revisión
+    }
+    fn cambia_revisión(&mut self, revisión: u64) {
+        self.revisión = revisión;
+    }
+}
+
+impl Documento {
+    // Resumen del documento para el registro de auditoría
+    pub fn resumen(&self) -> Value {
+        json!({
+            "título": self.título,
+            "padre": self.padre,
+            "hijos": self.hijos,
+            "longitud": self.contenido.len()
+        })
+    }
+}
+
+// Primeros segmentos de ruta que ya usa el servidor, y que no pueden empezar la ruta de un slug
+const SEGMENTOS_RESERVADOS: &[&str] = &["api", "documento"];
+
+// Un slug nuevo no puede estar vacío ni contener "/", no puede repetir el de un hermano y, en
+// los hijos de la raíz, no puede ser un segmento reservado
+pub fn comprueba_slug(documentos: &[Documento], padre: Id, slug: &str) -> Result<(), Status> {
+    if slug.is_empty() || slug.contains('/') {
+        return Err(Status::BadRequest);
+    }
+    if padre == 0 && SEGMENTOS_RESERVADOS.contains(&slug) {
+        return Err(Status::Forbidden);
+    }
+    if documentos
+        .iter()
+        .any(|d| d.padre == padre && d.id != 0 && d.slug.as_deref() == Some(slug))
+    {
+        return Err(Status::Forbidden);
+    }
+    Ok(())
+}
+
+// Dirección pública de la página de un documento, relativa a url_sitio. Si el documento y todos
+// sus ancestros tienen slug, es la ruta de sus slugs, como "/tutorial/instalacion"; si no,
+// "/documento/<id>". La de la raíz es "/".
+pub fn dirección(documentos: &[Documento], id: Id) -> String {
+    if id == 0 {
+        return "/".to_string();
+    }
+    let mut camino = integridad::ancestros(documentos, id);
+    camino.retain(|a| *a != 0);
+    camino.reverse();
+    camino.push(id);
+    let slugs: Option<Vec<&str>> = camino
+        .iter()
+        .map(|i| {
+            documentos
+                .iter()
+                .find(|d| d.id == *i)
+                .and_then(|d| d.slug.as_deref())
+        })
+        .collect();
+    match slugs {
+        Some(slugs) if !SEGMENTOS_RESERVADOS.contains(&slugs[0]) => slugs
+            .iter()
+            .map(|s| format!("/{}", RawStr::new(s).percent_encode()))
+            .collect(),
+        _ => format!("/documento/{}", id),
+    }
+}
+
+// El documento de una dirección pública, ya separada en segmentos: "documento/<id>" o la ruta
+// de slugs desde la raíz. No encuentra la raíz, que no tiene página propia.
+pub fn busca_por_dirección(documentos: &[Documento], segmentos: &[&str]) -> Option<Id> {
+    if let ["documento", id] = segmentos {
+        let id = id.parse::<Id>().ok()?;
+        return documentos
+            .iter()
+            .any(|d| d.id == id && id != 0)
+            .then_some(id);
+    }
+    if segmentos.is_empty() || SEGMENTOS_RESERVADOS.contains(&segmentos[0]) {
+        return None;
+    }
+    let mut actual = documentos.iter().find(|d| d.id == 0)?;
+    for segmento in segmentos {
+        actual = actual.hijos.iter().find_map(|h| {
+            documentos
+                .iter()
+                .find(|d| d.id == *h && d.slug.as_deref() == Some(*segmento))
+        })?;
+    }
+    Some(actual.id)
+}
+
 // Puntos de entrada de la api de documentos:
 
-#[get("/documentos", format = "json")]
-async fn lee_documentos(lista: &State<Documentos>, _editor: Editor) -> Value {
+// Con ?versión=<nombre> leo los documentos de una versión guardada, o de la última publicada
+// con ?versión=estable
+#[get("/documentos?<versión>", format = "json")]
+async fn lee_documentos(
+    versión: Option<&str>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    if !versiones::es_desarrollo(versión) {
+        let versiones = versiones.lock().await;
+        let versión = versiones::busca(&versiones, versión.unwrap()).ok_or(Status::NotFound)?;
+        return Ok(json!(versión.documentos));
+    }
     let lista = lista.lock().await;
 
-    json!(*lista)
+    Ok(json!(*lista))
 }
 
+#[allow(clippy::too_many_arguments)]
 #[post("/documento", format = "json", data = "<documento>")]
 async fn crea_documento(
     documento: Json<Documento>,
     lista: &State<Documentos>,
-    _usuario: Usuario,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    contadores: &State<Contadores>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
     _editor: Editor,
-) -> Value {
-    let mut lista = lista.lock().await;
-    let identificador: Id;
-
-    unsafe {
-        identificador = lee_nuevo_id();
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut lista = bloquea_para_cambiar(lista, almacén).await?;
+    let mut doc = documento.into_inner();
+    // El nodo 0 es la raíz del árbol de documentos y debe existir para contener los hijos.
+    // Compruebo el padre antes de gastar un identificador.
+    let id_padre = lista
+        .iter()
+        .position(|d| d.id == doc.padre)
+        .ok_or(Status::Forbidden)?;
+    if let Some(slug) = doc.slug.as_deref() {
+        comprueba_slug(&lista, doc.padre, slug)?;
     }
+    // Las traducciones se añaden después, cada una con su ruta, y sólo la importación marca
+    // los documentos que crea
+    doc.traducciones = BTreeMap::new();
+    doc.origen = None;
 
-    let mut doc = documento.into_inner();
+    let identificador: Id = contadores.nuevo_id(almacén, "documentos")?;
     doc.id = identificador;
+    doc.revisión = 1;
+
+    let resumen = doc.resumen();
+    let mut nuevos = lista.clone();
+    nuevos[id_padre].hijos.push(identificador);
+    nuevos[id_padre].revisión += 1;
+    nuevos.push(doc);
+
+    guarda_copia_documentos(almacén, índice, &nuevos).await?;
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "crea_documento",
+            Some(&usuario),
+            format!("documento/{}", identificador),
+            ip,
+        )
+        .después(resumen),
+    );
+    eventos.emite(Evento::nuevo(
+        "creado",
+        &lista[lista.len() - 1],
+        &lista,
+        &usuario,
+    ));
+
+    Ok(json!({ "estado": "ok", "id": Some(identificador) }))
+}
 
-    let id_padre = lista.iter().position(|d| d.id == doc.padre).unwrap();
-    lista[id_padre].hijos.push(identificador);
-
-    lista.push(doc);
-
-    let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-    guarda_copia_documentos(j).await;
+// El documento se sirve en el idioma de ?idioma=, o en el preferido de Accept-Language si está
+// traducido; si no, en el original
+#[allow(clippy::too_many_arguments)]
+#[get("/documento/<id>?<versión>&<idioma>", format = "json")]
+async fn lee_documento(
+    id: Id,
+    versión: Option<&str>,
+    idioma: Option<&str>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    bloqueos: &State<Bloqueos>,
+    aceptados: IdiomasAceptados,
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<EnIdioma<Value>>, Status> {
+    // El original lleva la revisión como etiqueta, para poder usarla después en If-Match; las
+    // traducciones llevan además el idioma
+    let etiqueta = |doc: &Documento, elegido: &str| {
+        if elegido == traducciones::lee_configuración().origen {
+            condiciones::etiqueta(doc.revisión)
+        } else {
+            condiciones::etiqueta_en(doc.revisión, elegido)
+        }
+    };
 
-    json!({ "estado": "ok", "id": Some(identificador) })
+    if !versiones::es_desarrollo(versión) {
+        let versiones = versiones.lock().await;
+        let versión = versiones::busca(&versiones, versión.unwrap()).ok_or(Status::NotFound)?;
+        let doc = versión
+            .documentos
+            .iter()
+            .find(|d| d.id == id)
+            .ok_or(Status::NotFound)?;
+        let elegido = traducciones::elige(doc, idioma, &aceptados);
+        let respuesta = EnIdioma {
+            respuesta: traducciones::en_idioma(doc, &elegido),
+            idioma: elegido.clone(),
+        };
+        return Ok(Etiquetada::con_etiqueta(
+            etiqueta(doc, &elegido),
+            &si_no_coincide,
+            respuesta,
+        ));
+    }
+    let lista = lista.lock().await;
+    let doc = lista.iter().find(|d| d.id == id).ok_or(Status::NotFound)?;
+    let elegido = traducciones::elige(doc, idioma, &aceptados);
+    let mut respuesta = traducciones::en_idioma(doc, &elegido);
+    // Incluyo quién está editando el documento, si alguien lo tiene bloqueado
+    let mut bloqueos = bloqueos.lock().await;
+    if let Some(bloqueo) = bloqueos::activo(&mut bloqueos, id) {
+        respuesta["bloqueo"] = bloqueo.resumen();
+    }
+    Ok(Etiquetada::con_etiqueta(
+        etiqueta(doc, &elegido),
+        &si_no_coincide,
+        EnIdioma {
+            respuesta,
+            idioma: elegido,
+        },
+    ))
 }
 
-#[get("/documento/<id>", format = "json")]
-async fn lee_documento(id: Id, lista: &State<Documentos>) -> Option<Json<Documento>> {
-    let lista = lista.lock().await;
-    let i = lista.iter().position(|d| d.id == id).unwrap();
-    let doc: Documento = lista[i].clone();
-    Some(Json(Documento {
-        id: doc.id,
-        padre: doc.padre,
-        título: doc.título.clone(),
-        contenido: doc.contenido.clone(),
-        hijos: doc.hijos.clone(),
-    }))
-}
-
-#[patch("/documento/<id>", format = "json", data = "<documento>")]
+// Al mover un documento con ?reescribe=true, cambio también los enlaces a las direcciones
+// públicas del documento y sus descendientes que han dejado de existir. Los enlaces a
+// "/documento/<id>" no necesitan cambiar.
+#[allow(clippy::too_many_arguments)]
+#[patch(
+    "/documento/<id>?<forzar>&<reescribe>",
+    format = "json",
+    data = "<documento>"
+)]
 async fn cambia_documento(
     id: Id,
+    forzar: Option<bool>,
+    reescribe: Option<bool>,
     documento: Json<Documento>,
     lista: &State<Documentos>,
-    _usuario: Usuario,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    bloqueos: &State<Bloqueos>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
     _editor: Editor,
-) -> Option<Json<Documento>> {
-    let mut lista = lista.lock().await;
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
+) -> Result<Etiquetada<Json<Documento>>, Status> {
+    let mut lista = bloquea_para_cambiar(lista, almacén).await?;
     let doc = documento.into_inner();
-    let i = lista.iter().position(|d| d.id == id).unwrap();
-    (*lista)[i].padre = doc.padre;
-    (*lista)[i].título = doc.título;
-    (*lista)[i].contenido = doc.contenido;
+    let i = lista
+        .iter()
+        .position(|d| d.id == id)
+        .ok_or(Status::NotFound)?;
+    si_coincide.comprueba((*lista)[i].revisión)?;
+    bloqueos::comprueba(bloqueos, id, &usuario, forzar.unwrap_or(false)).await?;
+    let antes = (*lista)[i].resumen();
+    let padre_anterior = (*lista)[i].padre;
+    let ancestros_anteriores = integridad::ancestros(&lista, id);
+    let direcciones_anteriores: Vec<(Id, String)> = papelera::subárbol(&lista, id)
+        .into_iter()
+        .map(|d| (d, dirección(&lista, d)))
+        .collect();
+    // Hago los cambios sobre una copia, y sólo la pongo en su lugar cuando la he guardado
+    let mut nuevos = lista.clone();
+    let acción = if padre_anterior != doc.padre {
+        // La raíz no se mueve, el nuevo padre debe existir y no puedo colgar un documento
+        // de sí mismo ni de uno de sus descendientes
+        if id == 0
+            || !nuevos.iter().any(|d| d.id == doc.padre)
+            || integridad::crearía_ciclo(&nuevos, id, doc.padre)
+        {
+            return Err(Status::Forbidden);
+        }
+        // Su slug no puede repetir el de un hermano en el nuevo padre
+        if let Some(slug) = nuevos[i].slug.as_deref() {
+            comprueba_slug(&nuevos, doc.padre, slug)?;
+        }
+        // Saco el documento de la lista de hijos de su padre anterior y lo añado al final
+        // de la del nuevo
+        for d in nuevos.iter_mut() {
+            if d.id == padre_anterior {
+                d.hijos.retain(|&h| h != id);
+                d.revisión += 1;
+            }
+            if d.id == doc.padre && !d.hijos.contains(&id) {
+                d.hijos.push(id);
+                d.revisión += 1;
+            }
+        }
+        "mueve_documento"
+    } else {
+        "cambia_documento"
+    };
+    nuevos[i].padre = doc.padre;
+    nuevos[i].título = doc.título;
+    nuevos[i].contenido = doc.contenido;
+    nuevos[i].revisión += 1;
     //No modifico la lista de hijos
     //(*lista)[i].hijos = doc.hijos;
 
-    let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-    guarda_copia_documentos(j).await;
+    let mut reescritos: Vec<Id> = vec![];
+    if acción == "mueve_documento" && reescribe.unwrap_or(false) {
+        let cambios: HashMap<Vec<String>, String> = direcciones_anteriores
+            .iter()
+            .filter_map(|(d, anterior)| {
+                let nueva = dirección(&nuevos, *d);
+                (nueva != *anterior).then(|| (enlaces::segmentos(anterior), nueva))
+            })
+            .collect();
+        if !cambios.is_empty() {
+            for d in nuevos.iter_mut() {
+                let mut cambiado = false;
+                let contenido = enlaces::reubica(&d.contenido, &cambios);
+                cambiado |= contenido != d.contenido;
+                d.contenido = contenido;
+                for traducción in d.traducciones.values_mut() {
+                    let contenido = enlaces::reubica(&traducción.contenido, &cambios);
+                    cambiado |= contenido != traducción.contenido;
+                    traducción.contenido = contenido;
+                }
+                // El documento movido ya tiene una revisión nueva
+                if cambiado && d.id != id {
+                    d.revisión += 1;
+                    reescritos.push(d.id);
+                }
+            }
+        }
+    }
+
+    guarda_copia_documentos(almacén, índice, &nuevos).await?;
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(acción, Some(&usuario), format!("documento/{}", id), ip)
+            .antes(antes)
+            .después((*lista)[i].resumen()),
+    );
+    for reescrito in reescritos.iter() {
+        auditoría.registra(
+            Entrada::nueva(
+                "reescribe_enlaces",
+                Some(&usuario),
+                format!("documento/{}", reescrito),
+                ip,
+            )
+            .después(json!({ "movido": id })),
+        );
+    }
+
+    let tipo = if acción == "mueve_documento" {
+        "movido"
+    } else {
+        "cambiado"
+    };
+    eventos
+        .emite(Evento::nuevo(tipo, &lista[i], &lista, &usuario).antes_bajo(ancestros_anteriores));
+    for reescrito in reescritos {
+        let documento = lista.iter().find(|d| d.id == reescrito).unwrap();
+        eventos.emite(Evento::nuevo("cambiado", documento, &lista, &usuario));
+    }
 
-    return Some(Json((*lista)[i].clone()));
+    return Ok(Etiquetada::completa(
+        (*lista)[i].revisión,
+        Json((*lista)[i].clone()),
+    ));
 }
 
-#[delete("/documento/<id>")]
+#[allow(clippy::too_many_arguments)]
+#[delete("/documento/<id>?<forzar>&<recursivo>&<confirmación>&<rompe_enlaces>")]
 async fn borra_documento(
     id: Id,
+    forzar: Option<bool>,
+    recursivo: Option<bool>,
+    confirmación: Option<usize>,
+    rompe_enlaces: Option<bool>,
     lista: &State<Documentos>,
-    _usuario: Usuario,
+    índice: &State<ÍndiceEnlaces>,
+    papelera: &State<Papelera>,
+    almacén: &State<Almacenamiento>,
+    bloqueos: &State<Bloqueos>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
     _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
 ) -> Status {
-    let mut lista = lista.lock().await;
-    let i = lista.iter().position(|d| d.id == id).unwrap();
-    let id_hijo = (*lista)[i].id;
-    if ((*lista)[i].hijos.len() != 0) {
+    let mut lista = match bloquea_para_cambiar(lista, almacén).await {
+        Ok(lista) => lista,
+        Err(estado) => return estado,
+    };
+    let i = match lista.iter().position(|d| d.id == id) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+    if let Err(estado) = si_coincide.comprueba((*lista)[i].revisión) {
+        return estado;
+    }
+    // La raíz no se borra
+    if id == 0 {
         return Status::Forbidden;
     }
 
-    if (i != 0 && id_hijo != 0) {
-        let id_padre = lista.iter().position(|d| d.id == lista[i].padre).unwrap();
+    // Un documento con hijos sólo se borra con ?recursivo=true, y confirmando con
+    // ?confirmación=<n> el número de documentos que se van a borrar
+    let subárbol = papelera::subárbol(&lista, id);
+    if subárbol.len() > 1 {
+        if !recursivo.unwrap_or(false) {
+            return Status::Forbidden;
+        }
+        if confirmación != Some(subárbol.len()) {
+            return Status::PreconditionRequired;
+        }
+    }
+    // Si otros documentos enlazan con los que voy a borrar, sus enlaces quedarían rotos. Sólo
+    // los borro confirmándolo con ?rompe_enlaces=true; GET /documento/<id>/enlaces dice cuáles son.
+    if !rompe_enlaces.unwrap_or(false) && !índice.entrantes(&lista, &subárbol).is_empty() {
+        return Status::Conflict;
+    }
+    for documento in subárbol.iter() {
+        if let Err(estado) =
+            bloqueos::comprueba(bloqueos, *documento, &usuario, forzar.unwrap_or(false)).await
+        {
+            return estado;
+        }
+    }
+
+    // Preparo los cambios sobre una copia, y sólo la doy por buena si se guarda todo
+    let evento = Evento::nuevo("borrado", &lista[i], &lista, &usuario);
+    let antes = (*lista)[i].resumen();
+    let mut nuevos = lista.clone();
+    let eliminado = papelera::retira(&mut nuevos, id, &usuario.nombre);
+
+    let mut papelera = papelera.lock().await;
+    papelera.push(eliminado);
+    if let Err(estado) = papelera::guarda_copia_papelera(almacén, &papelera).await {
+        papelera.pop();
+        return estado;
+    }
+    if let Err(estado) = guarda_copia_documentos(almacén, índice, &nuevos).await {
+        papelera.pop();
+        let _ = papelera::guarda_copia_papelera(almacén, &papelera).await;
+        return estado;
+    }
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "borra_documento",
+            Some(&usuario),
+            format!("documento/{}", id),
+            ip,
+        )
+        .antes(antes)
+        .después(json!({ "papelera": true, "documentos": subárbol.len() })),
+    );
+    for documento in subárbol {
+        bloqueos::olvida(bloqueos, documento).await;
+    }
+    eventos.emite(evento);
+
+    Status::Accepted
+}
 
-        (*lista)[id_padre].hijos.retain(|&h| h != id_hijo);
+// Copia un documento con todos sus descendientes bajo "padre", o junto al original si no se
+// indica. Las copias reciben ids nuevos, y los enlaces entre documentos copiados, por id o por
+// dirección, pasan a apuntar a sus copias. Con ?sufijo=<texto>, lo añado al título de la copia
+// del documento.
+#[allow(clippy::too_many_arguments)]
+#[post("/documento/<id>/copia?<padre>&<sufijo>")]
+async fn copia_documento(
+    id: Id,
+    padre: Option<Id>,
+    sufijo: Option<String>,
+    lista: &State<Documentos>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    contadores: &State<Contadores>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut lista = bloquea_para_cambiar(lista, almacén).await?;
+    let original = lista.iter().find(|d| d.id == id).ok_or(Status::NotFound)?;
+    // La raíz no se copia, y el destino debe existir
+    let destino = padre.unwrap_or(original.padre);
+    if id == 0 || !lista.iter().any(|d| d.id == destino) {
+        return Err(Status::Forbidden);
+    }
 
-        lista.remove(i);
+    // Reservo un id nuevo para cada documento del subárbol
+    let subárbol = papelera::subárbol(&lista, id);
+    let mut cambios: HashMap<Id, Id> = HashMap::new();
+    for documento in subárbol.iter() {
+        cambios.insert(*documento, contadores.nuevo_id(almacén, "documentos")?);
+    }
 
-        let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-        guarda_copia_documentos(j).await;
+    let mut nuevos = lista.clone();
+    for documento in subárbol.iter() {
+        let original = lista.iter().find(|d| d.id == *documento).unwrap();
+        let mut copia = original.clone();
+        copia.id = cambios[documento];
+        copia.padre = if *documento == id {
+            destino
+        } else {
+            cambios[&original.padre]
+        };
+        if *documento == id {
+            if let Some(sufijo) = sufijo.as_deref() {
+                copia.título.push_str(sufijo);
+            }
+            // El slug identifica al documento entre sus hermanos, así que no lo repito
+            copia.slug = None;
+        }
+        // La copia no sale de la importación, que la borraría al volver a importar
+        copia.origen = None;
+        copia.contenido = enlaces::reescribe(&original.contenido, &cambios);
+        for traducción in copia.traducciones.values_mut() {
+            traducción.contenido = enlaces::reescribe(&traducción.contenido, &cambios);
+        }
+        copia.hijos = original.hijos.iter().map(|h| cambios[h]).collect();
+        copia.revisión = 1;
+        nuevos.push(copia);
+    }
+    let copia = cambios[&id];
+    let doc_padre = nuevos.iter_mut().find(|d| d.id == destino).unwrap();
+    doc_padre.hijos.push(copia);
+    doc_padre.revisión += 1;
+
+    // Los enlaces por dirección a los originales pasan a la dirección de sus copias
+    let direcciones: HashMap<Vec<String>, String> = subárbol
+        .iter()
+        .map(|d| {
+            let anterior = dirección(&lista, *d);
+            (
+                enlaces::segmentos(&anterior),
+                dirección(&nuevos, cambios[d]),
+            )
+        })
+        .collect();
+    let copias: HashSet<Id> = cambios.values().copied().collect();
+    for d in nuevos.iter_mut().filter(|d| copias.contains(&d.id)) {
+        d.contenido = enlaces::reubica(&d.contenido, &direcciones);
+        for traducción in d.traducciones.values_mut() {
+            traducción.contenido = enlaces::reubica(&traducción.contenido, &direcciones);
+        }
+    }
 
-        return Status::Accepted;
-    } else {
-        return Status::Forbidden;
+    guarda_copia_documentos(almacén, índice, &nuevos).await?;
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "copia_documento",
+            Some(&usuario),
+            format!("documento/{}", copia),
+            ip,
+        )
+        .después(json!({ "original": id, "padre": destino, "documentos": subárbol.len() })),
+    );
+    for documento in subárbol.iter() {
+        let copiado = lista.iter().find(|d| d.id == cambios[documento]).unwrap();
+        eventos.emite(Evento::nuevo("creado", copiado, &lista, &usuario));
     }
+
+    Ok(json!({ "estado": "ok", "id": copia, "documentos": subárbol.len() }))
 }
 
-pub fn prepara_estado_inicial() -> Documentos {
-    // Documento raíz, nodo 0
-    let doc_raíz: Documento = Documento {
+// Documento raíz, nodo 0, con el que empieza un árbol vacío
+pub fn raíz() -> Documento {
+    Documento {
         // Nodo inicial
         id: 0,
         padre: 0,
         título: String::new(),
         contenido: String::new(),
         hijos: vec![],
-    };
+        revisión: 1,
+        traducciones: BTreeMap::new(),
+        slug: None,
+        origen: None,
+    }
+}
 
-    // Intento cargar documentos previos
-    let archivo = std::fs::read_to_string("documentos.json");
-
-    let documentos: Documentos = match archivo {
-        Ok(contenido) => {
-            // Si he podido leer el archivo, intento procesarlo como JSON
-            let v: Vec<Documento> = serde_json::from_str::<Vec<Documento>>(&contenido).unwrap();
-            // Si me ha dejado procesarlo como JSON, intento encontrar el ID más grande
-            let max_id_doc = v.iter().max_by_key(|doc| doc.id);
-            match max_id_doc {
-                // Si encuentra el ID más grande...
-                Some(doc) => {
-                    // establezco el contador de IDs...
-                    unsafe {
-                        CONTADOR_IDS = doc.id + 1;
-                    }
-                    // y devuelvo el vector de documentos
-                    Mutex::new(v)
-                }
-                None => {
-                    // El vector estaba vacío o no era válido...
-                    unsafe {
-                        // inicio el contador de IDs...
-                        CONTADOR_IDS = 1;
-                    }
-                    // y cargo el nodo raíz
-                    Mutex::new(vec![doc_raíz])
-                }
-            }
-        }
-        Err(_e) => {
-            // Si hay error al abrir los documentos, creo un nodo 0 inicial y establezco el contador de IDs a 0
-            unsafe {
-                CONTADOR_IDS = 1;
-            }
-            Mutex::new(vec![doc_raíz])
-        }
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> Documentos {
+    // Intento cargar documentos previos. Si ninguna copia se puede leer, prefiero no arrancar
+    // antes que empezar con un árbol vacío y sobrescribir los datos al primer cambio.
+    let guardados = almacén
+        .carga_documentos()
+        .unwrap_or_else(|e| panic!("No he podido cargar los documentos: {}", e));
+    almacén.anota_huella("documentos").unwrap();
+
+    // Si no hay documentos guardados, o la lista está vacía, creo el nodo 0 inicial
+    let documentos: Documentos = match guardados {
+        Some(v) if !v.is_empty() => Mutex::new(v),
+        _ => Mutex::new(vec![raíz()]),
     };
 
     return documentos;
@@ -226,6 +747,7 @@ pub fn rutas() -> Vec<rocket::Route> {
         crea_documento,
         lee_documento,
         cambia_documento,
-        borra_documento
+        borra_documento,
+        copia_documento
     ]
 }
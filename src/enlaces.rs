@@ -0,0 +1,482 @@
+use rocket::http::{RawStr, Status};
+use rocket::serde::json::{json, Value};
+use rocket::{Config, State};
+
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use super::documentos::{self, Documento, Documentos};
+use super::id::Id;
+use super::roles::Editor;
+use super::usuarios::Usuario;
+
+/*
+ * Enlaces entre documentos
+ */
+
+// El contenido de los documentos es HTML. Considero que un enlace apunta a otro documento cuando
+// el valor de su atributo href termina en "documento/<id>", con o sin un fragmento ("#...") o una
+// consulta ("?...") detrás, y es una ruta relativa o empieza por url_sitio. Así reconozco tanto
+// "/documento/12" como "https://ejemplo.es/documento/12#sintaxis" si el sitio es
+// https://ejemplo.es, pero no los enlaces a otros sitios.
+//
+// También pueden apuntar a la dirección pública de un documento, como "/referencia/tipos", que
+// depende de los slugs del documento y sus ancestros. Esas rutas empiezan por "/" o por
+// url_sitio. Como también pueden ser archivos del sitio, sólo las doy por rotas cuando su primer
+// segmento es el slug de un documento.
+const ATRIBUTO: &str = "href=";
+const RUTA: &str = "documento/";
+
+// Un enlace a un documento por su id dentro del contenido
+struct Enlace {
+    id: Id,
+    // Posición del id
+    número: Range<usize>,
+    // Posición de todo el valor del href, salvo el fragmento y la consulta
+    ruta: Range<usize>,
+}
+
+// A dónde apunta un enlace
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub enum Destino {
+    Documento(Id),
+    // Segmentos de una dirección pública, ya descodificados
+    Dirección(Vec<String>),
+}
+
+impl Destino {
+    // El documento al que apunta en el árbol actual, si existe
+    pub fn resuelve(&self, documentos: &[Documento]) -> Option<Id> {
+        match self {
+            Destino::Documento(id) => documentos.iter().any(|d| d.id == *id).then_some(*id),
+            Destino::Dirección(segmentos) => {
+                let segmentos: Vec<&str> = segmentos.iter().map(|s| s.as_str()).collect();
+                documentos::busca_por_dirección(documentos, &segmentos)
+            }
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Destino::Documento(id) => format!("/documento/{}", id),
+            Destino::Dirección(segmentos) => format!("/{}", segmentos.join("/")),
+        }
+    }
+}
+
+// Un href apunta a otro sitio si lleva esquema, como "https:" o "mailto:", o empieza por "//"
+fn es_externo(valor: &str) -> bool {
+    if valor.starts_with("//") {
+        return true;
+    }
+    let fin_esquema = valor.find([':', '/', '#', '?']);
+    match fin_esquema {
+        Some(fin) if valor[fin..].starts_with(':') => {
+            let esquema = &valor[..fin];
+            esquema.starts_with(|c: char| c.is_ascii_alphabetic())
+                && esquema
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+        }
+        _ => false,
+    }
+}
+
+// Busca en el valor de un href el id del documento al que apunta. Las posiciones son relativas al
+// valor.
+fn documento_enlazado(valor: &str, url_sitio: &str) -> Option<Enlace> {
+    let fin_ruta = valor.find(['#', '?']).unwrap_or(valor.len());
+    let en_sitio = !url_sitio.is_empty() && valor.starts_with(url_sitio);
+    if !en_sitio && es_externo(valor) {
+        return None;
+    }
+    let ruta = valor[..fin_ruta].trim_end_matches('/');
+    let inicio = ruta.rfind(RUTA)? + RUTA.len();
+    // "documento/" debe empezar un segmento de la ruta
+    let antes = &ruta[..inicio - RUTA.len()];
+    if !(antes.is_empty() || antes.ends_with('/')) {
+        return None;
+    }
+    let número = &ruta[inicio..];
+    if número.is_empty() || !número.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(Enlace {
+        id: número.parse().ok()?,
+        número: inicio..inicio + número.len(),
+        ruta: 0..fin_ruta,
+    })
+}
+
+// Los segmentos descodificados de una ruta, como los de una dirección pública
+pub fn segmentos(ruta: &str) -> Vec<String> {
+    ruta.split('/')
+        .filter(|s| !s.is_empty())
+        .map(|s| RawStr::new(s).percent_decode_lossy().into_owned())
+        .collect()
+}
+
+// Busca en el valor de un href una ruta del sitio, sin el origen, el fragmento ni la consulta.
+// Devuelve sus segmentos y la posición de la ruta, relativa al valor.
+fn ruta_enlazada(valor: &str, url_sitio: &str) -> Option<(Vec<String>, Range<usize>)> {
+    let fin_ruta = valor.find(['#', '?']).unwrap_or(valor.len());
+    let inicio = if !url_sitio.is_empty() && valor.starts_with(url_sitio) {
+        url_sitio.len()
+    } else {
+        0
+    };
+    let ruta = &valor[inicio..fin_ruta];
+    if !ruta.starts_with('/') || ruta.starts_with("//") {
+        return None;
+    }
+    let segmentos = segmentos(ruta);
+    if segmentos.is_empty() {
+        return None;
+    }
+    Some((segmentos, inicio..fin_ruta))
+}
+
+// Llama a "visita" con la posición del valor de cada href del contenido
+fn recorre_valores(contenido: &str, mut visita: impl FnMut(Range<usize>)) {
+    let mut desde = 0;
+    while let Some(posición) = contenido[desde..].find(ATRIBUTO) {
+        let inicio_valor = desde + posición + ATRIBUTO.len();
+        desde = inicio_valor;
+        let comilla = match contenido[inicio_valor..].chars().next() {
+            Some(c) if c == '"' || c == '\'' => c,
+            _ => continue,
+        };
+        let valor_desde = inicio_valor + 1;
+        let valor_hasta = match contenido[valor_desde..].find(comilla) {
+            Some(fin) => valor_desde + fin,
+            None => break,
+        };
+        visita(valor_desde..valor_hasta);
+        desde = valor_hasta;
+    }
+}
+
+// Recorre los href del contenido, y llama a "visita" con cada uno que apunta a un documento
+fn recorre(contenido: &str, url_sitio: &str, mut visita: impl FnMut(Enlace)) {
+    recorre_valores(contenido, |valor| {
+        if let Some(enlace) = documento_enlazado(&contenido[valor.clone()], url_sitio) {
+            visita(Enlace {
+                id: enlace.id,
+                número: valor.start + enlace.número.start..valor.start + enlace.número.end,
+                ruta: valor.start + enlace.ruta.start..valor.start + enlace.ruta.end,
+            });
+        }
+    });
+}
+
+// Recorre los href del contenido que son rutas del sitio y no apuntan a un documento por su id
+fn recorre_rutas(
+    contenido: &str,
+    url_sitio: &str,
+    mut visita: impl FnMut(Vec<String>, Range<usize>),
+) {
+    recorre_valores(contenido, |valor| {
+        let texto = &contenido[valor.clone()];
+        if documento_enlazado(texto, url_sitio).is_some() {
+            return;
+        }
+        if let Some((segmentos, ruta)) = ruta_enlazada(texto, url_sitio) {
+            visita(segmentos, valor.start + ruta.start..valor.start + ruta.end);
+        }
+    });
+}
+
+// Sustituye cada trozo del contenido por su texto. Los trozos están en orden y no se solapan.
+fn sustituye(contenido: &str, cambios: Vec<(Range<usize>, String)>) -> String {
+    let mut resultado = String::with_capacity(contenido.len());
+    let mut copiado = 0;
+    for (trozo, texto) in cambios {
+        resultado.push_str(&contenido[copiado..trozo.start]);
+        resultado.push_str(&texto);
+        copiado = trozo.end;
+    }
+    resultado.push_str(&contenido[copiado..]);
+    resultado
+}
+
+// Cambia los enlaces a los documentos de "cambios" por enlaces a sus nuevos ids, y deja los
+// demás como estaban
+pub fn reescribe(contenido: &str, cambios: &HashMap<Id, Id>) -> String {
+    let mut trozos = vec![];
+    recorre(contenido, &url_sitio(), |enlace| {
+        if let Some(nuevo) = cambios.get(&enlace.id) {
+            trozos.push((enlace.número, nuevo.to_string()));
+        }
+    });
+    sustituye(contenido, trozos)
+}
+
+// Cambia la dirección de los enlaces a documentos por la que indique "destino", conservando el
+// fragmento y la consulta. Los enlaces para los que "destino" no tiene dirección no cambian.
+pub fn redirige(contenido: &str, destino: impl Fn(Id) -> Option<String>) -> String {
+    let mut trozos = vec![];
+    recorre(contenido, &url_sitio(), |enlace| {
+        if let Some(dirección) = destino(enlace.id) {
+            trozos.push((enlace.ruta, dirección));
+        }
+    });
+    sustituye(contenido, trozos)
+}
+
+// Cambia las rutas del sitio que aparecen en "cambios" por su nueva dirección, conservando el
+// origen, el fragmento y la consulta
+pub fn reubica(contenido: &str, cambios: &HashMap<Vec<String>, String>) -> String {
+    let mut trozos = vec![];
+    recorre_rutas(contenido, &url_sitio(), |segmentos, ruta| {
+        if let Some(dirección) = cambios.get(&segmentos) {
+            trozos.push((ruta, dirección.clone()));
+        }
+    });
+    sustituye(contenido, trozos)
+}
+
+fn url_sitio() -> String {
+    Config::figment()
+        .extract_inner::<String>("url_sitio")
+        .unwrap_or_default()
+}
+
+// Los destinos de los enlaces de un documento, en su contenido y en sus traducciones
+fn destinos(documento: &Documento, url_sitio: &str) -> Vec<Destino> {
+    let mut destinos = vec![];
+    let contenidos = std::iter::once(&documento.contenido)
+        .chain(documento.traducciones.values().map(|t| &t.contenido));
+    for contenido in contenidos {
+        recorre(contenido, url_sitio, |enlace| {
+            destinos.push(Destino::Documento(enlace.id))
+        });
+        recorre_rutas(contenido, url_sitio, |segmentos, _| {
+            destinos.push(Destino::Dirección(segmentos))
+        });
+    }
+    let mut vistos = HashSet::new();
+    destinos.retain(|d| vistos.insert(d.clone()));
+    destinos
+}
+
+// Una ruta es de un documento si su primer segmento es el slug de un hijo de la raíz. Así no
+// confundo con enlaces rotos los enlaces a archivos del sitio o a otras rutas del servidor.
+fn es_de_documento(documentos: &[Documento], destino: &Destino) -> bool {
+    match destino {
+        Destino::Documento(_) => true,
+        Destino::Dirección(segmentos) => documentos
+            .iter()
+            .any(|d| d.padre == 0 && d.id != 0 && d.slug.as_deref() == Some(segmentos[0].as_str())),
+    }
+}
+
+// Índice de los enlaces que salen de cada documento. Lo actualizo cada vez que se guardan o se
+// recargan los documentos, y sólo vuelvo a leer los enlaces de un documento cuando cambia su
+// revisión, que aumenta con cada cambio de su contenido o de sus traducciones.
+pub struct ÍndiceEnlaces {
+    salientes: Mutex<HashMap<Id, Leídos>>,
+}
+
+// Los destinos de los enlaces de un documento, con la revisión en la que los leí
+type Leídos = (u64, Arc<Vec<Destino>>);
+
+impl ÍndiceEnlaces {
+    pub fn actualiza(&self, documentos: &[Documento]) {
+        let mut salientes = self.salientes.lock().unwrap();
+        let url_sitio = url_sitio();
+        let ids: HashSet<Id> = documentos.iter().map(|d| d.id).collect();
+        salientes.retain(|id, _| ids.contains(id));
+        for documento in documentos {
+            let al_día = salientes
+                .get(&documento.id)
+                .is_some_and(|(revisión, _)| *revisión == documento.revisión);
+            if !al_día {
+                salientes.insert(
+                    documento.id,
+                    (
+                        documento.revisión,
+                        Arc::new(destinos(documento, &url_sitio)),
+                    ),
+                );
+            }
+        }
+    }
+
+    // Los enlaces que salen de un documento
+    pub fn salientes(&self, id: Id) -> Arc<Vec<Destino>> {
+        self.salientes
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map(|(_, destinos)| destinos.clone())
+            .unwrap_or_default()
+    }
+
+    // Los documentos que enlazan con alguno de "ids", salvo ellos mismos
+    pub fn entrantes(&self, documentos: &[Documento], ids: &[Id]) -> Vec<Id> {
+        let mut entrantes: Vec<Id> = self
+            .salientes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(origen, (_, destinos))| {
+                !ids.contains(origen)
+                    && destinos
+                        .iter()
+                        .any(|d| d.resuelve(documentos).is_some_and(|id| ids.contains(&id)))
+            })
+            .map(|(origen, _)| *origen)
+            .collect();
+        entrantes.sort_unstable();
+        entrantes
+    }
+
+    // Los enlaces a documentos que no existen, con el documento en el que están
+    pub fn rotos(&self, documentos: &[Documento]) -> Vec<(Id, Destino)> {
+        let mut rotos: Vec<(Id, Destino)> = self
+            .salientes
+            .lock()
+            .unwrap()
+            .iter()
+            .flat_map(|(origen, (_, destinos))| destinos.iter().map(move |d| (*origen, d)))
+            .filter(|(_, destino)| {
+                es_de_documento(documentos, destino) && destino.resuelve(documentos).is_none()
+            })
+            .map(|(origen, destino)| (origen, destino.clone()))
+            .collect();
+        rotos.sort_by_key(|(origen, destino)| (*origen, destino.describe()));
+        rotos
+    }
+}
+
+pub fn prepara_estado_inicial(documentos: &[Documento]) -> ÍndiceEnlaces {
+    let índice = ÍndiceEnlaces {
+        salientes: Mutex::new(HashMap::new()),
+    };
+    índice.actualiza(documentos);
+    índice
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+fn título(documentos: &[Documento], id: Id) -> Option<String> {
+    documentos
+        .iter()
+        .find(|d| d.id == id)
+        .map(|d| d.título.clone())
+}
+
+// Los enlaces que salen de un documento, y los documentos que enlazan con él
+#[get("/documento/<id>/enlaces", format = "json")]
+async fn lee_enlaces(
+    id: Id,
+    lista: &State<Documentos>,
+    índice: &State<ÍndiceEnlaces>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    let lista = lista.lock().await;
+    if !lista.iter().any(|d| d.id == id) {
+        return Err(Status::NotFound);
+    }
+    let salientes: Vec<Value> = índice
+        .salientes(id)
+        .iter()
+        .map(|destino| {
+            let documento = destino.resuelve(&lista);
+            json!({
+                "enlace": destino.describe(),
+                "documento": documento,
+                "título": documento.and_then(|d| título(&lista, d)),
+                "roto": documento.is_none() && es_de_documento(&lista, destino),
+            })
+        })
+        .collect();
+    let entrantes: Vec<Value> = índice
+        .entrantes(&lista, &[id])
+        .into_iter()
+        .map(|origen| json!({ "documento": origen, "título": título(&lista, origen) }))
+        .collect();
+
+    Ok(json!({ "estado": "ok", "salientes": salientes, "entrantes": entrantes }))
+}
+
+// Todos los enlaces rotos de los documentos
+#[get("/enlaces/rotos", format = "json")]
+async fn lee_enlaces_rotos(
+    lista: &State<Documentos>,
+    índice: &State<ÍndiceEnlaces>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Value {
+    let lista = lista.lock().await;
+    let rotos: Vec<Value> = índice
+        .rotos(&lista)
+        .iter()
+        .map(|(origen, destino)| {
+            json!({
+                "documento": origen,
+                "título": título(&lista, *origen),
+                "enlace": destino.describe(),
+            })
+        })
+        .collect();
+
+    json!({ "estado": "ok", "rotos": rotos })
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![lee_enlaces, lee_enlaces_rotos]
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+
+    fn documento(id: Id, revisión: u64, contenido: &str) -> Documento {
+        let mut documento = documentos::raíz();
+        documento.id = id;
+        documento.revisión = revisión;
+        documento.contenido = contenido.to_string();
+        documento
+    }
+
+    fn ids(destinos: &[Destino]) -> Vec<String> {
+        destinos.iter().map(|d| d.describe()).collect()
+    }
+
+    #[test]
+    fn sólo_vuelve_a_leer_los_documentos_que_cambian_de_revisión() {
+        let mut documentos = vec![
+            documento(0, 1, ""),
+            documento(1, 1, r#"<a href="/documento/2">dos</a>"#),
+            documento(2, 1, ""),
+        ];
+        let índice = prepara_estado_inicial(&documentos);
+        assert_eq!(ids(&índice.salientes(1)), vec!["/documento/2"]);
+        assert_eq!(índice.entrantes(&documentos, &[2]), vec![1]);
+        let antes = índice.salientes(1);
+
+        // Sin cambiar la revisión, el índice conserva lo que leyó
+        documentos[1].contenido = r#"<a href="/documento/3">tres</a>"#.to_string();
+        índice.actualiza(&documentos);
+        assert!(Arc::ptr_eq(&antes, &índice.salientes(1)));
+
+        documentos[1].revisión = 2;
+        índice.actualiza(&documentos);
+        assert_eq!(ids(&índice.salientes(1)), vec!["/documento/3"]);
+        assert!(índice.entrantes(&documentos, &[2]).is_empty());
+        assert_eq!(
+            ids(&[índice.rotos(&documentos)[0].1.clone()]),
+            vec!["/documento/3"]
+        );
+
+        // Los documentos que desaparecen salen del índice
+        documentos.truncate(1);
+        índice.actualiza(&documentos);
+        assert!(índice.salientes(1).is_empty());
+    }
+}
@@ -0,0 +1,965 @@
+use chrono::Utc;
+
+use rocket::http::{ContentType, Header, Status};
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+use std::time::Instant;
+
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::almacen;
+use super::condiciones::{Etiquetada, SiNoCoincide};
+use super::documentos::{Documento, Documentos};
+use super::enlaces;
+use super::exportacion::{self, escapa, texto};
+use super::id::Id;
+use super::traducciones::{self, IdiomasAceptados};
+use super::versiones::{self, Versiones};
+
+/*
+ * Libros electrónicos EPUB
+ */
+
+// Genero un EPUB 3 con un subárbol de documentos: cada documento es un capítulo, en el orden de
+// lectura, y el índice del libro se construye con sus títulos. La hoja de estilo va dentro del
+// libro, y el código ya va resaltado, para que se vea igual que en la web aunque se lea sin
+// conexión.
+const ESTILO: &str = "body { font-family: serif; line-height: 1.5; }
+h1, h2, h3 { font-family: sans-serif; }
+pre, code { font-family: monospace; font-size: 0.9em; }
+pre { background: #f6f8fa; border: 1px solid #e1e4e8; padding: 0.6em; white-space: pre-wrap; }
+code { background: #f6f8fa; padding: 0 0.2em; }
+pre code { background: none; padding: 0; }
+/* Resaltado del código: las palabras clave, los números, los textos y los comentarios */
+.palabra-clave { color: #d73a49; font-weight: bold; }
+.número { color: #005cc5; }
+.texto { color: #032f62; }
+.comentario { color: #6a737d; font-style: italic; }
+";
+
+// Palabras clave de los lenguajes que sé resaltar. En los bloques de otros lenguajes sólo coloreo
+// los números, los textos y los comentarios.
+const PALABRAS_CLAVE: &[(&str, &[&str])] = &[(
+    "ñ",
+    &[
+        "externo",
+        "público",
+        "devuelve",
+        "si",
+        "sino",
+        "mientras",
+        "para",
+        "rompe",
+        "continúa",
+        "cierto",
+        "falso",
+        "bool",
+        "ent",
+        "ent8",
+        "ent16",
+        "ent32",
+        "ent64",
+        "nat",
+        "nat8",
+        "nat16",
+        "nat32",
+        "nat64",
+        "real",
+        "real32",
+        "real64",
+    ],
+)];
+
+// Las entidades de HTML que más aparecen en los documentos. En XHTML sólo existen las de XML, así
+// que sustituyo las demás por su carácter.
+const ENTIDADES: &[(&str, char)] = &[
+    ("nbsp", '\u{a0}'),
+    ("aacute", 'á'),
+    ("eacute", 'é'),
+    ("iacute", 'í'),
+    ("oacute", 'ó'),
+    ("uacute", 'ú'),
+    ("Aacute", 'Á'),
+    ("Eacute", 'É'),
+    ("Iacute", 'Í'),
+    ("Oacute", 'Ó'),
+    ("Uacute", 'Ú'),
+    ("ntilde", 'ñ'),
+    ("Ntilde", 'Ñ'),
+    ("uuml", 'ü'),
+    ("Uuml", 'Ü'),
+    ("ccedil", 'ç'),
+    ("Ccedil", 'Ç'),
+    ("agrave", 'à'),
+    ("egrave", 'è'),
+    ("ograve", 'ò'),
+    ("iexcl", '¡'),
+    ("iquest", '¿'),
+    ("laquo", '«'),
+    ("raquo", '»'),
+    ("ordf", 'ª'),
+    ("ordm", 'º'),
+    ("middot", '·'),
+    ("deg", '°'),
+    ("times", '×'),
+    ("copy", '©'),
+    ("reg", '®'),
+    ("euro", '€'),
+    ("hellip", '…'),
+    ("ndash", '–'),
+    ("mdash", '—'),
+    ("lsquo", '‘'),
+    ("rsquo", '’'),
+    ("ldquo", '“'),
+    ("rdquo", '”'),
+    ("larr", '←'),
+    ("rarr", '→'),
+];
+
+// Elementos que en HTML no se cierran y en XHTML deben cerrarse solos
+const VACÍOS: &[&str] = &[
+    "br", "hr", "img", "input", "meta", "link", "col", "wbr", "source", "area",
+];
+
+// Elementos de bloque: al empezar uno, HTML da por cerrado el párrafo abierto
+const BLOQUES: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "div",
+    "dl",
+    "fieldset",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+
+// Elementos que HTML da por cerrados al empezar otro como ellos, salvo que antes se encuentre
+// uno de los límites. Por ejemplo, un <li> cierra el <li> anterior de la misma lista, pero no
+// el de la lista que la contiene.
+const IMPLÍCITOS: &[(&[&str], &[&str])] = &[
+    (&["li"], &["ul", "ol"]),
+    (&["dt", "dd"], &["dl"]),
+    (&["tr"], &["table", "thead", "tbody", "tfoot"]),
+    (&["td", "th"], &["tr", "table"]),
+    (&["thead", "tbody", "tfoot"], &["table"]),
+    (&["option"], &["select"]),
+];
+
+// Cambio las entidades que XML no conoce por su carácter, y escapo los "&" sueltos
+pub fn entidades(html: &str) -> String {
+    let mut resultado = String::with_capacity(html.len());
+    let mut resto = html;
+    while let Some(i) = resto.find('&') {
+        resultado.push_str(&resto[..i]);
+        resto = &resto[i + 1..];
+        let nombre = resto
+            .find(';')
+            .filter(|fin| *fin <= 10)
+            .map(|fin| &resto[..fin]);
+        match nombre {
+            Some(nombre)
+                if ["amp", "lt", "gt", "quot", "apos"].contains(&nombre)
+                    || nombre.starts_with('#') =>
+            {
+                resultado.push('&')
+            }
+            Some(nombre) => match ENTIDADES.iter().find(|(e, _)| *e == nombre) {
+                Some((_, carácter)) => {
+                    resultado.push(*carácter);
+                    resto = &resto[nombre.len() + 1..];
+                }
+                None => resultado.push_str("&amp;"),
+            },
+            None => resultado.push_str("&amp;"),
+        }
+    }
+    resultado.push_str(resto);
+    resultado
+}
+
+// Un elemento abierto, con el lenguaje de su código si es un bloque de código
+struct Abierto {
+    nombre: String,
+    lenguaje: Option<String>,
+}
+
+// Una etiqueta de apertura o de cierre, con los atributos ya en forma de XML
+struct Etiqueta {
+    nombre: String,
+    cierre: bool,
+    vacía: bool,
+    atributos: Vec<(String, String)>,
+}
+
+impl Etiqueta {
+    fn atributo(&self, nombre: &str) -> Option<&str> {
+        self.atributos
+            .iter()
+            .find(|(n, _)| n == nombre)
+            .map(|(_, v)| v.as_str())
+    }
+
+    fn escribe(&self, resultado: &mut String) {
+        resultado.push('<');
+        resultado.push_str(&self.nombre);
+        for (nombre, valor) in self.atributos.iter() {
+            resultado.push_str(&format!(" {}=\"{}\"", nombre, valor));
+        }
+        resultado.push_str(if self.vacía { " />" } else { ">" });
+    }
+}
+
+// Los nombres de XML empiezan por una letra, "_" o ":", y siguen con letras, cifras, "_", ":",
+// "." o "-"
+fn es_nombre_xml(nombre: &str) -> bool {
+    nombre.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':')
+        && nombre
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_:.-".contains(c))
+}
+
+// Lee la etiqueta del principio de "texto", que empieza por "<". Devuelve la etiqueta y su
+// longitud, o None si no es una etiqueta.
+fn lee_etiqueta(texto: &str) -> Option<(Etiqueta, usize)> {
+    let cierre = texto[1..].starts_with('/');
+    let inicio_nombre = if cierre { 2 } else { 1 };
+    let largo_nombre = texto[inicio_nombre..]
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+        .unwrap_or(texto.len() - inicio_nombre);
+    let nombre = texto[inicio_nombre..inicio_nombre + largo_nombre].to_lowercase();
+    if !nombre.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return None;
+    }
+    let mut etiqueta = Etiqueta {
+        nombre,
+        cierre,
+        vacía: false,
+        atributos: vec![],
+    };
+
+    let mut posición = inicio_nombre + largo_nombre;
+    loop {
+        let resto = &texto[posición..];
+        let espacios = resto.len() - resto.trim_start().len();
+        posición += espacios;
+        let resto = &texto[posición..];
+        if resto.is_empty() {
+            return None;
+        }
+        if resto.starts_with('>') {
+            return Some((etiqueta, posición + 1));
+        }
+        if resto.starts_with("/>") {
+            etiqueta.vacía = true;
+            return Some((etiqueta, posición + 2));
+        }
+        if resto.starts_with('/') {
+            posición += 1;
+            continue;
+        }
+
+        // Nombre del atributo, y su valor si lo tiene: entre comillas dobles, simples o sin
+        // comillas. Los atributos sin valor, como "disabled", toman su propio nombre.
+        let largo = resto
+            .find(|c: char| c.is_whitespace() || "=>/".contains(c))
+            .unwrap_or(resto.len())
+            .max(1);
+        let nombre = resto[..largo].to_lowercase();
+        posición += largo;
+        let resto = &texto[posición..];
+        let tras_espacios = resto.trim_start();
+        let valor = if let Some(valor) = tras_espacios.strip_prefix('=') {
+            let valor = valor.trim_start();
+            posición = texto.len() - valor.len();
+            match valor.chars().next() {
+                Some(comilla) if comilla == '"' || comilla == '\'' => {
+                    let fin = valor[1..].find(comilla)?;
+                    posición += fin + 2;
+                    &valor[1..fin + 1]
+                }
+                _ => {
+                    let fin = valor
+                        .find(|c: char| c.is_whitespace() || c == '>')
+                        .unwrap_or(valor.len());
+                    posición += fin;
+                    &valor[..fin]
+                }
+            }
+        } else {
+            &nombre
+        };
+        // XML no admite atributos repetidos ni nombres como los de Vue ("@click")
+        if es_nombre_xml(&nombre) && etiqueta.atributo(&nombre).is_none() {
+            let valor = valor
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;");
+            etiqueta.atributos.push((nombre, valor));
+        }
+    }
+}
+
+// El lenguaje de la primera clase "language-<lenguaje>" o "lang-<lenguaje>", salvo "none"
+fn lenguaje_de(etiqueta: &Etiqueta) -> Option<String> {
+    etiqueta
+        .atributo("class")?
+        .split_whitespace()
+        .find_map(|clase| {
+            clase
+                .strip_prefix("language-")
+                .or_else(|| clase.strip_prefix("lang-"))
+        })
+        .filter(|lenguaje| *lenguaje != "none")
+        .map(|lenguaje| desescapa(lenguaje).to_lowercase())
+}
+
+// Cierra los elementos abiertos desde el último hasta el que cumple "busca", si lo encuentra
+// antes de uno que cumpla "límite"
+fn cierra_hasta(
+    abiertos: &mut Vec<Abierto>,
+    resultado: &mut String,
+    busca: impl Fn(&str) -> bool,
+    límite: impl Fn(&str) -> bool,
+) {
+    let encontrado = abiertos
+        .iter()
+        .rev()
+        .take_while(|a| busca(&a.nombre) || !límite(&a.nombre))
+        .position(|a| busca(&a.nombre));
+    if let Some(desde_el_final) = encontrado {
+        for _ in 0..=desde_el_final {
+            let abierto = abiertos.pop().unwrap();
+            resultado.push_str(&format!("</{}>", abierto.nombre));
+        }
+    }
+}
+
+// Adapto el HTML de los documentos a XHTML: cambio las entidades, pongo entre comillas los
+// atributos, cierro los elementos vacíos y los que HTML deja sin cerrar, como <p> o <li>, y
+// quito los cierres que no tienen apertura. También resalto el código de los bloques <pre> que
+// indican su lenguaje con una clase "language-<lenguaje>", en el <pre> o en su <code>, como los
+// de Markdown o los del editor.
+fn a_xhtml(html: &str) -> String {
+    let html = entidades(html);
+    let mut resultado = String::with_capacity(html.len());
+    let mut abiertos: Vec<Abierto> = vec![];
+    let mut resto = html.as_str();
+    loop {
+        let i = resto.find('<').unwrap_or(resto.len());
+        // El texto del código se resalta si su bloque indica el lenguaje
+        let texto = &resto[..i];
+        match abiertos
+            .iter()
+            .rev()
+            .find(|a| a.nombre == "code" || a.nombre == "pre")
+        {
+            Some(Abierto {
+                lenguaje: Some(lenguaje),
+                ..
+            }) => resultado.push_str(&resalta(texto, lenguaje)),
+            _ => resultado.push_str(texto),
+        }
+        resto = &resto[i..];
+        if resto.is_empty() {
+            break;
+        }
+
+        // Los comentarios y las declaraciones no hacen falta en el libro
+        if resto.starts_with("<!") || resto.starts_with("<?") {
+            let fin = if resto.starts_with("<!--") {
+                resto.find("-->").map(|f| f + 3)
+            } else {
+                resto.find('>').map(|f| f + 1)
+            };
+            resto = &resto[fin.unwrap_or(resto.len())..];
+            continue;
+        }
+        let (etiqueta, largo) = match lee_etiqueta(resto) {
+            Some(leída) => leída,
+            None => {
+                resultado.push_str("&lt;");
+                resto = &resto[1..];
+                continue;
+            }
+        };
+        resto = &resto[largo..];
+        let nombre = etiqueta.nombre.as_str();
+
+        if etiqueta.cierre {
+            cierra_hasta(&mut abiertos, &mut resultado, |a| a == nombre, |_| false);
+            continue;
+        }
+
+        if BLOQUES.contains(&nombre) {
+            cierra_hasta(
+                &mut abiertos,
+                &mut resultado,
+                |a| a == "p",
+                |a| BLOQUES.contains(&a) || a == "li" || a == "td" || a == "th",
+            );
+        }
+        if let Some((cerrados, límites)) = IMPLÍCITOS.iter().find(|(c, _)| c.contains(&nombre)) {
+            cierra_hasta(
+                &mut abiertos,
+                &mut resultado,
+                |a| cerrados.contains(&a),
+                |a| límites.contains(&a),
+            );
+        }
+
+        if VACÍOS.contains(&nombre) || etiqueta.vacía {
+            Etiqueta {
+                vacía: true,
+                ..etiqueta
+            }
+            .escribe(&mut resultado);
+            continue;
+        }
+        let lenguaje = match nombre {
+            "pre" => lenguaje_de(&etiqueta),
+            "code" => match abiertos.last() {
+                Some(pre) if pre.nombre == "pre" => lenguaje_de(&etiqueta).or(pre.lenguaje.clone()),
+                _ => None,
+            },
+            _ => None,
+        };
+        etiqueta.escribe(&mut resultado);
+        abiertos.push(Abierto {
+            nombre: etiqueta.nombre,
+            lenguaje,
+        });
+    }
+    while let Some(abierto) = abiertos.pop() {
+        resultado.push_str(&format!("</{}>", abierto.nombre));
+    }
+    resultado
+}
+
+// Deshace el escape de XML, con las entidades ya reducidas a las de XML
+fn desescapa(texto: &str) -> String {
+    let mut resultado = String::with_capacity(texto.len());
+    let mut resto = texto;
+    while let Some(i) = resto.find('&') {
+        resultado.push_str(&resto[..i]);
+        resto = &resto[i..];
+        let fin = match resto.find(';') {
+            Some(fin) => fin,
+            None => break,
+        };
+        let nombre = &resto[1..fin];
+        let carácter = match nombre {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => nombre
+                .strip_prefix("#x")
+                .or_else(|| nombre.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| nombre.strip_prefix('#').map(|dec| dec.parse::<u32>()))
+                .and_then(|código| código.ok())
+                .and_then(char::from_u32),
+        };
+        match carácter {
+            Some(carácter) => {
+                resultado.push(carácter);
+                resto = &resto[fin + 1..];
+            }
+            None => {
+                resultado.push('&');
+                resto = &resto[1..];
+            }
+        }
+    }
+    resultado.push_str(resto);
+    resultado
+}
+
+// Resalta un trozo de código, ya escapado para XML, con un <span> de la clase de cada palabra
+// clave, número, texto o comentario. Los comentarios son los de C, "//" y "/* */".
+fn resalta(código: &str, lenguaje: &str) -> String {
+    let palabras_clave: &[&str] = PALABRAS_CLAVE
+        .iter()
+        .find(|(l, _)| *l == lenguaje)
+        .map(|(_, palabras)| *palabras)
+        .unwrap_or(&[]);
+    let código = desescapa(código);
+    let caracteres: Vec<(usize, char)> = código.char_indices().collect();
+    let posición = |i: usize| caracteres.get(i).map_or(código.len(), |(p, _)| *p);
+    let mut resultado = String::with_capacity(código.len());
+    let mut i = 0;
+    while i < caracteres.len() {
+        let c = caracteres[i].1;
+        let siguiente = caracteres.get(i + 1).map(|(_, c)| *c);
+        let inicio = i;
+        let clase = if c == '/' && siguiente == Some('/') {
+            while i < caracteres.len() && caracteres[i].1 != '\n' {
+                i += 1;
+            }
+            Some("comentario")
+        } else if c == '/' && siguiente == Some('*') {
+            i += 2;
+            while i < caracteres.len()
+                && !(caracteres[i].1 == '*' && caracteres.get(i + 1).map(|(_, c)| *c) == Some('/'))
+            {
+                i += 1;
+            }
+            i = (i + 2).min(caracteres.len());
+            Some("comentario")
+        } else if c == '"' || c == '\'' {
+            i += 1;
+            while i < caracteres.len() && caracteres[i].1 != c && caracteres[i].1 != '\n' {
+                if caracteres[i].1 == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(caracteres.len());
+            Some("texto")
+        } else if c.is_ascii_digit() {
+            while i < caracteres.len()
+                && (caracteres[i].1.is_alphanumeric()
+                    || caracteres[i].1 == '.'
+                    || caracteres[i].1 == '_')
+            {
+                i += 1;
+            }
+            Some("número")
+        } else if c.is_alphabetic() || c == '_' {
+            while i < caracteres.len()
+                && (caracteres[i].1.is_alphanumeric() || caracteres[i].1 == '_')
+            {
+                i += 1;
+            }
+            let palabra = &código[posición(inicio)..posición(i)];
+            palabras_clave.contains(&palabra).then_some("palabra-clave")
+        } else {
+            i += 1;
+            None
+        };
+        let trozo = escapa(&código[posición(inicio)..posición(i)]);
+        match clase {
+            Some(clase) => {
+                resultado.push_str(&format!("<span class=\"{}\">{}</span>", clase, trozo))
+            }
+            None => resultado.push_str(&trozo),
+        }
+    }
+    resultado
+}
+
+fn capítulo(id: Id) -> String {
+    format!("capitulo-{}.xhtml", id)
+}
+
+fn página_xhtml(idioma: &str, título: &str, cuerpo: &str, nav: bool) -> String {
+    let espacio_epub = if nav {
+        " xmlns:epub=\"http://www.idpf.org/2007/ops\""
+    } else {
+        ""
+    };
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE html>\n\
+         <html xmlns=\"http://www.w3.org/1999/xhtml\"{} xml:lang=\"{}\" lang=\"{}\">\n\
+         <head>\n<meta charset=\"UTF-8\" />\n<title>{}</title>\n\
+         <link rel=\"stylesheet\" type=\"text/css\" href=\"estilo.css\" />\n</head>\n\
+         <body>\n{}\n</body>\n</html>\n",
+        espacio_epub, idioma, idioma, título, cuerpo
+    )
+}
+
+// Índice del libro: una lista anidada con los títulos, a partir de "id"
+fn índice(
+    documentos: &[Documento],
+    id: Id,
+    idioma: Option<&str>,
+    visitados: &mut HashSet<Id>,
+) -> String {
+    let documento = match documentos.iter().find(|d| d.id == id) {
+        Some(documento) if visitados.insert(id) => documento,
+        _ => return String::new(),
+    };
+    let hijos: String = documento
+        .hijos
+        .iter()
+        .map(|h| índice(documentos, *h, idioma, visitados))
+        .collect();
+    let hijos = if hijos.is_empty() {
+        hijos
+    } else {
+        format!("\n<ol>\n{}</ol>\n", hijos)
+    };
+    format!(
+        "<li><a href=\"{}\">{}</a>{}</li>\n",
+        capítulo(id),
+        escapa(&título_de(documento, idioma)),
+        hijos
+    )
+}
+
+// La raíz no tiene título, así que uso el del sitio
+fn título_de(documento: &Documento, idioma: Option<&str>) -> String {
+    if documento.id == 0 {
+        exportacion::lee_configuración().título
+    } else {
+        texto(documento, idioma).0.to_string()
+    }
+}
+
+// Genera el libro con el documento "id" y sus descendientes
+pub fn genera(
+    documentos: &[Documento],
+    id: Id,
+    idioma: Option<&str>,
+    identificador: &str,
+) -> Result<Vec<u8>, String> {
+    let orden = exportacion::en_orden(documentos, id);
+    let raíz = orden
+        .first()
+        .ok_or(format!("No existe el documento {}", id))?;
+    let título = título_de(raíz, idioma);
+    let idioma_libro = idioma
+        .map(|i| i.to_string())
+        .unwrap_or_else(|| traducciones::lee_configuración().origen);
+    let incluidos: HashSet<Id> = orden.iter().map(|d| d.id).collect();
+
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let error = |e: zip::result::ZipError| e.to_string();
+    // El tipo del archivo va el primero y sin comprimir
+    zip.start_file(
+        "mimetype",
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )
+    .map_err(error)?;
+    zip.write_all(b"application/epub+zip")
+        .map_err(|e| e.to_string())?;
+
+    let opciones = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut añade = |ruta: &str, contenido: &str| -> Result<(), String> {
+        zip.start_file(ruta, opciones).map_err(error)?;
+        zip.write_all(contenido.as_bytes())
+            .map_err(|e| e.to_string())
+    };
+
+    añade(
+        "META-INF/container.xml",
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+         <rootfiles>\n\
+         <rootfile full-path=\"OEBPS/libro.opf\" media-type=\"application/oebps-package+xml\"/>\n\
+         </rootfiles>\n\
+         </container>\n",
+    )?;
+    añade("OEBPS/estilo.css", ESTILO)?;
+
+    let mut manifiesto = String::new();
+    let mut lomo = String::new();
+    for documento in orden.iter() {
+        let (_, contenido) = texto(documento, idioma);
+        // Los enlaces a documentos del libro apuntan a sus capítulos
+        let contenido = enlaces::redirige(contenido, |id| {
+            incluidos.contains(&id).then(|| capítulo(id))
+        });
+        let título_capítulo = escapa(&título_de(documento, idioma));
+        let cuerpo = format!("<h1>{}</h1>\n{}", título_capítulo, a_xhtml(&contenido));
+        añade(
+            &format!("OEBPS/{}", capítulo(documento.id)),
+            &página_xhtml(&idioma_libro, &título_capítulo, &cuerpo, false),
+        )?;
+        manifiesto.push_str(&format!(
+            "<item id=\"c{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>\n",
+            documento.id,
+            capítulo(documento.id)
+        ));
+        lomo.push_str(&format!("<itemref idref=\"c{}\"/>\n", documento.id));
+    }
+
+    let nav = format!(
+        "<nav epub:type=\"toc\" id=\"toc\">\n<h1>{}</h1>\n<ol>\n{}</ol>\n</nav>",
+        escapa(&título),
+        índice(documentos, id, idioma, &mut HashSet::new())
+    );
+    añade(
+        "OEBPS/indice.xhtml",
+        &página_xhtml(&idioma_libro, &escapa(&título), &nav, true),
+    )?;
+
+    let paquete = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" unique-identifier=\"id\" xml:lang=\"{idioma}\">\n\
+         <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+         <dc:identifier id=\"id\">{identificador}</dc:identifier>\n\
+         <dc:title>{título}</dc:title>\n\
+         <dc:language>{idioma}</dc:language>\n\
+         <meta property=\"dcterms:modified\">{modificado}</meta>\n\
+         </metadata>\n\
+         <manifest>\n\
+         <item id=\"indice\" href=\"indice.xhtml\" media-type=\"application/xhtml+xml\" properties=\"nav\"/>\n\
+         <item id=\"estilo\" href=\"estilo.css\" media-type=\"text/css\"/>\n\
+         {manifiesto}\
+         </manifest>\n\
+         <spine>\n{lomo}</spine>\n\
+         </package>\n",
+        idioma = idioma_libro,
+        identificador = escapa(identificador),
+        título = escapa(&título),
+        modificado = Utc::now().format("%Y-%m-%dT%H:%M:%SZ"),
+        manifiesto = manifiesto,
+        lomo = lomo,
+    );
+    añade("OEBPS/libro.opf", &paquete)?;
+
+    Ok(zip.finish().map_err(error)?.into_inner())
+}
+
+// Guardo el último libro generado para cada documento, versión e idioma, con la huella de las
+// revisiones de sus documentos y el momento en que se pidió por última vez. Mientras no cambie
+// ninguno, lo sirvo sin volver a generarlo.
+pub type Libros = Mutex<HashMap<String, (String, Arc<Vec<u8>>, Instant)>>;
+
+// Libros que guardo como mucho. Al llegar al límite, olvido el que lleva más tiempo sin pedirse,
+// que suele ser de un documento o una versión que ya no existen.
+const LIBROS_GUARDADOS: usize = 32;
+
+pub fn prepara_estado_inicial() -> Libros {
+    Mutex::new(HashMap::new())
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[derive(Responder)]
+struct Libro(Vec<u8>, ContentType, Header<'static>, Header<'static>);
+
+// Cualquiera puede descargar el libro, igual que puede leer los documentos. Por defecto incluye
+// los documentos en desarrollo; con ?versión=, los de esa versión.
+#[allow(clippy::too_many_arguments)]
+#[get("/documento/<id>/epub?<versión>&<idioma>")]
+async fn descarga_epub(
+    id: Id,
+    versión: Option<&str>,
+    idioma: Option<&str>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    libros: &State<Libros>,
+    aceptados: IdiomasAceptados,
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<Libro>, Status> {
+    let (nombre_versión, documentos) = if versiones::es_desarrollo(versión) {
+        (versiones::desarrollo(), lista.lock().await.clone())
+    } else {
+        let versiones = versiones.lock().await;
+        let encontrada = versiones::busca(&versiones, versión.unwrap()).ok_or(Status::NotFound)?;
+        (encontrada.nombre.clone(), encontrada.documentos.clone())
+    };
+    let raíz = documentos
+        .iter()
+        .find(|d| d.id == id)
+        .ok_or(Status::NotFound)?;
+    let elegido = traducciones::elige(raíz, idioma, &aceptados);
+    let idioma_libro = (elegido != traducciones::lee_configuración().origen).then_some(elegido);
+
+    // La huella cambia en cuanto cambia cualquier documento del libro
+    let revisiones: Vec<String> = exportacion::en_orden(&documentos, id)
+        .iter()
+        .map(|d| format!("{}:{}", d.id, d.revisión))
+        .collect();
+    let clave = format!(
+        "{}/{}/{}",
+        id,
+        nombre_versión,
+        idioma_libro.as_deref().unwrap_or("")
+    );
+    let huella = almacen::calcula_huella(format!("{}\n{}", clave, revisiones.join(",")).as_bytes());
+    let etiqueta = format!("\"{}\"", &huella[..16]);
+
+    let mut libros = libros.lock().await;
+    let libro = match libros.get_mut(&clave) {
+        Some((guardada, libro, usado)) if *guardada == huella => {
+            *usado = Instant::now();
+            libro.clone()
+        }
+        _ => {
+            let identificador = format!(
+                "urn:lenguaje-ñ:{}:{}",
+                nombre_versión.replace(char::is_whitespace, "-"),
+                id
+            );
+            let libro = Arc::new(
+                genera(&documentos, id, idioma_libro.as_deref(), &identificador).map_err(|e| {
+                    println!("No he podido generar el libro: {}", e);
+                    Status::InternalServerError
+                })?,
+            );
+            if libros.len() >= LIBROS_GUARDADOS && !libros.contains_key(&clave) {
+                let olvidado = libros
+                    .iter()
+                    .min_by_key(|(_, (_, _, usado))| *usado)
+                    .map(|(clave, _)| clave.clone());
+                if let Some(olvidado) = olvidado {
+                    libros.remove(&olvidado);
+                }
+            }
+            libros.insert(clave, (huella, libro.clone(), Instant::now()));
+            libro
+        }
+    };
+
+    Ok(Etiquetada::con_etiqueta(
+        etiqueta,
+        &si_no_coincide,
+        Libro(
+            libro.to_vec(),
+            ContentType::new("application", "epub+zip"),
+            Header::new(
+                "Content-Disposition",
+                format!("attachment; filename=\"documento-{}.epub\"", id),
+            ),
+            Header::new("Cache-Control", "public, no-cache"),
+        ),
+    ))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![descarga_epub]
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+    use crate::documentos;
+
+    use std::collections::BTreeMap;
+    use std::io::Read;
+
+    // Falla si el texto no es XML bien formado
+    fn comprueba_xml(xml: &str) {
+        let opciones = roxmltree::ParsingOptions {
+            allow_dtd: true,
+            ..Default::default()
+        };
+        if let Err(e) = roxmltree::Document::parse_with_options(xml, opciones) {
+            panic!("XML mal formado: {}\n{}", e, xml);
+        }
+    }
+
+    fn documento(id: Id, padre: Id, título: &str, contenido: &str, hijos: Vec<Id>) -> Documento {
+        Documento {
+            id,
+            padre,
+            título: título.to_string(),
+            contenido: contenido.to_string(),
+            hijos,
+            revisión: 1,
+            traducciones: BTreeMap::new(),
+            slug: None,
+            origen: None,
+        }
+    }
+
+    const DESORDENADO: &str = "<p>Uno<p>Dos <input type=checkbox checked disabled>\
+        <ul><li>a<li>b<ul><li>c</ul></ul><P CLASS=nota>x&nbsp;&copy; a & b</div><br>\
+        <img src=a.png alt='dice \"hola\"'><!-- nota --><table><tr><td>1<td>2<tr><td>3</table>";
+
+    #[test]
+    fn el_html_sin_cerrar_pasa_a_xhtml_bien_formado() {
+        let xhtml = a_xhtml(DESORDENADO);
+        comprueba_xml(&format!("<div>{}</div>", xhtml));
+        assert!(xhtml.starts_with("<p>Uno</p><p>Dos "));
+        assert!(
+            xhtml.contains("<input type=\"checkbox\" checked=\"checked\" disabled=\"disabled\" />")
+        );
+        assert!(xhtml.contains("<li>a</li><li>b<ul><li>c</li></ul></li>"));
+        assert!(xhtml.contains("<p class=\"nota\">"));
+        assert!(xhtml.contains("alt=\"dice &quot;hola&quot;\""));
+        assert!(xhtml.contains("<tr><td>1</td><td>2</td></tr><tr><td>3</td></tr>"));
+        assert!(!xhtml.contains("nota -->"));
+    }
+
+    #[test]
+    fn resalta_los_bloques_de_código_con_lenguaje() {
+        let xhtml = a_xhtml(
+            "<pre class=\"language-ñ language-none\"><code>público ent inicia()\n{\n    \
+             escribeln(&quot;¡Hola!&quot;); // saluda\n    devuelve 0;\n}</code></pre>\
+             <p>Usa <code>devuelve</code> para terminar</p>",
+        );
+        comprueba_xml(&format!("<div>{}</div>", xhtml));
+        assert!(xhtml.contains("<span class=\"palabra-clave\">público</span>"));
+        assert!(xhtml.contains("<span class=\"palabra-clave\">ent</span> inicia()"));
+        assert!(xhtml.contains("<span class=\"texto\">&quot;¡Hola!&quot;</span>"));
+        assert!(xhtml.contains("<span class=\"comentario\">// saluda</span>"));
+        assert!(xhtml.contains("<span class=\"número\">0</span>"));
+        // El código fuera de un bloque no lleva lenguaje, y no lo resalto
+        assert!(xhtml.contains("<code>devuelve</code>"));
+    }
+
+    #[test]
+    fn los_archivos_del_libro_son_xml_bien_formado() {
+        let mut raíz = documentos::raíz();
+        raíz.hijos.push(1);
+        let documentos = vec![
+            raíz,
+            documento(
+                1,
+                0,
+                "Tutorial <Ñ>",
+                &format!(
+                    "{}<pre><code class=\"language-ñ\">devuelve 0;</code></pre>",
+                    DESORDENADO
+                ),
+                vec![2],
+            ),
+            documento(
+                2,
+                1,
+                "Fin",
+                "<a href=\"/documento/1#inicio\">Volver",
+                vec![],
+            ),
+        ];
+        let libro = genera(&documentos, 1, None, "urn:prueba").unwrap();
+
+        let mut zip = zip::ZipArchive::new(Cursor::new(libro)).unwrap();
+        let mut capítulos = 0;
+        for i in 0..zip.len() {
+            let mut archivo = zip.by_index(i).unwrap();
+            let nombre = archivo.name().to_string();
+            if nombre.ends_with(".xhtml") || nombre.ends_with(".opf") || nombre.ends_with(".xml") {
+                let mut xml = String::new();
+                archivo.read_to_string(&mut xml).unwrap();
+                comprueba_xml(&xml);
+                if nombre == "OEBPS/capitulo-2.xhtml" {
+                    assert!(xml.contains("<a href=\"capitulo-1.xhtml#inicio\">Volver</a>"));
+                }
+                capítulos += nombre.starts_with("OEBPS/capitulo-") as usize;
+            }
+        }
+        assert_eq!(capítulos, 2);
+    }
+}
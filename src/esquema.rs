@@ -0,0 +1,91 @@
+use rocket::serde::json::{json, Value};
+
+/*
+ * Versiones del formato de los datos guardados
+ */
+
+// Versión del formato que escribe esta versión del servidor. Cada vez que cambie el formato de
+// algún dato guardado, hay que subirla y añadir la migración correspondiente a MIGRACIONES.
+pub const VERSIÓN_ACTUAL: u32 = 2;
+
+// Una migración pasa los datos de una colección de la versión anterior a "versión"
+pub struct Migración {
+    pub versión: u32,
+    pub descripción: &'static str,
+    pub aplica: fn(colección: &str, datos: &mut Value),
+}
+
+pub const MIGRACIONES: &[Migración] = &[
+    Migración {
+        versión: 1,
+        descripción: "Guarda los datos dentro de un sobre con su versión y completa el correo \
+                      y el sujeto OIDC de los usuarios",
+        aplica: migra_a_1,
+    },
+    Migración {
+        versión: 2,
+        descripción: "Añade el número de revisión a los documentos y los usuarios",
+        aplica: migra_a_2,
+    },
+];
+
+// Antes de la versión 1 los datos se guardaban tal cual, sin sobre
+fn migra_a_1(colección: &str, datos: &mut Value) {
+    if colección != "usuarios" {
+        return;
+    }
+    for usuario in datos.as_array_mut().into_iter().flatten() {
+        if let Some(usuario) = usuario.as_object_mut() {
+            usuario.entry("correo").or_insert(json!(""));
+            usuario.entry("sujeto_oidc").or_insert(Value::Null);
+        }
+    }
+}
+
+// La versión 2 añade la revisión, que se envía como ETag. Los datos existentes empiezan en la 1.
+fn migra_a_2(colección: &str, datos: &mut Value) {
+    if colección != "documentos" && colección != "usuarios" {
+        return;
+    }
+    for elemento in datos.as_array_mut().into_iter().flatten() {
+        if let Some(elemento) = elemento.as_object_mut() {
+            elemento.entry("revisión").or_insert(json!(1));
+        }
+    }
+}
+
+// Separa la versión y los datos de un archivo. Los archivos sin sobre son de la versión 0.
+pub fn desenvuelve(contenido: Value) -> (u32, Value) {
+    match contenido {
+        Value::Object(mut sobre)
+            if sobre.contains_key("versión") && sobre.contains_key("datos") =>
+        {
+            let versión = sobre["versión"].as_u64().unwrap_or(0) as u32;
+            (versión, sobre.remove("datos").unwrap())
+        }
+        datos => (0, datos),
+    }
+}
+
+pub fn envuelve(datos: Value) -> Value {
+    json!({ "versión": VERSIÓN_ACTUAL, "datos": datos })
+}
+
+// Migraciones que habría que aplicar a unos datos de la versión indicada
+pub fn pendientes(versión: u32) -> Vec<&'static Migración> {
+    MIGRACIONES.iter().filter(|m| m.versión > versión).collect()
+}
+
+// Lleva los datos de una colección desde su versión hasta la actual
+pub fn migra(colección: &str, versión: u32, mut datos: Value) -> Result<Value, String> {
+    if versión > VERSIÓN_ACTUAL {
+        return Err(format!(
+            "Los {} tienen la versión {}, más nueva que la de este servidor ({})",
+            colección, versión, VERSIÓN_ACTUAL
+        ));
+    }
+    for migración in pendientes(versión) {
+        (migración.aplica)(colección, &mut datos);
+    }
+    Ok(datos)
+}
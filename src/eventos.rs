@@ -0,0 +1,263 @@
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::stream::{Event, EventStream};
+use rocket::serde::Serialize;
+use rocket::tokio::select;
+use rocket::tokio::sync::broadcast::{self, error::RecvError};
+use rocket::{Shutdown, State};
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use super::documentos::Documento;
+use super::id::Id;
+use super::integridad;
+use super::roles::Visitante;
+use super::usuarios::Usuario;
+
+/*
+ * Avisos de cambios en tiempo real
+ */
+
+// Cada cambio en el árbol de documentos genera un evento, que envío a todos los que escuchan
+// en /api/v1/eventos. Así el editor y la administración se actualizan sin tener que volver a
+// pedir la lista de documentos cada poco.
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Evento {
+    // "creado", "cambiado", "movido", "borrado", "restaurado", "recargado" o "publicada"
+    pub tipo: &'static str,
+    pub documento: Id,
+    pub padre: Id,
+    pub título: String,
+    pub revisión: u64,
+    // Nombre de quien hizo el cambio, sólo para los editores
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub actor: Option<String>,
+    // Nombre de la versión publicada, sólo en los eventos "publicada"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versión: Option<String>,
+    // Número del evento, que envío como id del evento SSE. Empieza en 1 al arrancar el servidor.
+    #[serde(skip)]
+    número: u64,
+    // Ancestros del documento, para filtrar por subárbol. Si se ha movido, los de antes y los
+    // de después.
+    #[serde(skip)]
+    ancestros: Vec<Id>,
+}
+
+impl Evento {
+    // "documentos" es la lista en la que está, o estaba, el documento
+    pub fn nuevo(
+        tipo: &'static str,
+        documento: &Documento,
+        documentos: &[Documento],
+        actor: &Usuario,
+    ) -> Evento {
+        Evento {
+            tipo,
+            documento: documento.id,
+            padre: documento.padre,
+            título: documento.título.clone(),
+            revisión: documento.revisión,
+            actor: Some(actor.nombre.clone()),
+            versión: None,
+            número: 0,
+            ancestros: integridad::ancestros(documentos, documento.id),
+        }
+    }
+
+    // Un documento movido interesa también a quien escucha el subárbol del que ha salido
+    pub fn antes_bajo(mut self, ancestros: Vec<Id>) -> Evento {
+        for ancestro in ancestros {
+            if !self.ancestros.contains(&ancestro) {
+                self.ancestros.push(ancestro);
+            }
+        }
+        self
+    }
+
+    // Todo el árbol puede haber cambiado, por ejemplo al restaurar una copia o al recargar los
+    // documentos editados fuera del servidor: los clientes deben volver a leerlo entero
+    pub fn recarga() -> Evento {
+        Evento {
+            tipo: "recargado",
+            documento: 0,
+            padre: 0,
+            título: String::new(),
+            revisión: 0,
+            actor: None,
+            versión: None,
+            número: 0,
+            ancestros: vec![],
+        }
+    }
+
+    // Se ha publicado una versión de la documentación, que abarca el árbol entero
+    pub fn publicación(versión: &str, actor: &Usuario) -> Evento {
+        Evento {
+            tipo: "publicada",
+            documento: 0,
+            padre: 0,
+            título: String::new(),
+            revisión: 0,
+            actor: Some(actor.nombre.clone()),
+            versión: Some(versión.to_string()),
+            número: 0,
+            ancestros: vec![],
+        }
+    }
+
+    fn pertenece_a(&self, subárbol: Id) -> bool {
+        self.tipo == "recargado"
+            || self.tipo == "publicada"
+            || subárbol == 0
+            || self.documento == subárbol
+            || self.ancestros.contains(&subárbol)
+    }
+}
+
+// Eventos pendientes por oyente. Si alguno se retrasa más, le pido que recargue. También es el
+// número de eventos recientes que guardo para los clientes que se reconectan.
+const RECIENTES: usize = 256;
+
+pub struct Eventos {
+    canal: broadcast::Sender<Evento>,
+    // Número del último evento y los eventos más recientes, del más antiguo al más nuevo
+    recientes: Mutex<(u64, VecDeque<Evento>)>,
+}
+
+impl Eventos {
+    pub fn emite(&self, mut evento: Evento) {
+        // Numero y envío con los recientes bloqueados, para que quien se suscribe a la vez los
+        // vea en el mismo orden en la lista y en el canal
+        let mut recientes = self.recientes.lock().unwrap();
+        recientes.0 += 1;
+        evento.número = recientes.0;
+        if recientes.1.len() == RECIENTES {
+            recientes.1.pop_front();
+        }
+        recientes.1.push_back(evento.clone());
+        // Si nadie escucha, el evento se pierde, y no pasa nada
+        let _ = self.canal.send(evento);
+    }
+
+    // Para quien quiera seguir los cambios dentro del servidor, como los webhooks
+    pub fn suscribe(&self) -> broadcast::Receiver<Evento> {
+        self.canal.subscribe()
+    }
+
+    // Me suscribo y recupero los eventos posteriores a "último" que todavía guardo. Si ya no
+    // los guardo todos, o "último" es de antes de arrancar el servidor, sólo puedo pedir que
+    // recargue; la recarga lleva el número del último evento, para reanudar desde ahí.
+    fn reanuda(&self, último: u64) -> (broadcast::Receiver<Evento>, Vec<Evento>) {
+        let recientes = self.recientes.lock().unwrap();
+        let receptor = self.canal.subscribe();
+        let primero = recientes
+            .1
+            .front()
+            .map(|e| e.número)
+            .unwrap_or(recientes.0 + 1);
+        let pendientes = if último > recientes.0 || último + 1 < primero {
+            let mut recarga = Evento::recarga();
+            recarga.número = recientes.0;
+            vec![recarga]
+        } else {
+            recientes
+                .1
+                .iter()
+                .filter(|e| e.número > último)
+                .cloned()
+                .collect()
+        };
+        (receptor, pendientes)
+    }
+}
+
+pub fn prepara_estado_inicial() -> Eventos {
+    let (canal, _) = broadcast::channel(RECIENTES);
+    Eventos {
+        canal,
+        recientes: Mutex::new((0, VecDeque::new())),
+    }
+}
+
+// Id del último evento que recibió el cliente, que EventSource envía al reconectarse
+pub struct ÚltimoEvento(Option<u64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ÚltimoEvento {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> request::Outcome<ÚltimoEvento, Self::Error> {
+        request::Outcome::Success(ÚltimoEvento(
+            request
+                .headers()
+                .get_one("Last-Event-ID")
+                .and_then(|id| id.trim().parse().ok()),
+        ))
+    }
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+// Cualquiera puede leer los documentos, así que cualquiera puede escuchar sus cambios. Sólo los
+// editores ven quién los ha hecho. Con ?documento=<id> sólo recibo los de ese subárbol. Con la
+// cabecera Last-Event-ID recibo primero los que me he perdido, y con ?límite=<n> el flujo
+// termina después de <n> eventos.
+#[get("/eventos?<documento>&<límite>")]
+fn escucha_eventos(
+    documento: Option<Id>,
+    límite: Option<usize>,
+    eventos: &State<Eventos>,
+    visitante: Visitante,
+    último: ÚltimoEvento,
+    mut apagado: Shutdown,
+) -> EventStream![] {
+    let (mut receptor, pendientes) = match último.0 {
+        Some(último) => eventos.reanuda(último),
+        None => (eventos.suscribe(), vec![]),
+    };
+    let mut pendientes = VecDeque::from(pendientes);
+    let subárbol = documento.unwrap_or(0);
+    let editor = visitante.tiene_rol("Editor");
+    let mut restantes = límite.unwrap_or(usize::MAX);
+
+    EventStream! {
+        while restantes > 0 {
+            let mut evento = match pendientes.pop_front() {
+                Some(evento) => evento,
+                None => select! {
+                    recibido = receptor.recv() => match recibido {
+                        Ok(evento) => evento,
+                        Err(RecvError::Closed) => break,
+                        // Me he perdido eventos: el cliente debe volver a leer el árbol
+                        Err(RecvError::Lagged(_)) => Evento::recarga(),
+                    },
+                    _ = &mut apagado => break,
+                },
+            };
+            if !evento.pertenece_a(subárbol) {
+                continue;
+            }
+            if !editor {
+                evento.actor = None;
+            }
+            restantes -= 1;
+            let mensaje = Event::json(&evento).event(evento.tipo);
+            // La recarga por retraso no tiene número: no se puede reanudar desde ella
+            if evento.número > 0 {
+                yield mensaje.id(evento.número.to_string());
+            } else {
+                yield mensaje;
+            }
+        }
+    }
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![escucha_eventos]
+}
@@ -0,0 +1,415 @@
+use rocket::http::{ContentType, Header, Status};
+use rocket::serde::json::{json, Value};
+use rocket::serde::Deserialize;
+use rocket::{Config, State};
+
+use std::collections::HashSet;
+use std::io::{Cursor, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+use super::auditoria::{Auditoría, Entrada};
+use super::documentos::{Documento, Documentos};
+use super::enlaces;
+use super::id::Id;
+use super::roles::Administrador;
+use super::traducciones;
+use super::usuarios::Usuario;
+use super::versiones::{self, Versiones};
+
+/*
+ * Exportación de la documentación a un sitio estático
+ */
+
+// Recorro el árbol de documentos desde la raíz y genero una página HTML por documento, con el menú
+// de navegación y enlaces al anterior y al siguiente en el orden de lectura. La raíz es la
+// portada, "index.html", e incluye el índice completo. El resultado se puede copiar a cualquier
+// alojamiento de archivos estáticos o leer sin conexión.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigExportación {
+    // Carpeta en la que escribe la exportación el punto de acceso
+    #[serde(default = "carpeta_por_defecto")]
+    pub carpeta: String,
+    // Como las secciones de Rocket.toml, la clave va sin tilde: "titulo"
+    #[serde(default = "título_por_defecto", rename = "titulo")]
+    pub título: String,
+    // Plantilla HTML propia; sin ella uso la del servidor
+    #[serde(default)]
+    pub plantilla: Option<String>,
+    // Carpeta con archivos que copio tal cual junto a las páginas, como imágenes o estilos
+    #[serde(default)]
+    pub recursos: Option<String>,
+}
+
+fn carpeta_por_defecto() -> String {
+    "exportacion".to_string()
+}
+
+fn título_por_defecto() -> String {
+    "Documentación del Lenguaje Ñ".to_string()
+}
+
+pub fn lee_configuración() -> ConfigExportación {
+    Config::figment()
+        .extract_inner::<ConfigExportación>("exportacion")
+        .unwrap_or(ConfigExportación {
+            carpeta: carpeta_por_defecto(),
+            título: título_por_defecto(),
+            plantilla: None,
+            recursos: None,
+        })
+}
+
+// Marcas que sustituyo en la plantilla: {{idioma}}, {{título_sitio}}, {{título}},
+// {{navegación}}, {{anterior}}, {{siguiente}} y {{contenido}}
+const PLANTILLA: &str = r#"<!DOCTYPE html>
+<html lang="{{idioma}}">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{título}} - {{título_sitio}}</title>
+<link rel="stylesheet" href="estilo.css">
+</head>
+<body>
+<nav class="menu">
+<a class="portada" href="index.html">{{título_sitio}}</a>
+{{navegación}}
+</nav>
+<main>
+<h1>{{título}}</h1>
+{{contenido}}
+<nav class="paginación">{{anterior}} {{siguiente}}</nav>
+</main>
+</body>
+</html>
+"#;
+
+const ESTILO: &str = "body { display: flex; margin: 0; font-family: sans-serif; line-height: 1.5; }
+.menu { flex: 0 0 16em; padding: 1em; background: #f4f4f4; min-height: 100vh; }
+.menu ul { list-style: none; padding-left: 1em; }
+.menu .actual { font-weight: bold; }
+main { flex: 1; max-width: 50em; padding: 1em 2em; }
+.paginación { display: flex; justify-content: space-between; margin-top: 3em; }
+";
+
+// Un archivo del sitio exportado, con su ruta relativa
+pub struct Archivo {
+    pub ruta: String,
+    pub contenido: Vec<u8>,
+}
+
+pub fn escapa(texto: &str) -> String {
+    texto
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn página(id: Id) -> String {
+    if id == 0 {
+        "index.html".to_string()
+    } else {
+        format!("{}.html", id)
+    }
+}
+
+// Título y contenido de un documento en un idioma, o en el original si no está traducido
+pub fn texto<'a>(documento: &'a Documento, idioma: Option<&str>) -> (&'a str, &'a str) {
+    match idioma.and_then(|idioma| documento.traducciones.get(idioma)) {
+        Some(traducción) => (&traducción.título, &traducción.contenido),
+        None => (&documento.título, &documento.contenido),
+    }
+}
+
+// Documentos en orden de lectura: cada uno seguido de sus hijos, empezando por "desde". Si el
+// árbol tiene algún problema, me salto los documentos que no encuentro o que ya he visitado.
+pub fn en_orden(documentos: &[Documento], desde: Id) -> Vec<&Documento> {
+    fn visita<'a>(
+        documentos: &'a [Documento],
+        id: Id,
+        visitados: &mut HashSet<Id>,
+        orden: &mut Vec<&'a Documento>,
+    ) {
+        let documento = match documentos.iter().find(|d| d.id == id) {
+            Some(documento) if visitados.insert(id) => documento,
+            _ => return,
+        };
+        orden.push(documento);
+        for hijo in documento.hijos.iter() {
+            visita(documentos, *hijo, visitados, orden);
+        }
+    }
+
+    let mut orden = vec![];
+    visita(documentos, desde, &mut HashSet::new(), &mut orden);
+    orden
+}
+
+// Lista anidada con los hijos de un documento, marcando el actual
+fn navegación(
+    documentos: &[Documento],
+    id: Id,
+    actual: Id,
+    idioma: Option<&str>,
+    visitados: &mut HashSet<Id>,
+) -> String {
+    let documento = match documentos.iter().find(|d| d.id == id) {
+        Some(documento) if visitados.insert(id) => documento,
+        _ => return String::new(),
+    };
+    let mut html = String::new();
+    for hijo in documento.hijos.iter() {
+        let doc_hijo = match documentos.iter().find(|d| d.id == *hijo) {
+            Some(doc_hijo) => doc_hijo,
+            None => continue,
+        };
+        let clase = if *hijo == actual {
+            " class=\"actual\""
+        } else {
+            ""
+        };
+        html.push_str(&format!(
+            "<li><a{} href=\"{}\">{}</a>{}</li>\n",
+            clase,
+            página(*hijo),
+            escapa(texto(doc_hijo, idioma).0),
+            navegación(documentos, *hijo, actual, idioma, visitados)
+        ));
+    }
+    if html.is_empty() {
+        return html;
+    }
+    format!("\n<ul>\n{}</ul>\n", html)
+}
+
+// Añade los archivos de una carpeta, y los de sus subcarpetas, con su ruta relativa a "base"
+fn añade_recursos(base: &Path, carpeta: &Path, archivos: &mut Vec<Archivo>) -> Result<(), String> {
+    let entradas =
+        std::fs::read_dir(carpeta).map_err(|e| format!("{}: {}", carpeta.display(), e))?;
+    for entrada in entradas {
+        let ruta = entrada.map_err(|e| e.to_string())?.path();
+        if ruta.is_dir() {
+            añade_recursos(base, &ruta, archivos)?;
+            continue;
+        }
+        let relativa = ruta.strip_prefix(base).unwrap();
+        archivos.push(Archivo {
+            ruta: relativa.to_string_lossy().replace('\\', "/"),
+            contenido: std::fs::read(&ruta).map_err(|e| format!("{}: {}", ruta.display(), e))?,
+        });
+    }
+    Ok(())
+}
+
+// Genera todos los archivos del sitio. Con "idioma", uso las traducciones que haya a ese idioma.
+pub fn genera(
+    documentos: &[Documento],
+    idioma: Option<&str>,
+    config: &ConfigExportación,
+) -> Result<Vec<Archivo>, String> {
+    let plantilla = match &config.plantilla {
+        Some(ruta) => std::fs::read_to_string(ruta).map_err(|e| format!("{}: {}", ruta, e))?,
+        None => PLANTILLA.to_string(),
+    };
+    let idioma_página = idioma
+        .map(|i| i.to_string())
+        .unwrap_or_else(|| traducciones::lee_configuración().origen);
+
+    let orden = en_orden(documentos, 0);
+    let exportados: HashSet<Id> = orden.iter().map(|d| d.id).collect();
+    let mut archivos = vec![];
+    for (i, documento) in orden.iter().enumerate() {
+        let (título, contenido) = texto(documento, idioma);
+        // Los enlaces a otros documentos apuntan a sus páginas
+        let mut contenido = enlaces::redirige(contenido, |id| {
+            exportados.contains(&id).then(|| página(id))
+        });
+        let título = if documento.id == 0 {
+            config.título.clone()
+        } else {
+            título.to_string()
+        };
+        if documento.id == 0 {
+            contenido.push_str("\n<h2>Índice</h2>");
+            contenido.push_str(&navegación(documentos, 0, 0, idioma, &mut HashSet::new()));
+        }
+        let enlace = |documento: Option<&&Documento>, clase: &str, flecha: &str| match documento {
+            Some(documento) if documento.id != 0 => format!(
+                "<a class=\"{}\" href=\"{}\">{}</a>",
+                clase,
+                página(documento.id),
+                flecha.replace("{}", &escapa(texto(documento, idioma).0))
+            ),
+            _ => String::new(),
+        };
+        let anterior = enlace(
+            i.checked_sub(1).and_then(|i| orden.get(i)),
+            "anterior",
+            "&larr; {}",
+        );
+        let siguiente = enlace(orden.get(i + 1), "siguiente", "{} &rarr;");
+
+        // El contenido va el último, para no sustituir marcas que aparezcan dentro de él
+        let html = plantilla
+            .replace("{{idioma}}", &idioma_página)
+            .replace("{{título_sitio}}", &escapa(&config.título))
+            .replace("{{título}}", &escapa(&título))
+            .replace(
+                "{{navegación}}",
+                &navegación(documentos, 0, documento.id, idioma, &mut HashSet::new()),
+            )
+            .replace("{{anterior}}", &anterior)
+            .replace("{{siguiente}}", &siguiente)
+            .replace("{{contenido}}", &contenido);
+        archivos.push(Archivo {
+            ruta: página(documento.id),
+            contenido: html.into_bytes(),
+        });
+    }
+
+    archivos.push(Archivo {
+        ruta: "estilo.css".to_string(),
+        contenido: ESTILO.as_bytes().to_vec(),
+    });
+    // Los recursos pueden sustituir el estilo por defecto
+    if let Some(recursos) = &config.recursos {
+        let mut copiados = vec![];
+        añade_recursos(Path::new(recursos), Path::new(recursos), &mut copiados)?;
+        archivos.retain(|a| !copiados.iter().any(|c| c.ruta == a.ruta));
+        archivos.extend(copiados);
+    }
+
+    Ok(archivos)
+}
+
+// Escribe el sitio en una carpeta. Si ya existe, sólo la sustituyo si contiene una exportación
+// anterior, para no borrar por error otra carpeta.
+pub fn escribe_carpeta(carpeta: &Path, archivos: &[Archivo]) -> Result<(), String> {
+    if carpeta.exists() && !carpeta.join("index.html").exists() {
+        return Err(format!(
+            "{} ya existe y no contiene una exportación; no la sustituyo",
+            carpeta.display()
+        ));
+    }
+    // Escribo primero en una carpeta temporal, para no dejar la exportación a medias
+    let temporal = PathBuf::from(format!("{}.tmp", carpeta.display()));
+    if temporal.exists() {
+        std::fs::remove_dir_all(&temporal).map_err(|e| e.to_string())?;
+    }
+    for archivo in archivos {
+        let ruta = temporal.join(&archivo.ruta);
+        if let Some(padre) = ruta.parent() {
+            std::fs::create_dir_all(padre).map_err(|e| format!("{}: {}", padre.display(), e))?;
+        }
+        std::fs::write(&ruta, &archivo.contenido)
+            .map_err(|e| format!("{}: {}", ruta.display(), e))?;
+    }
+    if carpeta.exists() {
+        std::fs::remove_dir_all(carpeta).map_err(|e| format!("{}: {}", carpeta.display(), e))?;
+    }
+    std::fs::rename(&temporal, carpeta).map_err(|e| format!("{}: {}", carpeta.display(), e))
+}
+
+// Empaqueta el sitio en un archivo zip
+pub fn empaqueta(archivos: &[Archivo]) -> Result<Vec<u8>, String> {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    let opciones = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    for archivo in archivos {
+        zip.start_file(archivo.ruta.as_str(), opciones)
+            .map_err(|e| e.to_string())?;
+        zip.write_all(&archivo.contenido)
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(zip.finish().map_err(|e| e.to_string())?.into_inner())
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+// Sólo vive lo que tarda en enviarse, así que no me preocupa su tamaño
+#[allow(clippy::large_enum_variant)]
+#[derive(Responder)]
+enum Exportación {
+    Zip(Vec<u8>, ContentType, Header<'static>),
+    Carpeta(Value),
+}
+
+// Exporta los documentos en desarrollo, o los de ?versión=, y los devuelve en un zip. Con
+// ?formato=carpeta, los escribe en la carpeta de la configuración.
+#[allow(clippy::too_many_arguments)]
+#[post("/exportación?<formato>&<versión>&<idioma>")]
+async fn exporta_documentos(
+    formato: Option<&str>,
+    versión: Option<&str>,
+    idioma: Option<&str>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Exportación, Status> {
+    let formato = formato.unwrap_or("zip");
+    if formato != "zip" && formato != "carpeta" {
+        return Err(Status::BadRequest);
+    }
+    let documentos = if versiones::es_desarrollo(versión) {
+        lista.lock().await.clone()
+    } else {
+        let versiones = versiones.lock().await;
+        versiones::busca(&versiones, versión.unwrap())
+            .ok_or(Status::NotFound)?
+            .documentos
+            .clone()
+    };
+
+    let config = lee_configuración();
+    let error = |e: String| {
+        println!("No he podido exportar los documentos: {}", e);
+        Status::InternalServerError
+    };
+    let archivos = genera(&documentos, idioma, &config).map_err(error)?;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "exporta_documentos",
+            Some(&usuario),
+            "exportación".to_string(),
+            ip,
+        )
+        .después(json!({
+            "formato": formato,
+            "versión": versión,
+            "idioma": idioma,
+            "archivos": archivos.len()
+        })),
+    );
+
+    if formato == "carpeta" {
+        escribe_carpeta(Path::new(&config.carpeta), &archivos).map_err(error)?;
+        return Ok(Exportación::Carpeta(json!({
+            "estado": "ok",
+            "carpeta": config.carpeta,
+            "archivos": archivos.len()
+        })));
+    }
+    let zip = empaqueta(&archivos).map_err(error)?;
+    Ok(Exportación::Zip(
+        zip,
+        ContentType::ZIP,
+        Header::new(
+            "Content-Disposition",
+            "attachment; filename=\"documentacion.zip\"",
+        ),
+    ))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![exporta_documentos]
+}
@@ -0,0 +1,579 @@
+use pulldown_cmark::{html, CowStr, Event, Options, Parser, Tag};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+
+use super::documentos::Documento;
+use super::id::Id;
+use super::papelera::{self, Eliminado};
+
+/*
+ * Importación de documentos desde archivos Markdown
+ */
+
+// Cada carpeta se convierte en un documento, con el contenido de su "index.md", y cada archivo
+// ".md" en un documento hijo del de su carpeta. Al principio de cada archivo puede ir una cabecera
+// entre líneas "---" con el título, el orden y el slug:
+//
+//     ---
+//     título: Instalación
+//     orden: 2
+//     slug: instalacion
+//     ---
+//
+// El slug, que por defecto es el nombre del archivo o de la carpeta, identifica al documento entre
+// sus hermanos. Los documentos importados guardan además su origen, la ruta de slugs dentro de la
+// carpeta. Así, al volver a importar la misma carpeta, cambio los documentos que ya importé en
+// lugar de crearlos otra vez, y borro los que ya no están. Los documentos sin origen, creados a
+// través de la API aunque tengan slug, no los toco.
+const ÍNDICE: &str = "index.md";
+
+// Un documento tal como está en la carpeta
+struct Fuente {
+    slug: String,
+    título: String,
+    orden: Option<i64>,
+    markdown: String,
+    // Archivo del que sale el contenido, para resolver los enlaces relativos
+    archivo: Option<PathBuf>,
+    hijos: Vec<Fuente>,
+}
+
+// Un cambio que hace, o que haría, la importación
+pub struct Cambio {
+    pub acción: &'static str,
+    pub documento: Id,
+    pub ruta: String,
+    pub detalle: String,
+}
+
+pub struct Importación {
+    pub documentos: Vec<Documento>,
+    pub eliminados: Vec<Eliminado>,
+    pub cambios: Vec<Cambio>,
+}
+
+// Separa la cabecera del resto del archivo
+fn cabecera(texto: &str) -> (HashMap<String, String>, &str) {
+    let mut campos = HashMap::new();
+    let resto = match texto
+        .strip_prefix("---\n")
+        .or_else(|| texto.strip_prefix("---\r\n"))
+    {
+        Some(resto) => resto,
+        None => return (campos, texto),
+    };
+    let mut posición = 0;
+    for línea in resto.split_inclusive('\n') {
+        posición += línea.len();
+        let línea = línea.trim();
+        if línea == "---" {
+            return (campos, &resto[posición..]);
+        }
+        if let Some((clave, valor)) = línea.split_once(':') {
+            let valor = valor.trim().trim_matches('"');
+            campos.insert(clave.trim().to_lowercase(), valor.to_string());
+        }
+    }
+    // Sin la línea de cierre, no es una cabecera
+    (HashMap::new(), texto)
+}
+
+// Lee un archivo Markdown. "nombre" es el de la carpeta o el archivo, sin la extensión.
+fn lee_fuente(archivo: Option<&Path>, nombre: &str) -> Result<Fuente, String> {
+    let texto = match archivo {
+        Some(archivo) => {
+            std::fs::read_to_string(archivo).map_err(|e| format!("{}: {}", archivo.display(), e))?
+        }
+        None => String::new(),
+    };
+    let (campos, markdown) = cabecera(&texto);
+    let campo = |claves: &[&str]| {
+        claves
+            .iter()
+            .find_map(|clave| campos.get(*clave))
+            .filter(|valor| !valor.is_empty())
+            .cloned()
+    };
+    let orden = match campo(&["orden", "order"]) {
+        Some(orden) => Some(orden.parse::<i64>().map_err(|_| {
+            format!(
+                "{}: el orden debe ser un número",
+                archivo.unwrap().display()
+            )
+        })?),
+        None => None,
+    };
+
+    Ok(Fuente {
+        slug: campo(&["slug"]).unwrap_or_else(|| nombre.to_string()),
+        título: campo(&["título", "titulo", "title"]).unwrap_or_else(|| nombre.to_string()),
+        orden,
+        markdown: markdown.to_string(),
+        archivo: archivo.map(|a| a.to_path_buf()),
+        hijos: vec![],
+    })
+}
+
+// Lee una carpeta con sus subcarpetas
+fn lee_carpeta(carpeta: &Path) -> Result<Fuente, String> {
+    let nombre = carpeta
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let índice = carpeta.join(ÍNDICE);
+    let mut fuente = lee_fuente(índice.is_file().then_some(índice.as_path()), &nombre)?;
+
+    let entradas =
+        std::fs::read_dir(carpeta).map_err(|e| format!("{}: {}", carpeta.display(), e))?;
+    let mut rutas: Vec<PathBuf> = entradas
+        .map(|e| e.map(|e| e.path()).map_err(|e| e.to_string()))
+        .collect::<Result<_, _>>()?;
+    // Ordeno por nombre para que el resultado no dependa del sistema de archivos
+    rutas.sort();
+    for ruta in rutas {
+        let nombre = ruta
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if nombre.starts_with('.') || nombre == ÍNDICE {
+            continue;
+        }
+        if ruta.is_dir() {
+            fuente.hijos.push(lee_carpeta(&ruta)?);
+        } else if let Some(nombre) = nombre.strip_suffix(".md") {
+            fuente.hijos.push(lee_fuente(Some(&ruta), nombre)?);
+        }
+    }
+    // Primero los que indican su orden, y luego los demás, por nombre
+    fuente
+        .hijos
+        .sort_by_key(|h| (h.orden.is_none(), h.orden.unwrap_or(0)));
+
+    let mut slugs = HashSet::new();
+    for hijo in fuente.hijos.iter() {
+        if !slugs.insert(hijo.slug.as_str()) {
+            return Err(format!(
+                "{}: hay dos documentos con el slug \"{}\"",
+                carpeta.display(),
+                hijo.slug
+            ));
+        }
+    }
+    Ok(fuente)
+}
+
+// Quita los "." y ".." de una ruta sin tocar el sistema de archivos
+fn normaliza(ruta: &Path) -> PathBuf {
+    let mut normalizada = PathBuf::new();
+    for componente in ruta.components() {
+        match componente {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalizada.pop();
+            }
+            otro => normalizada.push(otro),
+        }
+    }
+    normalizada
+}
+
+// Convierte el Markdown a HTML. Los enlaces relativos a otros archivos importados pasan a apuntar
+// a sus documentos.
+fn a_html<'a>(fuente: &'a Fuente, ids: &HashMap<PathBuf, Id>) -> String {
+    let carpeta = fuente
+        .archivo
+        .as_ref()
+        .and_then(|a| a.parent())
+        .map(|c| c.to_path_buf());
+    let redirige = |destino: &str| -> Option<String> {
+        let carpeta = match &carpeta {
+            Some(carpeta) if !destino.contains("://") && !destino.starts_with('/') => carpeta,
+            _ => return None,
+        };
+        let (ruta, fragmento) = match destino.find('#') {
+            Some(i) => (&destino[..i], &destino[i..]),
+            None => (destino, ""),
+        };
+        if ruta.is_empty() {
+            return None;
+        }
+        let mut enlazado = normaliza(&carpeta.join(ruta));
+        // Un enlace a una carpeta lleva a su índice
+        if !ruta.ends_with(".md") {
+            enlazado = enlazado.join(ÍNDICE);
+        }
+        ids.get(&enlazado)
+            .map(|id| format!("/documento/{}{}", id, fragmento))
+    };
+    let cambia = |destino: CowStr<'a>| -> CowStr<'a> {
+        match redirige(&destino) {
+            Some(nuevo) => nuevo.into(),
+            None => destino,
+        }
+    };
+
+    let eventos = Parser::new_ext(&fuente.markdown, Options::all()).map(|evento| match evento {
+        Event::Start(Tag::Link(tipo, destino, título)) => {
+            Event::Start(Tag::Link(tipo, cambia(destino), título))
+        }
+        Event::End(Tag::Link(tipo, destino, título)) => {
+            Event::End(Tag::Link(tipo, cambia(destino), título))
+        }
+        otro => otro,
+    });
+    let mut resultado = String::new();
+    html::push_html(&mut resultado, eventos);
+    resultado
+}
+
+// Las rutas son relativas al documento en el que importo, que es "/"
+fn visible(ruta: &str) -> String {
+    if ruta.is_empty() {
+        "/".to_string()
+    } else {
+        ruta.to_string()
+    }
+}
+
+struct Importador<'a> {
+    documentos: Vec<Documento>,
+    siguiente_id: &'a mut Id,
+    // Id de cada archivo importado, para resolver los enlaces
+    ids: HashMap<PathBuf, Id>,
+    // Id que corresponde a cada fuente, en el orden en que las recorro
+    asignados: Vec<Id>,
+    creados: HashSet<Id>,
+    sobrantes: Vec<(Id, String)>,
+    cambios: Vec<Cambio>,
+}
+
+impl<'a> Importador<'a> {
+    fn posición(&self, id: Id) -> usize {
+        self.documentos.iter().position(|d| d.id == id).unwrap()
+    }
+
+    // Primera pasada: busco o creo el documento de cada fuente y ordeno los hijos
+    fn asigna(&mut self, fuente: &Fuente, id: Id, ruta: &str) -> Result<(), String> {
+        self.asignados.push(id);
+        if let Some(archivo) = &fuente.archivo {
+            self.ids.insert(normaliza(archivo), id);
+        }
+
+        let mut hijos_importados = vec![];
+        for hijo in fuente.hijos.iter() {
+            let ruta_hijo = format!("{}/{}", ruta, hijo.slug);
+            let existente = self.documentos[self.posición(id)]
+                .hijos
+                .iter()
+                .find_map(|h| {
+                    self.documentos
+                        .iter()
+                        .find(|d| d.id == *h && d.slug.as_deref() == Some(hijo.slug.as_str()))
+                });
+            let id_hijo = match existente {
+                Some(existente) if existente.origen.is_some() => existente.id,
+                // No me apropio de un documento que no importé, ni repito su slug
+                Some(existente) => {
+                    return Err(format!(
+                        "{}: el documento {} ya usa el slug \"{}\" y no viene de una importación",
+                        visible(ruta),
+                        existente.id,
+                        hijo.slug
+                    ))
+                }
+                None => {
+                    let id_hijo = *self.siguiente_id;
+                    *self.siguiente_id += 1;
+                    self.documentos.push(Documento {
+                        id: id_hijo,
+                        padre: id,
+                        título: hijo.título.clone(),
+                        contenido: String::new(),
+                        hijos: vec![],
+                        revisión: 1,
+                        traducciones: BTreeMap::new(),
+                        slug: Some(hijo.slug.clone()),
+                        origen: Some(ruta_hijo.clone()),
+                    });
+                    self.creados.insert(id_hijo);
+                    self.cambios.push(Cambio {
+                        acción: "crea",
+                        documento: id_hijo,
+                        ruta: ruta_hijo.clone(),
+                        detalle: hijo.título.clone(),
+                    });
+                    id_hijo
+                }
+            };
+            hijos_importados.push(id_hijo);
+            self.asigna(hijo, id_hijo, &ruta_hijo)?;
+        }
+
+        // Los hijos importados van primero, en su orden. Detrás dejo los que no vienen de una
+        // importación, y los demás sobran.
+        let i = self.posición(id);
+        let mut hijos = hijos_importados.clone();
+        let mut sobrantes = vec![];
+        for hijo in self.documentos[i].hijos.iter() {
+            if hijos_importados.contains(hijo) {
+                continue;
+            }
+            match self.documentos.iter().find(|d| d.id == *hijo) {
+                Some(doc_hijo) => match &doc_hijo.origen {
+                    Some(origen) => sobrantes.push((*hijo, origen.clone())),
+                    None => hijos.push(*hijo),
+                },
+                None => continue,
+            }
+        }
+
+        // Sólo aviso del cambio de orden si no se explica por los documentos que creo o borro
+        let sin_cambios = |lista: &[Id]| -> Vec<Id> {
+            lista
+                .iter()
+                .copied()
+                .filter(|h| !self.creados.contains(h) && !sobrantes.iter().any(|(s, _)| s == h))
+                .collect()
+        };
+        if sin_cambios(&self.documentos[i].hijos) != sin_cambios(&hijos) {
+            self.cambios.push(Cambio {
+                acción: "ordena",
+                documento: id,
+                ruta: visible(ruta),
+                detalle: String::new(),
+            });
+        }
+        // Los que sobran siguen en la lista hasta que los saque, con su subárbol, al final
+        hijos.extend(sobrantes.iter().map(|(s, _)| *s));
+        if self.documentos[i].hijos != hijos {
+            self.documentos[i].hijos = hijos;
+            if !self.creados.contains(&id) {
+                self.documentos[i].revisión += 1;
+            }
+        }
+        self.sobrantes.extend(sobrantes);
+        Ok(())
+    }
+
+    // Segunda pasada: con todos los ids ya asignados, convierto el Markdown y actualizo los
+    // documentos que han cambiado
+    fn actualiza(&mut self, fuente: &Fuente, siguiente: &mut usize, ruta: &str, es_destino: bool) {
+        let id = self.asignados[*siguiente];
+        *siguiente += 1;
+        // El documento en el que importo sólo toma el contenido del "index.md" de la carpeta
+        let tiene_contenido = !es_destino || fuente.archivo.is_some();
+        if id != 0 && tiene_contenido {
+            let contenido = a_html(fuente, &self.ids);
+            let i = self.posición(id);
+            let doc = &mut self.documentos[i];
+            if doc.título != fuente.título || doc.contenido != contenido {
+                doc.título = fuente.título.clone();
+                doc.contenido = contenido;
+                if !self.creados.contains(&id) {
+                    doc.revisión += 1;
+                    self.cambios.push(Cambio {
+                        acción: "cambia",
+                        documento: id,
+                        ruta: visible(ruta),
+                        detalle: fuente.título.clone(),
+                    });
+                }
+            }
+        }
+        for hijo in fuente.hijos.iter() {
+            self.actualiza(hijo, siguiente, &format!("{}/{}", ruta, hijo.slug), false);
+        }
+    }
+}
+
+// Prepara la importación de una carpeta bajo el documento "destino", sin guardar nada. Los
+// documentos que sobran pasan a la papelera.
+pub fn importa(
+    carpeta: &Path,
+    documentos: &[Documento],
+    destino: Id,
+    siguiente_id: &mut Id,
+) -> Result<Importación, String> {
+    if !documentos.iter().any(|d| d.id == destino) {
+        return Err(format!("No existe el documento {}", destino));
+    }
+    let fuente = lee_carpeta(carpeta)?;
+
+    let mut importador = Importador {
+        documentos: documentos.to_vec(),
+        siguiente_id,
+        ids: HashMap::new(),
+        asignados: vec![],
+        creados: HashSet::new(),
+        sobrantes: vec![],
+        cambios: vec![],
+    };
+    importador.asigna(&fuente, destino, "")?;
+    importador.actualiza(&fuente, &mut 0, "", true);
+
+    let mut documentos = importador.documentos;
+    let mut eliminados = vec![];
+    let mut cambios = importador.cambios;
+    for (sobrante, ruta) in importador.sobrantes {
+        let eliminado = papelera::retira(&mut documentos, sobrante, "importación");
+        cambios.push(Cambio {
+            acción: "borra",
+            documento: sobrante,
+            ruta,
+            detalle: format!("{} documentos", eliminado.documentos.len()),
+        });
+        eliminados.push(eliminado);
+    }
+
+    Ok(Importación {
+        documentos,
+        eliminados,
+        cambios,
+    })
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+    use crate::documentos;
+    use crate::temporal::Carpeta;
+
+    // Carpeta temporal con los archivos indicados
+    fn carpeta(nombre: &str, archivos: &[(&str, &str)]) -> Carpeta {
+        let carpeta = Carpeta::nueva(&format!("importacion-{}", nombre));
+        for (archivo, texto) in archivos {
+            carpeta.escribe(archivo, texto);
+        }
+        carpeta
+    }
+
+    fn documento<'a>(documentos: &'a [Documento], slug: &str) -> &'a Documento {
+        documentos
+            .iter()
+            .find(|d| d.slug.as_deref() == Some(slug))
+            .unwrap()
+    }
+
+    fn acciones(importación: &Importación) -> Vec<&'static str> {
+        importación.cambios.iter().map(|c| c.acción).collect()
+    }
+
+    const GUÍA: &[(&str, &str)] = &[
+        ("index.md", "# Guía\n"),
+        (
+            "uno.md",
+            "---\ntítulo: Uno\norden: 1\n---\nPrimero, y luego [dos](dos.md)\n",
+        ),
+        ("dos.md", "---\ntítulo: Dos\norden: 2\n---\nSegundo\n"),
+        ("tres/index.md", "---\norden: 3\n---\nTercero\n"),
+        ("tres/cuatro.md", "Cuarto\n"),
+    ];
+
+    #[test]
+    fn importar_otra_vez_no_cambia_nada() {
+        let carpeta = carpeta("repetida", GUÍA);
+        let mut siguiente_id = 1;
+        let primera = importa(carpeta.ruta(), &[documentos::raíz()], 0, &mut siguiente_id).unwrap();
+        assert_eq!(acciones(&primera), vec!["crea"; 4]);
+        assert_eq!(primera.documentos.len(), 5);
+
+        let segunda = importa(carpeta.ruta(), &primera.documentos, 0, &mut siguiente_id).unwrap();
+        assert!(segunda.cambios.is_empty());
+        assert!(segunda.eliminados.is_empty());
+        assert_eq!(siguiente_id, 5);
+        assert_eq!(
+            serde_json::to_value(&segunda.documentos).unwrap(),
+            serde_json::to_value(&primera.documentos).unwrap()
+        );
+    }
+
+    #[test]
+    fn cambiar_el_orden_reordena_sin_crear_documentos() {
+        let carpeta = carpeta("reordenada", GUÍA);
+        let mut siguiente_id = 1;
+        let primera = importa(carpeta.ruta(), &[documentos::raíz()], 0, &mut siguiente_id).unwrap();
+        let uno = documento(&primera.documentos, "uno").id;
+        let dos = documento(&primera.documentos, "dos").id;
+        let tres = documento(&primera.documentos, "tres").id;
+
+        carpeta.escribe(
+            "uno.md",
+            "---\ntítulo: Uno\norden: 4\n---\nPrimero, y luego [dos](dos.md)\n",
+        );
+        let segunda = importa(carpeta.ruta(), &primera.documentos, 0, &mut siguiente_id).unwrap();
+        assert_eq!(acciones(&segunda), vec!["ordena"]);
+        assert_eq!(segunda.documentos[0].hijos, vec![dos, tres, uno]);
+        assert_eq!(
+            segunda.documentos[0].revisión,
+            primera.documentos[0].revisión + 1
+        );
+        // Los enlaces siguen apuntando al mismo documento
+        assert_eq!(
+            documento(&segunda.documentos, "uno").contenido,
+            documento(&primera.documentos, "uno").contenido
+        );
+    }
+
+    #[test]
+    fn quitar_un_archivo_lo_lleva_a_la_papelera() {
+        let carpeta = carpeta("recortada", GUÍA);
+        let mut siguiente_id = 1;
+        let primera = importa(carpeta.ruta(), &[documentos::raíz()], 0, &mut siguiente_id).unwrap();
+        let tres = documento(&primera.documentos, "tres").id;
+        let cuatro = documento(&primera.documentos, "cuatro").id;
+
+        std::fs::remove_dir_all(carpeta.ruta().join("tres")).unwrap();
+        let segunda = importa(carpeta.ruta(), &primera.documentos, 0, &mut siguiente_id).unwrap();
+        assert_eq!(acciones(&segunda), vec!["borra"]);
+        assert_eq!(segunda.eliminados.len(), 1);
+        assert_eq!(segunda.eliminados[0].id, tres);
+        assert_eq!(segunda.eliminados[0].documentos.len(), 2);
+        assert!(!segunda
+            .documentos
+            .iter()
+            .any(|d| d.id == tres || d.id == cuatro));
+        assert!(!segunda.documentos[0].hijos.contains(&tres));
+    }
+
+    // Un documento creado a través de la API bajo la raíz
+    fn ajeno(slug: &str) -> Vec<Documento> {
+        let mut raíz = documentos::raíz();
+        raíz.hijos.push(1);
+        let ajeno = Documento {
+            id: 1,
+            padre: 0,
+            título: "Escrito a mano".to_string(),
+            contenido: String::new(),
+            hijos: vec![],
+            revisión: 1,
+            traducciones: BTreeMap::new(),
+            slug: Some(slug.to_string()),
+            origen: None,
+        };
+        vec![raíz, ajeno]
+    }
+
+    #[test]
+    fn los_documentos_no_importados_no_se_tocan() {
+        let carpeta = carpeta("ajena", GUÍA);
+        let mut siguiente_id = 2;
+        let importación = importa(carpeta.ruta(), &ajeno("a-mano"), 0, &mut siguiente_id).unwrap();
+        assert!(importación.eliminados.is_empty());
+        assert_eq!(importación.documentos[0].hijos.last(), Some(&1));
+        assert_eq!(
+            documento(&importación.documentos, "uno").origen.as_deref(),
+            Some("/uno")
+        );
+    }
+
+    #[test]
+    fn no_se_apropia_de_un_slug_ajeno() {
+        let carpeta = carpeta("ocupada", GUÍA);
+        let mut siguiente_id = 2;
+        let error = importa(carpeta.ruta(), &ajeno("dos"), 0, &mut siguiente_id)
+            .err()
+            .unwrap();
+        assert!(error.contains("\"dos\""));
+    }
+}
@@ -0,0 +1,337 @@
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::serde::Serialize;
+use rocket::State;
+
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::condiciones::avanza_revisiones;
+use super::contadores::Contadores;
+use super::documentos::{self, Documento, Documentos};
+use super::enlaces::ÍndiceEnlaces;
+use super::eventos::{Evento, Eventos};
+use super::id::Id;
+use super::roles::Administrador;
+use super::usuarios::Usuario;
+
+/*
+ * Integridad del árbol de documentos
+ */
+
+// Invariantes del árbol:
+// - existe el documento raíz, con id 0, que es su propio padre
+// - no hay dos documentos con el mismo id
+// - el padre de cada documento existe, y subiendo por los padres se llega a la raíz
+// - los hijos de un documento son exactamente los documentos que lo tienen como padre, sin repetir
+#[derive(Clone, Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Problema {
+    pub tipo: &'static str,
+    pub documento: Id,
+    pub detalle: String,
+}
+
+impl Problema {
+    fn nuevo(tipo: &'static str, documento: Id, detalle: String) -> Problema {
+        Problema {
+            tipo,
+            documento,
+            detalle,
+        }
+    }
+}
+
+// Devuelve los ancestros de un documento hasta la raíz, o None si hay un ciclo o un padre
+// que no existe
+fn sube_hasta_la_raíz(id: Id, padres: &HashMap<Id, Id>) -> Option<Vec<Id>> {
+    let mut camino = vec![];
+    let mut actual = id;
+    let mut vistos = HashSet::new();
+    while actual != 0 {
+        if !vistos.insert(actual) {
+            return None;
+        }
+        actual = *padres.get(&actual)?;
+        camino.push(actual);
+    }
+    Some(camino)
+}
+
+// Indica si colgar el documento "id" de "nuevo_padre" crearía un ciclo, es decir, si el nuevo
+// padre es el propio documento o uno de sus descendientes
+pub fn crearía_ciclo(documentos: &[Documento], id: Id, nuevo_padre: Id) -> bool {
+    if id == nuevo_padre {
+        return true;
+    }
+    let padres: HashMap<Id, Id> = documentos.iter().map(|d| (d.id, d.padre)).collect();
+    match sube_hasta_la_raíz(nuevo_padre, &padres) {
+        Some(ancestros) => ancestros.contains(&id),
+        None => true,
+    }
+}
+
+// Ancestros de un documento, del padre a la raíz. Si el árbol está roto, los que se puedan
+// alcanzar antes de dar con el problema.
+pub fn ancestros(documentos: &[Documento], id: Id) -> Vec<Id> {
+    let padres: HashMap<Id, Id> = documentos.iter().map(|d| (d.id, d.padre)).collect();
+    let mut camino = vec![];
+    let mut actual = id;
+    while actual != 0 {
+        match padres.get(&actual) {
+            Some(padre) if !camino.contains(padre) => {
+                camino.push(*padre);
+                actual = *padre;
+            }
+            _ => break,
+        }
+    }
+    camino
+}
+
+pub fn comprueba(documentos: &[Documento]) -> Vec<Problema> {
+    let mut problemas = vec![];
+
+    match documentos.iter().find(|d| d.id == 0) {
+        None => problemas.push(Problema::nuevo(
+            "sin_raíz",
+            0,
+            "No existe el documento raíz".to_string(),
+        )),
+        Some(raíz) if raíz.padre != 0 => problemas.push(Problema::nuevo(
+            "raíz_con_padre",
+            0,
+            format!("El padre de la raíz es {}, y debería ser 0", raíz.padre),
+        )),
+        _ => {}
+    }
+
+    let mut ids = HashSet::new();
+    for doc in documentos.iter() {
+        if !ids.insert(doc.id) {
+            problemas.push(Problema::nuevo(
+                "id_repetido",
+                doc.id,
+                format!("Hay más de un documento con el id {}", doc.id),
+            ));
+        }
+    }
+
+    // Si hay ids repetidos, me quedo con el primero, que es el que usa la API
+    let mut por_id: HashMap<Id, &Documento> = HashMap::new();
+    for doc in documentos.iter() {
+        por_id.entry(doc.id).or_insert(doc);
+    }
+    let padres: HashMap<Id, Id> = por_id.values().map(|d| (d.id, d.padre)).collect();
+
+    for doc in documentos.iter().filter(|d| d.id != 0) {
+        if !por_id.contains_key(&doc.padre) {
+            problemas.push(Problema::nuevo(
+                "huérfano",
+                doc.id,
+                format!("Su padre, {}, no existe", doc.padre),
+            ));
+        } else if sube_hasta_la_raíz(doc.id, &padres).is_none() {
+            problemas.push(Problema::nuevo(
+                "ciclo",
+                doc.id,
+                "Subiendo por sus padres no se llega a la raíz".to_string(),
+            ));
+        } else if !por_id[&doc.padre].hijos.contains(&doc.id) {
+            problemas.push(Problema::nuevo(
+                "hijo_no_listado",
+                doc.id,
+                format!("Su padre, {}, no lo incluye entre sus hijos", doc.padre),
+            ));
+        }
+
+        let mut hijos = HashSet::new();
+        for hijo in doc.hijos.iter() {
+            if !hijos.insert(*hijo) {
+                problemas.push(Problema::nuevo(
+                    "hijo_repetido",
+                    doc.id,
+                    format!("Incluye varias veces al hijo {}", hijo),
+                ));
+            }
+        }
+    }
+
+    for doc in documentos.iter() {
+        for hijo in doc.hijos.iter() {
+            match por_id.get(hijo) {
+                None => problemas.push(Problema::nuevo(
+                    "hijo_inexistente",
+                    doc.id,
+                    format!("Su hijo {} no existe", hijo),
+                )),
+                Some(h) if h.padre != doc.id || h.id == 0 => problemas.push(Problema::nuevo(
+                    "hijo_ajeno",
+                    doc.id,
+                    format!("Incluye al hijo {}, cuyo padre es {}", hijo, h.padre),
+                )),
+                _ => {}
+            }
+        }
+    }
+
+    problemas
+}
+
+// Repara el árbol y devuelve los problemas que había. Los documentos con id repetido reciben
+// ids nuevos a partir de "siguiente_id", que queda apuntando al siguiente id libre.
+pub fn repara(documentos: &mut Vec<Documento>, siguiente_id: &mut Id) -> Vec<Problema> {
+    let problemas = comprueba(documentos);
+    if problemas.is_empty() {
+        return problemas;
+    }
+    let anteriores = documentos.clone();
+
+    // La raíz
+    match documentos.iter().position(|d| d.id == 0) {
+        Some(i) => documentos[i].padre = 0,
+        None => documentos.insert(
+            0,
+            Documento {
+                id: 0,
+                padre: 0,
+                título: String::new(),
+                contenido: String::new(),
+                hijos: vec![],
+                revisión: 1,
+                traducciones: Default::default(),
+                slug: None,
+                origen: None,
+            },
+        ),
+    }
+
+    // Ids repetidos: el primero conserva el id y los demás reciben uno nuevo
+    let mut ids = HashSet::new();
+    for doc in documentos.iter_mut() {
+        if !ids.insert(doc.id) {
+            doc.id = *siguiente_id;
+            *siguiente_id += 1;
+            ids.insert(doc.id);
+        }
+    }
+
+    // Huérfanos y ciclos: los cuelgo de la raíz. Lo repito hasta que no queda ninguno, porque
+    // romper un ciclo puede bastar para arreglar otros documentos.
+    loop {
+        let padres: HashMap<Id, Id> = documentos.iter().map(|d| (d.id, d.padre)).collect();
+        let perdido = documentos
+            .iter()
+            .position(|d| d.id != 0 && sube_hasta_la_raíz(d.id, &padres).is_none());
+        match perdido {
+            Some(i) => documentos[i].padre = 0,
+            None => break,
+        }
+    }
+
+    // Rehago las listas de hijos a partir de los padres. Respeto el orden de los hijos que ya
+    // estaban bien, y añado al final los que faltaban, por orden de id.
+    let mut hijos_de: HashMap<Id, Vec<Id>> = HashMap::new();
+    for doc in documentos.iter().filter(|d| d.id != 0) {
+        hijos_de.entry(doc.padre).or_default().push(doc.id);
+    }
+    for doc in documentos.iter_mut() {
+        let mut verdaderos = hijos_de.remove(&doc.id).unwrap_or_default();
+        verdaderos.sort_unstable();
+        let mut hijos: Vec<Id> = vec![];
+        for hijo in doc.hijos.iter() {
+            if verdaderos.contains(hijo) && !hijos.contains(hijo) {
+                hijos.push(*hijo);
+            }
+        }
+        for hijo in verdaderos {
+            if !hijos.contains(&hijo) {
+                hijos.push(hijo);
+            }
+        }
+        doc.hijos = hijos;
+    }
+    avanza_revisiones(&anteriores, documentos);
+
+    problemas
+}
+
+// Muestra en la consola los problemas del árbol, por ejemplo al arrancar el servidor
+pub fn informa(documentos: &[Documento]) {
+    let problemas = comprueba(documentos);
+    if problemas.is_empty() {
+        return;
+    }
+    println!(
+        "El árbol de documentos tiene {} problemas. Usa `servidor comprueba --repara` o \
+         /api/v1/integridad/reparación para repararlos:",
+        problemas.len()
+    );
+    for problema in problemas.iter() {
+        println!(
+            "  documento {}: {} ({})",
+            problema.documento, problema.detalle, problema.tipo
+        );
+    }
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[get("/integridad", format = "json")]
+async fn comprueba_integridad(
+    lista: &State<Documentos>,
+    _usuario: Usuario,
+    _administrador: Administrador,
+) -> Value {
+    let lista = lista.lock().await;
+    let problemas = comprueba(&lista);
+
+    json!({ "estado": "ok", "problemas": problemas })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/integridad/reparación")]
+async fn repara_integridad(
+    lista: &State<Documentos>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    contadores: &State<Contadores>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut lista = documentos::bloquea_para_cambiar(lista, almacén).await?;
+    let mut siguiente_id = *contadores.siguientes().get("documentos").unwrap_or(&1);
+    // Reparo una copia y sólo la paso a memoria cuando está guardada
+    let mut nuevos = lista.clone();
+    let problemas = repara(&mut nuevos, &mut siguiente_id);
+
+    if !problemas.is_empty() {
+        contadores.asegura("documentos", siguiente_id);
+        contadores.guarda(almacén)?;
+        documentos::guarda_copia_documentos(almacén, índice, &nuevos).await?;
+        *lista = nuevos;
+        eventos.emite(Evento::recarga());
+        auditoría.registra(
+            Entrada::nueva(
+                "repara_documentos",
+                Some(&usuario),
+                "documento/".to_string(),
+                ip,
+            )
+            .después(json!(problemas)),
+        );
+    }
+
+    Ok(json!({ "estado": "ok", "reparados": problemas }))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![comprueba_integridad, repara_integridad]
+}
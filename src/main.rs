@@ -4,13 +4,49 @@ extern crate rocket;
 use rocket::fairing::AdHoc;
 use rocket::serde::json::{json, Value};
 
+mod almacen;
 mod archivos;
+mod auditoria;
+mod bloqueos;
+mod comandos;
+mod condiciones;
+mod contadores;
+mod copias;
+mod correo;
 mod cors;
 mod documentos;
+mod enlaces;
+mod epub;
+mod esquema;
+mod eventos;
+mod exportacion;
 mod id;
+mod importacion;
+mod integridad;
+mod mapa;
+mod novedades;
+mod oidc;
+mod paginas;
+mod papelera;
+mod recuperacion;
 mod roles;
 mod sesion;
+#[cfg(test)]
+mod temporal;
+mod traducciones;
 mod usuarios;
+mod versiones;
+mod vigilancia;
+mod webhooks;
+
+#[catch(400)]
+fn error_400() -> Value {
+    json!({
+        "estado": "error",
+        "código": 400,
+        "mensaje": "Petición incorrecta."
+    })
+}
 
 #[catch(401)]
 fn error_401() -> Value {
@@ -39,6 +75,42 @@ fn error_404() -> Value {
     })
 }
 
+#[catch(409)]
+fn error_409() -> Value {
+    json!({
+        "estado": "error",
+        "código": 409,
+        "mensaje": "Los datos guardados han cambiado. Vuelve a leerlos antes de modificarlos."
+    })
+}
+
+#[catch(412)]
+fn error_412() -> Value {
+    json!({
+        "estado": "error",
+        "código": 412,
+        "mensaje": "La revisión no coincide: alguien ha cambiado el recurso. Vuelve a leerlo antes de modificarlo."
+    })
+}
+
+#[catch(423)]
+fn error_423() -> Value {
+    json!({
+        "estado": "error",
+        "código": 423,
+        "mensaje": "Otra persona está editando el documento. Espera a que termine o fuerza el cambio."
+    })
+}
+
+#[catch(428)]
+fn error_428() -> Value {
+    json!({
+        "estado": "error",
+        "código": 428,
+        "mensaje": "Para borrar un documento con hijos, confirma cuántos documentos se borran con ?confirmación=<número>."
+    })
+}
+
 #[catch(500)]
 fn error_500() -> Value {
     json!({
@@ -57,25 +129,94 @@ fn stage() -> rocket::fairing::AdHoc {
     println!("La clave ofuscada es: {}", sesion::ofusca_clave(&clave));
 
     rocket::fairing::AdHoc::on_ignite("JSON", |rocket| async {
+        let almacén = almacen::prepara_estado_inicial();
+        let mut documentos = documentos::prepara_estado_inicial(&almacén);
+        let mut usuarios = usuarios::prepara_estado_inicial(&almacén);
+        integridad::informa(documentos.get_mut());
+        let enlaces = enlaces::prepara_estado_inicial(documentos.get_mut());
+        let contadores = contadores::prepara_estado_inicial(
+            &almacén,
+            documentos.get_mut().iter().map(|d| d.id),
+            usuarios.get_mut().iter().map(|u| u.id),
+        );
+        let webhooks = webhooks::prepara_estado_inicial(&almacén);
+        let mut papelera = papelera::prepara_estado_inicial(&almacén);
+        // Los documentos de la papelera conservan sus ids, para poder restaurarlos
+        contadores.respeta(
+            "documentos",
+            papelera
+                .get_mut()
+                .iter()
+                .flat_map(|e| e.documentos.iter().map(|d| d.id)),
+        );
+        contadores.respeta("suscripciones", webhooks.ids().await.into_iter());
+
         rocket
             .mount("/", archivos::rutas())
+            .mount("/", mapa::rutas())
+            .mount("/", novedades::rutas())
             .mount("/api/v1/", documentos::rutas())
             .mount("/api/v1/", sesion::rutas())
+            .mount("/api/v1/", recuperacion::rutas())
+            .mount("/api/v1/", oidc::rutas())
+            .mount("/api/v1/", papelera::rutas())
+            .mount("/api/v1/", auditoria::rutas())
+            .mount("/api/v1/", bloqueos::rutas())
+            .mount("/api/v1/", copias::rutas())
+            .mount("/api/v1/", enlaces::rutas())
+            .mount("/api/v1/", epub::rutas())
+            .mount("/api/v1/", eventos::rutas())
+            .mount("/api/v1/", exportacion::rutas())
+            .mount("/api/v1/", integridad::rutas())
+            .mount("/api/v1/", traducciones::rutas())
             .mount("/api/v1/", usuarios::rutas())
+            .mount("/api/v1/", versiones::rutas())
+            .mount("/api/v1/", webhooks::rutas())
             .register(
                 "/api/v1/",
-                catchers![error_401, error_403, error_404, error_500],
+                catchers![
+                    error_400, error_401, error_403, error_404, error_409, error_412, error_423,
+                    error_428, error_500
+                ],
             )
-            .manage(documentos::prepara_estado_inicial())
-            .manage(usuarios::prepara_estado_inicial())
-            .manage(sesion::prepara_estado_inicial())
+            .manage(contadores)
+            .manage(documentos)
+            .manage(usuarios)
+            .manage(sesion::prepara_estado_inicial(&almacén))
+            .manage(recuperacion::prepara_estado_inicial())
+            .manage(correo::prepara_estado_inicial())
+            .manage(oidc::prepara_estado_inicial())
+            .manage(auditoria::prepara_estado_inicial())
+            .manage(bloqueos::prepara_estado_inicial())
+            .manage(eventos::prepara_estado_inicial())
+            .manage(enlaces)
+            .manage(epub::prepara_estado_inicial())
+            .manage(webhooks)
+            .manage(papelera)
+            .manage(versiones::prepara_estado_inicial(&almacén))
+            .manage(almacén)
     })
 }
 
-#[launch]
-fn rocket() -> _ {
+fn rocket() -> rocket::Rocket<rocket::Build> {
     rocket::build()
         .attach(cors::CORS)
         .attach(AdHoc::config::<usuarios::ConfigAdmin>())
         .attach(stage())
+        .attach(copias::CopiasProgramadas)
+        .attach(vigilancia::Vigilancia::nueva())
+        .attach(webhooks::Repartidor)
+}
+
+#[rocket::main]
+async fn main() {
+    // Si recibo argumentos, ejecuto la orden correspondiente en lugar de iniciar el servidor
+    let argumentos: Vec<String> = std::env::args().skip(1).collect();
+    if !argumentos.is_empty() {
+        std::process::exit(comandos::ejecuta(&argumentos));
+    }
+
+    if let Err(e) = rocket().launch().await {
+        println!("El servidor se ha detenido por un error: {}", e);
+    }
 }
@@ -0,0 +1,137 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use rocket::http::ContentType;
+use rocket::serde::Deserialize;
+use rocket::{Config, State};
+
+use std::fs;
+
+use super::auditoria::Auditoría;
+use super::documentos::{self, Documentos};
+use super::exportacion::{self, escapa};
+use super::integridad;
+use super::novedades;
+
+/*
+ * Mapa del sitio y robots.txt para los buscadores
+ */
+
+// archivos.rs responde con index.html a cualquier ruta que no conoce, así que los buscadores no
+// encuentran las páginas de los documentos. El mapa del sitio las enumera todas. Estas rutas
+// tienen más prioridad que la de archivos.rs, aunque existan archivos con el mismo nombre en
+// "sitio".
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ConfigRobots {
+    // Rutas que los buscadores no deben recorrer
+    #[serde(default)]
+    prohibidas: Vec<String>,
+    // Archivo propio que sustituye al robots.txt generado
+    archivo: Option<String>,
+}
+
+fn lee_configuración() -> ConfigRobots {
+    Config::figment()
+        .extract_inner::<ConfigRobots>("robots")
+        .unwrap_or(ConfigRobots {
+            prohibidas: vec!["/api/".to_string()],
+            archivo: None,
+        })
+}
+
+fn url_sitio() -> String {
+    Config::figment()
+        .extract_inner::<String>("url_sitio")
+        .unwrap_or_default()
+}
+
+// Cuanto más profundo está un documento en el árbol, menos prioridad tiene: 1.0 la portada,
+// 0.8 sus hijos, y así hasta un mínimo de 0.2
+fn prioridad(profundidad: usize) -> f32 {
+    (1.0 - 0.2 * profundidad as f32).max(0.2)
+}
+
+/*
+ * Puntos de acceso
+ */
+
+#[derive(Responder)]
+struct Xml(String, ContentType);
+
+// Las páginas de los documentos en desarrollo, que son los que cualquiera puede leer. La fecha
+// de cada página es la de la última revisión de su documento que aparece en el registro de
+// auditoría, la misma que anuncian los canales de novedades.
+#[get("/sitemap.xml")]
+async fn mapa_del_sitio(lista: &State<Documentos>, auditoría: &State<Auditoría>) -> Xml {
+    // Las fechas salen de las marcas que guarda el registro de auditoría, sin leer el archivo ni
+    // bloquear los documentos
+    let fechas = novedades::fechas(auditoría, None);
+    let lista = lista.lock().await;
+    let url_sitio = url_sitio();
+    let cambiado = |id| fechas.get(&id).map(|f| f.cambiado);
+    let mut urls = String::new();
+    for documento in exportacion::en_orden(&lista, 0) {
+        let cambiado = if documento.id == 0 {
+            lista.iter().filter_map(|d| cambiado(d.id)).max()
+        } else {
+            cambiado(documento.id)
+        };
+        let fecha = cambiado
+            .and_then(|c| DateTime::<Utc>::from_timestamp(c as i64, 0))
+            .map(|f| {
+                format!(
+                    "<lastmod>{}</lastmod>",
+                    f.to_rfc3339_opts(SecondsFormat::Secs, true)
+                )
+            })
+            .unwrap_or_default();
+        let profundidad = if documento.id == 0 {
+            0
+        } else {
+            integridad::ancestros(&lista, documento.id).len()
+        };
+        urls.push_str(&format!(
+            "<url><loc>{}{}</loc>{}<priority>{:.1}</priority></url>\n",
+            escapa(&url_sitio),
+            escapa(&documentos::dirección(&lista, documento.id)),
+            fecha,
+            prioridad(profundidad)
+        ));
+    }
+
+    Xml(
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <urlset xmlns=\"http://www.sitemaps.org/schemas/sitemap/0.9\">\n\
+             {}</urlset>\n",
+            urls
+        ),
+        ContentType::XML,
+    )
+}
+
+// El archivo configurado, o uno que permite recorrer todo salvo las rutas prohibidas y enlaza
+// con el mapa del sitio
+#[get("/robots.txt")]
+fn robots() -> (ContentType, String) {
+    let config = lee_configuración();
+    if let Some(archivo) = &config.archivo {
+        match fs::read_to_string(archivo) {
+            Ok(contenido) => return (ContentType::Plain, contenido),
+            Err(e) => println!("No he podido leer {}: {}", archivo, e),
+        }
+    }
+    let mut robots = "User-agent: *\n".to_string();
+    if config.prohibidas.is_empty() {
+        robots.push_str("Disallow:\n");
+    }
+    for ruta in config.prohibidas.iter() {
+        robots.push_str(&format!("Disallow: {}\n", ruta));
+    }
+    robots.push_str(&format!("\nSitemap: {}/sitemap.xml\n", url_sitio()));
+    (ContentType::Plain, robots)
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![mapa_del_sitio, robots]
+}
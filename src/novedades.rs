@@ -0,0 +1,484 @@
+use chrono::{DateTime, SecondsFormat, Utc};
+
+use rocket::http::{ContentType, Status};
+use rocket::serde::Deserialize;
+use rocket::{Config, State};
+
+use std::collections::HashMap;
+
+use super::almacen;
+use super::auditoria::{Auditoría, Entrada};
+use super::condiciones::{Etiquetada, SiNoCoincide};
+use super::documentos::{self, Documento, Documentos};
+use super::epub;
+use super::exportacion::{self, escapa};
+use super::id::Id;
+use super::integridad;
+use super::traducciones;
+use super::versiones::{self, Versiones};
+
+/*
+ * Canales de novedades Atom y RSS
+ */
+
+// Los canales anuncian los documentos creados o cambiados más recientemente, para que los
+// lectores sigan el tutorial y la referencia desde su lector de noticias. Los documentos no
+// guardan quién ni cuándo los cambió, así que lo saco del registro de auditoría.
+const NOVEDADES: usize = 20;
+
+// Caracteres del resumen de cada documento
+const LONGITUD_RESUMEN: usize = 300;
+
+// Acciones del registro de auditoría que cuentan como novedad
+const CREACIONES: &[&str] = &["crea_documento", "copia_documento"];
+const CAMBIOS: &[&str] = &["cambia_documento"];
+
+// Elementos que no separan palabras al quitar las etiquetas del resumen
+const EN_LÍNEA: &[&str] = &[
+    "a", "abbr", "b", "code", "em", "i", "kbd", "mark", "s", "small", "span", "strong", "sub",
+    "sup", "u", "var",
+];
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ConfigNovedades {
+    // Versión que anuncian los canales: un nombre, o el de los documentos en desarrollo. Si no se
+    // indica, la última publicada. En Rocket.toml la clave va sin tilde: "version"
+    #[serde(rename = "version")]
+    versión: Option<String>,
+}
+
+fn lee_configuración() -> ConfigNovedades {
+    Config::figment()
+        .extract_inner::<ConfigNovedades>("novedades")
+        .unwrap_or(ConfigNovedades { versión: None })
+}
+
+// Cuándo se creó y cambió por última vez un documento, y quién lo cambió
+#[derive(Clone)]
+pub struct Fechas {
+    // Segundos desde el 1 de enero de 1970
+    pub creado: Option<u64>,
+    pub cambiado: u64,
+    pub autor: String,
+}
+
+// Las fechas de cada documento en desarrollo, con la versión None, y en cada versión guardada
+pub type Marcas = HashMap<(Option<String>, Id), Fechas>;
+
+// Anota en las marcas una entrada del registro de auditoría, si crea o cambia un documento. Al
+// crear una versión, sus documentos toman las fechas que tenían en su origen, y desde entonces
+// sólo cambian con sus propias correcciones.
+pub fn anota(marcas: &mut Marcas, entrada: &Entrada) {
+    let acción = entrada.acción.as_str();
+    if acción == "crea_versión" || acción == "borra_versión" {
+        let nombre = match entrada.objetivo.strip_prefix("versiones/") {
+            Some(nombre) => nombre.to_string(),
+            None => return,
+        };
+        marcas.retain(|(versión, _), _| versión.as_deref() != Some(nombre.as_str()));
+        if acción == "crea_versión" {
+            let origen = entrada
+                .después
+                .as_ref()
+                .and_then(|después| después["origen"].as_str())
+                .filter(|origen| !versiones::es_desarrollo(Some(origen)))
+                .map(|origen| origen.to_string());
+            let copiadas: Vec<_> = marcas
+                .iter()
+                .filter(|((versión, _), _)| *versión == origen)
+                .map(|((_, id), fechas)| ((Some(nombre.clone()), *id), fechas.clone()))
+                .collect();
+            marcas.extend(copiadas);
+        }
+        return;
+    }
+
+    let creación = CREACIONES.contains(&acción);
+    if !creación && !CAMBIOS.contains(&acción) {
+        return;
+    }
+    let (versión, documento) = match entrada.objetivo.strip_prefix("versiones/") {
+        Some(resto) => match resto.split_once("/documento/") {
+            Some((versión, documento)) => (Some(versión.to_string()), documento),
+            None => return,
+        },
+        None => match entrada.objetivo.strip_prefix("documento/") {
+            Some(documento) => (None, documento),
+            None => return,
+        },
+    };
+    let documento: Id = match documento.parse() {
+        Ok(documento) => documento,
+        Err(_) => return,
+    };
+    let fechas = marcas.entry((versión, documento)).or_insert(Fechas {
+        creado: None,
+        cambiado: entrada.momento,
+        autor: String::new(),
+    });
+    if creación {
+        fechas.creado = Some(entrada.momento);
+    }
+    fechas.cambiado = entrada.momento;
+    fechas.autor = entrada.nombre_actor.clone();
+}
+
+// Las fechas de los documentos de una versión, o de los documentos en desarrollo con None
+pub fn fechas(auditoría: &Auditoría, versión: Option<&str>) -> HashMap<Id, Fechas> {
+    auditoría
+        .marcas()
+        .iter()
+        .filter(|((de, _), _)| de.as_deref() == versión)
+        .map(|((_, id), fechas)| (*id, fechas.clone()))
+        .collect()
+}
+
+pub struct Novedad<'a> {
+    pub documento: &'a Documento,
+    pub fechas: Fechas,
+}
+
+// Las fechas de los documentos del árbol. Los documentos que no aparecen en el registro no están,
+// y la raíz, que no tiene contenido, tampoco.
+fn historial<'a>(documentos: &'a [Documento], fechas: &HashMap<Id, Fechas>) -> Vec<Novedad<'a>> {
+    documentos
+        .iter()
+        .filter(|d| d.id != 0)
+        .filter_map(|documento| {
+            fechas.get(&documento.id).map(|f| Novedad {
+                documento,
+                fechas: f.clone(),
+            })
+        })
+        .collect()
+}
+
+// Las novedades de los documentos del subárbol, de la más reciente a la más antigua
+fn novedades<'a>(
+    documentos: &'a [Documento],
+    fechas: &HashMap<Id, Fechas>,
+    subárbol: Id,
+) -> Vec<Novedad<'a>> {
+    let mut novedades: Vec<Novedad> = historial(documentos, fechas)
+        .into_iter()
+        .filter(|n| {
+            subárbol == 0
+                || n.documento.id == subárbol
+                || integridad::ancestros(documentos, n.documento.id).contains(&subárbol)
+        })
+        .collect();
+    novedades.sort_by(|a, b| {
+        b.fechas
+            .cambiado
+            .cmp(&a.fechas.cambiado)
+            .then(b.documento.id.cmp(&a.documento.id))
+    });
+    novedades.truncate(NOVEDADES);
+    novedades
+}
+
+// El principio del contenido, como texto sin etiquetas, con "longitud" caracteres como mucho. El
+// resultado ya es texto XML válido.
+pub fn resumen(contenido: &str, longitud: usize) -> String {
+    let mut texto = String::new();
+    let mut etiqueta: Option<String> = None;
+    for c in contenido.chars() {
+        match (&mut etiqueta, c) {
+            (None, '<') => etiqueta = Some(String::new()),
+            (None, _) => texto.push(c),
+            (Some(interior), '>') => {
+                // Los elementos de bloque separan palabras; los demás, como <b>, no
+                let nombre: String = interior
+                    .trim_start_matches('/')
+                    .chars()
+                    .take_while(|c| c.is_ascii_alphanumeric())
+                    .collect::<String>()
+                    .to_lowercase();
+                if !EN_LÍNEA.contains(&nombre.as_str()) {
+                    texto.push(' ');
+                }
+                etiqueta = None;
+            }
+            (Some(interior), _) => interior.push(c),
+        }
+    }
+    let texto = epub::entidades(&texto);
+    let palabras: Vec<&str> = texto.split_whitespace().collect();
+    let texto = palabras.join(" ");
+    if texto.chars().count() <= longitud {
+        return texto;
+    }
+    // No corto a mitad de una entidad
+    let mut corte: String = texto.chars().take(longitud).collect();
+    if let Some(i) = corte.rfind('&') {
+        if !corte[i..].contains(';') {
+            corte.truncate(i);
+        }
+    }
+    format!("{}…", corte.trim_end())
+}
+
+fn fecha(momento: u64) -> DateTime<Utc> {
+    DateTime::<Utc>::from_timestamp(momento as i64, 0).unwrap_or_default()
+}
+
+struct Canal {
+    título: String,
+    url_sitio: String,
+    // Dirección del propio canal
+    dirección: String,
+}
+
+impl Canal {
+    fn nuevo(documentos: &[Documento], subárbol: Id, archivo: &str) -> Canal {
+        let url_sitio = Config::figment()
+            .extract_inner::<String>("url_sitio")
+            .unwrap_or_default();
+        let mut título = exportacion::lee_configuración().título;
+        let mut dirección = format!("{}/{}", url_sitio, archivo);
+        if let Some(documento) = documentos.iter().find(|d| d.id == subárbol && d.id != 0) {
+            título = format!("{}: {}", título, documento.título);
+            dirección = format!("{}?documento={}", dirección, subárbol);
+        }
+        Canal {
+            título,
+            url_sitio,
+            dirección,
+        }
+    }
+
+    // El identificador no cambia aunque cambie la dirección de la página al mover el documento
+    fn identificador(&self, id: Id) -> String {
+        format!("{}/documento/{}", self.url_sitio, id)
+    }
+
+    fn enlace(&self, documentos: &[Documento], id: Id) -> String {
+        format!(
+            "{}{}",
+            self.url_sitio,
+            documentos::dirección(documentos, id)
+        )
+    }
+
+    fn atom(&self, documentos: &[Documento], novedades: &[Novedad]) -> String {
+        let rfc3339 = |momento| fecha(momento).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut entradas = String::new();
+        for novedad in novedades {
+            let enlace = self.enlace(documentos, novedad.documento.id);
+            entradas.push_str(&format!(
+                "<entry>\n\
+                 <title>{}</title>\n\
+                 <id>{}</id>\n\
+                 <link href=\"{}\"/>\n\
+                 {}<updated>{}</updated>\n\
+                 <author><name>{}</name></author>\n\
+                 <summary type=\"text\">{}</summary>\n\
+                 </entry>\n",
+                escapa(&novedad.documento.título),
+                escapa(&self.identificador(novedad.documento.id)),
+                escapa(&enlace),
+                novedad
+                    .fechas
+                    .creado
+                    .map(|c| format!("<published>{}</published>\n", rfc3339(c)))
+                    .unwrap_or_default(),
+                rfc3339(novedad.fechas.cambiado),
+                escapa(&novedad.fechas.autor),
+                resumen(&novedad.documento.contenido, LONGITUD_RESUMEN),
+            ));
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <feed xmlns=\"http://www.w3.org/2005/Atom\" xml:lang=\"{}\">\n\
+             <title>{}</title>\n\
+             <id>{}</id>\n\
+             <link rel=\"self\" href=\"{}\"/>\n\
+             <link href=\"{}/\"/>\n\
+             <updated>{}</updated>\n\
+             {}</feed>\n",
+            traducciones::lee_configuración().origen,
+            escapa(&self.título),
+            escapa(&self.dirección),
+            escapa(&self.dirección),
+            escapa(&self.url_sitio),
+            rfc3339(novedades.first().map(|n| n.fechas.cambiado).unwrap_or(0)),
+            entradas
+        )
+    }
+
+    fn rss(&self, documentos: &[Documento], novedades: &[Novedad]) -> String {
+        let rfc2822 = |momento| fecha(momento).to_rfc2822();
+        let mut elementos = String::new();
+        for novedad in novedades {
+            let enlace = self.enlace(documentos, novedad.documento.id);
+            // Cada cambio es un elemento nuevo para el lector, así que el guid lleva la revisión
+            elementos.push_str(&format!(
+                "<item>\n\
+                 <title>{}</title>\n\
+                 <link>{}</link>\n\
+                 <guid isPermaLink=\"false\">{}#{}</guid>\n\
+                 <pubDate>{}</pubDate>\n\
+                 <dc:creator>{}</dc:creator>\n\
+                 <description>{}</description>\n\
+                 </item>\n",
+                escapa(&novedad.documento.título),
+                escapa(&enlace),
+                escapa(&self.identificador(novedad.documento.id)),
+                novedad.documento.revisión,
+                rfc2822(novedad.fechas.cambiado),
+                escapa(&novedad.fechas.autor),
+                resumen(&novedad.documento.contenido, LONGITUD_RESUMEN),
+            ));
+        }
+        format!(
+            "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+             <rss version=\"2.0\" xmlns:atom=\"http://www.w3.org/2005/Atom\" \
+             xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+             <channel>\n\
+             <title>{}</title>\n\
+             <link>{}/</link>\n\
+             <description>{}</description>\n\
+             <language>{}</language>\n\
+             <atom:link rel=\"self\" type=\"application/rss+xml\" href=\"{}\"/>\n\
+             <lastBuildDate>{}</lastBuildDate>\n\
+             {}</channel>\n\
+             </rss>\n",
+            escapa(&self.título),
+            escapa(&self.url_sitio),
+            escapa(&self.título),
+            traducciones::lee_configuración().origen,
+            escapa(&self.dirección),
+            rfc2822(novedades.first().map(|n| n.fechas.cambiado).unwrap_or(0)),
+            elementos
+        )
+    }
+}
+
+/*
+ * Puntos de acceso
+ */
+
+#[derive(Responder)]
+struct Xml(String, ContentType);
+
+// La etiqueta es la huella del canal, que sólo cambia cuando hay novedades
+fn responde(xml: String, tipo: ContentType, si_no_coincide: &SiNoCoincide) -> Etiquetada<Xml> {
+    let huella = almacen::calcula_huella(xml.as_bytes());
+    Etiquetada::con_etiqueta(
+        format!("\"{}\"", &huella[..16]),
+        si_no_coincide,
+        Xml(xml, tipo),
+    )
+}
+
+// Genera un canal con las novedades del subárbol, o None si el subárbol no existe
+type Formato = fn(&Canal, &[Documento], &[Novedad]) -> String;
+
+fn genera(
+    documentos: &[Documento],
+    fechas: &HashMap<Id, Fechas>,
+    subárbol: Id,
+    archivo: &str,
+    formato: Formato,
+) -> Option<String> {
+    if !documentos.iter().any(|d| d.id == subárbol) {
+        return None;
+    }
+    let canal = Canal::nuevo(documentos, subárbol, archivo);
+    Some(formato(
+        &canal,
+        documentos,
+        &novedades(documentos, fechas, subárbol),
+    ))
+}
+
+// Los canales anuncian la versión configurada, o la estable si no se indica ninguna. Las fechas
+// salen de las marcas que guarda el registro de auditoría, antes de bloquear los documentos.
+async fn canal(
+    subárbol: Id,
+    archivo: &str,
+    formato: Formato,
+    lista: &Documentos,
+    versiones: &Versiones,
+    auditoría: &Auditoría,
+) -> Result<String, Status> {
+    let configurada = lee_configuración()
+        .versión
+        .unwrap_or_else(|| versiones::ESTABLE.to_string());
+    if !versiones::es_desarrollo(Some(&configurada)) {
+        let versiones = versiones.lock().await;
+        let xml = match versiones::busca(&versiones, &configurada) {
+            Some(versión) => {
+                let fechas = fechas(auditoría, Some(&versión.nombre));
+                genera(&versión.documentos, &fechas, subárbol, archivo, formato)
+            }
+            // Mientras no exista la versión, o no se haya publicado ninguna, el canal está vacío
+            None => genera(
+                &[documentos::raíz()],
+                &HashMap::new(),
+                subárbol,
+                archivo,
+                formato,
+            ),
+        };
+        return xml.ok_or(Status::NotFound);
+    }
+    let fechas = fechas(auditoría, None);
+    let lista = lista.lock().await;
+    genera(&lista, &fechas, subárbol, archivo, formato).ok_or(Status::NotFound)
+}
+
+// Cualquiera puede leer los canales, igual que los documentos. Con ?documento=<id> sólo
+// anuncian las novedades de ese subárbol.
+#[get("/feed.atom?<documento>")]
+async fn canal_atom(
+    documento: Option<Id>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    auditoría: &State<Auditoría>,
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<Xml>, Status> {
+    let xml = canal(
+        documento.unwrap_or(0),
+        "feed.atom",
+        Canal::atom,
+        lista,
+        versiones,
+        auditoría,
+    )
+    .await?;
+    Ok(responde(
+        xml,
+        ContentType::new("application", "atom+xml"),
+        &si_no_coincide,
+    ))
+}
+
+#[get("/feed.rss?<documento>")]
+async fn canal_rss(
+    documento: Option<Id>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    auditoría: &State<Auditoría>,
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<Xml>, Status> {
+    let xml = canal(
+        documento.unwrap_or(0),
+        "feed.rss",
+        Canal::rss,
+        lista,
+        versiones,
+        auditoría,
+    )
+    .await?;
+    Ok(responde(
+        xml,
+        ContentType::new("application", "rss+xml"),
+        &si_no_coincide,
+    ))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![canal_atom, canal_rss]
+}
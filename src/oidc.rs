@@ -0,0 +1,825 @@
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use jsonwebtoken::jwk::{AlgorithmParameters, JwkSet};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+
+use rand::thread_rng;
+use rand::Rng;
+
+use reqwest::Url;
+
+use rocket::http::{Cookie, CookieJar, SameSite, Status};
+use rocket::response::Redirect;
+use rocket::serde::json::{json, Value};
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Mutex;
+use rocket::Config;
+use rocket::State;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::contadores::Contadores;
+use super::id::Id;
+use super::sesion::{self, SesionesActivas};
+use super::usuarios::{self, Usuario, Usuarios};
+use super::webhooks::Webhooks;
+
+/*
+ * Acceso a través de un proveedor de identidad externo (OpenID Connect)
+ */
+
+// Tiempo máximo entre que envío al usuario al proveedor y que éste le devuelve
+const VIGENCIA_PETICIÓN: u64 = 600;
+
+// Galleta con el "state" de la petición, para que la vuelta sólo valga en el navegador que la
+// empezó. Si no, alguien podría hacer entrar a otro con su propia cuenta.
+const GALLETA_ESTADO: &str = "oidc_estado";
+const RUTA_GALLETA: &str = "/api/v1/oidc";
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigOidc {
+    // Dirección del proveedor, la uso para el descubrimiento y para validar "iss"
+    emisor: String,
+    id_cliente: String,
+    secreto_cliente: Option<String>,
+    // Dirección de este servidor a la que vuelve el proveedor: .../api/v1/oidc/vuelta
+    redireccion: String,
+    #[serde(default = "alcance_por_defecto")]
+    alcance: String,
+    // Reivindicación con la que doy nombre a los usuarios nuevos
+    #[serde(default = "campo_nombre_por_defecto")]
+    campo_nombre: String,
+    // Reivindicación con la lista de roles del proveedor, si la hay
+    campo_roles: Option<String>,
+    // Rol del proveedor => rol local
+    #[serde(default)]
+    mapa_roles: HashMap<String, String>,
+    // Si es falso, sólo pueden entrar los usuarios que ya existan aquí
+    #[serde(default)]
+    crea_usuarios: bool,
+    // Roles de los usuarios que creo al entrar por primera vez
+    #[serde(default)]
+    roles_nuevos: Vec<String>,
+    // Adónde envío al usuario cuando ha entrado
+    #[serde(default = "destino_por_defecto")]
+    destino: String,
+}
+
+fn alcance_por_defecto() -> String {
+    "openid profile email".to_string()
+}
+
+fn campo_nombre_por_defecto() -> String {
+    "preferred_username".to_string()
+}
+
+fn destino_por_defecto() -> String {
+    "/".to_string()
+}
+
+// Lo que necesito del documento de descubrimiento del proveedor
+#[derive(Clone, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct Descubrimiento {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct RespuestaSímbolos {
+    id_token: String,
+}
+
+// Datos que guardo mientras el usuario está en el proveedor
+struct PeticiónPendiente {
+    verificador: String,
+    nonce: String,
+    caducidad: SystemTime,
+}
+
+pub struct Oidc {
+    config: Option<ConfigOidc>,
+    descubrimiento: Mutex<Option<Descubrimiento>>,
+    // Claves públicas del proveedor. Sólo las vuelvo a descargar si no encuentro la que firmó un
+    // símbolo, porque el proveedor las ha rotado.
+    claves: Mutex<Option<JwkSet>>,
+    // Peticiones pendientes, indexadas por el parámetro "state"
+    pendientes: Mutex<HashMap<String, PeticiónPendiente>>,
+}
+
+fn aleatorio_url() -> String {
+    let mut aleatorio = [0u8; 32];
+    thread_rng().fill(&mut aleatorio[..]);
+    base64::encode_config(aleatorio, base64::URL_SAFE_NO_PAD)
+}
+
+// Desafío PKCE con el método S256
+fn desafío_pkce(verificador: &str) -> String {
+    let mut olla = Sha256::new();
+    olla.input_str(verificador);
+    let mut resumen = [0u8; 32];
+    olla.result(&mut resumen);
+    base64::encode_config(resumen, base64::URL_SAFE_NO_PAD)
+}
+
+impl Oidc {
+    async fn descubre(&self, config: &ConfigOidc) -> Result<Descubrimiento, String> {
+        let mut descubrimiento = self.descubrimiento.lock().await;
+        if let Some(d) = &*descubrimiento {
+            return Ok(d.clone());
+        }
+
+        let dirección = format!(
+            "{}/.well-known/openid-configuration",
+            config.emisor.trim_end_matches('/')
+        );
+        let d: Descubrimiento = reqwest::get(&dirección)
+            .await
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+        // El documento debe ser del mismo emisor que configuré, que es el que valido en "iss"
+        if d.issuer != config.emisor {
+            return Err(format!(
+                "El emisor del descubrimiento, {}, no es el configurado, {}",
+                d.issuer, config.emisor
+            ));
+        }
+        *descubrimiento = Some(d.clone());
+        Ok(d)
+    }
+
+    async fn clave_de_verificación(
+        &self,
+        config: &ConfigOidc,
+        descubrimiento: &Descubrimiento,
+        algoritmo: Algorithm,
+        kid: Option<String>,
+    ) -> Result<DecodingKey, String> {
+        match algoritmo {
+            // Los algoritmos simétricos usan el secreto del cliente como clave
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                let secreto = config
+                    .secreto_cliente
+                    .as_ref()
+                    .ok_or("Falta el secreto del cliente")?;
+                Ok(DecodingKey::from_secret(secreto.as_bytes()))
+            }
+            _ => {
+                let mut claves = self.claves.lock().await;
+                let mut descargadas = false;
+                let clave = loop {
+                    if claves.is_none() {
+                        let descarga: JwkSet = reqwest::get(&descubrimiento.jwks_uri)
+                            .await
+                            .map_err(|e| e.to_string())?
+                            .json()
+                            .await
+                            .map_err(|e| e.to_string())?;
+                        *claves = Some(descarga);
+                        descargadas = true;
+                    }
+                    let guardadas = claves.as_ref().unwrap();
+                    let encontrada = match &kid {
+                        Some(kid) => guardadas.find(kid),
+                        None => guardadas.keys.first(),
+                    };
+                    match encontrada {
+                        Some(clave) => break clave.clone(),
+                        None if !descargadas => *claves = None,
+                        None => {
+                            return Err(
+                                "No encuentro la clave con la que se firmó el símbolo".to_string()
+                            )
+                        }
+                    }
+                };
+                match &clave.algorithm {
+                    AlgorithmParameters::RSA(rsa) => {
+                        DecodingKey::from_rsa_components(&rsa.n, &rsa.e).map_err(|e| e.to_string())
+                    }
+                    _ => Err("Tipo de clave no admitido".to_string()),
+                }
+            }
+        }
+    }
+
+    // Cambia el código de autorización por el símbolo de identidad, y devuelve sus reivindicaciones
+    async fn canjea_código(
+        &self,
+        config: &ConfigOidc,
+        código: &str,
+        pendiente: &PeticiónPendiente,
+    ) -> Result<HashMap<String, Value>, String> {
+        let descubrimiento = self.descubre(config).await?;
+
+        let mut formulario = vec![
+            ("grant_type", "authorization_code".to_string()),
+            ("code", código.to_string()),
+            ("redirect_uri", config.redireccion.clone()),
+            ("client_id", config.id_cliente.clone()),
+            ("code_verifier", pendiente.verificador.clone()),
+        ];
+        if let Some(secreto) = &config.secreto_cliente {
+            formulario.push(("client_secret", secreto.clone()));
+        }
+
+        let respuesta: RespuestaSímbolos = reqwest::Client::new()
+            .post(&descubrimiento.token_endpoint)
+            .form(&formulario)
+            .send()
+            .await
+            .map_err(|e| e.to_string())?
+            .error_for_status()
+            .map_err(|e| e.to_string())?
+            .json()
+            .await
+            .map_err(|e| e.to_string())?;
+
+        let cabecera = decode_header(&respuesta.id_token).map_err(|e| e.to_string())?;
+        let clave = self
+            .clave_de_verificación(config, &descubrimiento, cabecera.alg, cabecera.kid)
+            .await?;
+
+        let mut validación = Validation::new(cabecera.alg);
+        validación.set_issuer(&[&descubrimiento.issuer]);
+        validación.set_audience(&[&config.id_cliente]);
+        let reivindicaciones =
+            decode::<HashMap<String, Value>>(&respuesta.id_token, &clave, &validación)
+                .map_err(|e| e.to_string())?
+                .claims;
+
+        // Compruebo que el símbolo corresponde a esta petición y no a otra anterior
+        if reivindicaciones.get("nonce").and_then(|n| n.as_str()) != Some(pendiente.nonce.as_str())
+        {
+            return Err("El nonce del símbolo no coincide".to_string());
+        }
+
+        Ok(reivindicaciones)
+    }
+}
+
+// Busco el usuario local que corresponde a la identidad externa, y lo creo si así lo permite la configuración
+fn usuario_de_identidad(
+    config: &ConfigOidc,
+    reivindicaciones: &HashMap<String, Value>,
+    lista: &mut Vec<Usuario>,
+    contadores: &Contadores,
+    almacén: &Almacenamiento,
+) -> Result<Option<Usuario>, Status> {
+    let texto = |campo: &str| {
+        reivindicaciones
+            .get(campo)
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_string())
+    };
+    let sujeto = match texto("sub") {
+        Some(sub) => format!("{}|{}", config.emisor, sub),
+        None => return Ok(None),
+    };
+    // Sólo me fío del correo si el proveedor dice expresamente que lo ha verificado. Si no,
+    // cualquiera con una cuenta en el proveedor podría poner el correo de otro usuario y entrar
+    // en su lugar.
+    let correo = texto("email").filter(|_| {
+        reivindicaciones
+            .get("email_verified")
+            .and_then(|v| v.as_bool())
+            == Some(true)
+    });
+
+    // Roles locales que corresponden a los roles del proveedor
+    let roles_externos: Vec<String> = config
+        .campo_roles
+        .as_ref()
+        .and_then(|campo| reivindicaciones.get(campo))
+        .and_then(|v| v.as_array())
+        .map(|roles| {
+            roles
+                .iter()
+                .filter_map(|r| r.as_str())
+                .filter_map(|r| config.mapa_roles.get(r).cloned())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let posición = lista
+        .iter()
+        .position(|u| u.sujeto_oidc.as_deref() == Some(sujeto.as_str()))
+        .or_else(|| {
+            let correo = correo.as_ref()?;
+            lista
+                .iter()
+                .position(|u| !u.correo.is_empty() && &u.correo == correo)
+        });
+
+    let i = match posición {
+        Some(i) => i,
+        None if config.crea_usuarios => {
+            let identificador = contadores.nuevo_id(almacén, "usuarios")?;
+            lista.push(Usuario {
+                id: identificador,
+                nombre: texto(&config.campo_nombre).unwrap_or_else(|| sujeto.clone()),
+                // Clave aleatoria: estos usuarios sólo entran a través del proveedor
+                clave: aleatorio_url(),
+                roles: config.roles_nuevos.clone(),
+                correo: correo.clone().unwrap_or_default(),
+                sujeto_oidc: None,
+                revisión: 0,
+            });
+            lista.len() - 1
+        }
+        None => return Ok(None),
+    };
+
+    // Sólo cambio la revisión si cambia algo; los usuarios nuevos empiezan en la 1
+    let mut cambiado = lista[i].sujeto_oidc.as_deref() != Some(sujeto.as_str());
+    lista[i].sujeto_oidc = Some(sujeto);
+    for rol in roles_externos.iter() {
+        if !lista[i].roles.contains(rol) {
+            lista[i].roles.push(rol.clone());
+            cambiado = true;
+        }
+    }
+    // Los roles locales de "mapa_roles" los decide el proveedor: si ya no los envía, los retiro
+    if config.campo_roles.is_some() {
+        let antes = lista[i].roles.len();
+        lista[i].roles.retain(|rol| {
+            !config.mapa_roles.values().any(|mapeado| mapeado == rol)
+                || roles_externos.contains(rol)
+        });
+        cambiado |= lista[i].roles.len() != antes;
+    }
+    if cambiado {
+        lista[i].revisión += 1;
+    }
+
+    Ok(Some(lista[i].clone()))
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[get("/oidc/inicio")]
+async fn inicia_oidc(caja: &CookieJar<'_>, oidc: &State<Oidc>) -> Result<Redirect, Status> {
+    let config = oidc.config.as_ref().ok_or(Status::NotFound)?;
+    let descubrimiento = oidc.descubre(config).await.map_err(|e| {
+        println!("No he podido descubrir el proveedor OIDC: {}", e);
+        Status::BadGateway
+    })?;
+
+    let estado = aleatorio_url();
+    let pendiente = PeticiónPendiente {
+        verificador: aleatorio_url(),
+        nonce: aleatorio_url(),
+        caducidad: SystemTime::now()
+            .checked_add(Duration::from_secs(VIGENCIA_PETICIÓN))
+            .unwrap(),
+    };
+
+    let dirección = Url::parse_with_params(
+        &descubrimiento.authorization_endpoint,
+        &[
+            ("response_type", "code"),
+            ("client_id", config.id_cliente.as_str()),
+            ("redirect_uri", config.redireccion.as_str()),
+            ("scope", config.alcance.as_str()),
+            ("state", estado.as_str()),
+            ("nonce", pendiente.nonce.as_str()),
+            (
+                "code_challenge",
+                desafío_pkce(&pendiente.verificador).as_str(),
+            ),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|_| Status::InternalServerError)?;
+
+    let mut pendientes = oidc.pendientes.lock().await;
+    let ahora = SystemTime::now();
+    pendientes.retain(|_, p| p.caducidad > ahora);
+    pendientes.insert(estado.clone(), pendiente);
+    // El proveedor vuelve con una redirección desde otro sitio, así que la galleta no puede ser
+    // SameSite=Strict
+    caja.add_private(
+        Cookie::build(GALLETA_ESTADO, estado)
+            .path(RUTA_GALLETA)
+            .same_site(SameSite::Lax)
+            .http_only(true)
+            .finish(),
+    );
+
+    Ok(Redirect::to(dirección.to_string()))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[get("/oidc/vuelta?<code>&<state>")]
+async fn vuelta_oidc(
+    code: String,
+    state: String,
+    caja: &CookieJar<'_>,
+    oidc: &State<Oidc>,
+    estado_sesiones: &State<SesionesActivas>,
+    estado_usuarios: &State<Usuarios>,
+    almacén: &State<Almacenamiento>,
+    contadores: &State<Contadores>,
+    webhooks: &State<Webhooks>,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Redirect, Status> {
+    let config = oidc.config.as_ref().ok_or(Status::NotFound)?;
+
+    // La vuelta debe llegar al navegador que empezó la petición
+    let galleta = caja.get_private(GALLETA_ESTADO);
+    caja.remove_private(
+        Cookie::build(GALLETA_ESTADO, "")
+            .path(RUTA_GALLETA)
+            .finish(),
+    );
+    if galleta.as_ref().map(|g| g.value()) != Some(state.as_str()) {
+        auditoría.registra(Entrada::nueva(
+            "acceso_fallido",
+            None,
+            "oidc".to_string(),
+            ip,
+        ));
+        return Err(Status::Unauthorized);
+    }
+
+    let pendiente = oidc
+        .pendientes
+        .lock()
+        .await
+        .remove(&state)
+        .ok_or(Status::Unauthorized)?;
+    if pendiente.caducidad < SystemTime::now() {
+        return Err(Status::Unauthorized);
+    }
+
+    let reivindicaciones = oidc
+        .canjea_código(config, &code, &pendiente)
+        .await
+        .map_err(|e| {
+            println!("No he podido validar la identidad OIDC: {}", e);
+            auditoría.registra(Entrada::nueva(
+                "acceso_fallido",
+                None,
+                "oidc".to_string(),
+                ip,
+            ));
+            Status::Unauthorized
+        })?;
+
+    let mut lista = usuarios::bloquea_para_cambiar(estado_usuarios, almacén).await?;
+    // Revisiones anteriores, para saber si el acceso ha creado o cambiado el usuario
+    let revisiones: Vec<(Id, u64)> = lista.iter().map(|u| (u.id, u.revisión)).collect();
+    let mut nuevos = lista.clone();
+    let usuario =
+        match usuario_de_identidad(config, &reivindicaciones, &mut nuevos, contadores, almacén)? {
+            Some(usuario) => usuario,
+            None => {
+                auditoría.registra(Entrada::nueva(
+                    "acceso_fallido",
+                    None,
+                    "oidc".to_string(),
+                    ip,
+                ));
+                return Err(Status::Forbidden);
+            }
+        };
+    usuarios::guarda_copia_usuarios(almacén, &nuevos).await?;
+    *lista = nuevos;
+    drop(lista);
+    auditoría.registra(Entrada::nueva(
+        "acceso_oidc",
+        Some(&usuario),
+        format!("usuario/{}", usuario.id),
+        ip,
+    ));
+    let evento = match revisiones.iter().find(|(id, _)| *id == usuario.id) {
+        None => Some("usuario.creado"),
+        Some((_, revisión)) if *revisión != usuario.revisión => Some("usuario.cambiado"),
+        _ => None,
+    };
+    if let Some(evento) = evento {
+        webhooks.dispara(
+            evento,
+            json!({ "id": usuario.id, "usuario": usuario.resumen(), "actor": usuario.nombre }),
+        );
+    }
+
+    // A partir de aquí, la sesión es igual que la de un acceso con clave
+    let símbolo_sesión: String = sesion::crea_símbolo_sesión();
+    let sesión = sesion::crea_sesión(usuario);
+    let mut sesiones = estado_sesiones.lock().await;
+    sesiones.insert(sesion::ofusca_clave(&símbolo_sesión), sesión);
+    sesion::guarda_copia_sesiones(almacén, &sesiones).await?;
+    drop(sesiones);
+    caja.add_private(Cookie::new("sesión", símbolo_sesión));
+
+    Ok(Redirect::to(config.destino.clone()))
+}
+
+pub fn prepara_estado_inicial() -> Oidc {
+    // Si no hay sección "oidc" en la configuración, sólo se puede entrar con clave
+    let config = Config::figment().extract_inner::<ConfigOidc>("oidc").ok();
+    Oidc {
+        config,
+        descubrimiento: Mutex::new(None),
+        claves: Mutex::new(None),
+        pendientes: Mutex::new(HashMap::new()),
+    }
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![inicia_oidc, vuelta_oidc]
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+    use crate::almacen::AlmacénSqlite;
+    use crate::contadores;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    const EMISOR: &str = "https://proveedor.ejemplo";
+
+    fn configuración(emisor: &str, crea_usuarios: bool) -> ConfigOidc {
+        ConfigOidc {
+            emisor: emisor.to_string(),
+            id_cliente: "cliente".to_string(),
+            secreto_cliente: Some("secreto".to_string()),
+            redireccion: "http://localhost/api/v1/oidc/vuelta".to_string(),
+            alcance: alcance_por_defecto(),
+            campo_nombre: campo_nombre_por_defecto(),
+            campo_roles: Some("grupos".to_string()),
+            mapa_roles: HashMap::from([("redactores".to_string(), "editor".to_string())]),
+            crea_usuarios,
+            roles_nuevos: vec![],
+            destino: destino_por_defecto(),
+        }
+    }
+
+    fn usuario(id: Id, nombre: &str, correo: &str) -> Usuario {
+        Usuario {
+            id,
+            nombre: nombre.to_string(),
+            clave: String::new(),
+            roles: vec![],
+            correo: correo.to_string(),
+            sujeto_oidc: None,
+            revisión: 1,
+        }
+    }
+
+    fn reivindicaciones(valor: Value) -> HashMap<String, Value> {
+        rocket::serde::json::from_value(valor).unwrap()
+    }
+
+    // Busca o crea el usuario de la identidad en una lista con el administrador y "ana"
+    fn entra(
+        config: &ConfigOidc,
+        lista: &mut Vec<Usuario>,
+        identidad: Value,
+    ) -> Result<Option<Usuario>, Status> {
+        let almacén: Almacenamiento = Box::new(AlmacénSqlite::nuevo(":memory:").unwrap());
+        let contadores =
+            contadores::prepara_estado_inicial(&almacén, [0].into_iter(), [0, 1].into_iter());
+        usuario_de_identidad(
+            config,
+            &reivindicaciones(identidad),
+            lista,
+            &contadores,
+            &almacén,
+        )
+    }
+
+    fn usuarios() -> Vec<Usuario> {
+        vec![
+            usuario(0, "Administrador", ""),
+            usuario(1, "ana", "ana@ejemplo.es"),
+        ]
+    }
+
+    #[test]
+    fn asocia_el_correo_verificado_al_usuario_existente() {
+        let config = configuración(EMISOR, false);
+        let mut lista = usuarios();
+        let identidad = json!({ "sub": "a1", "email": "ana@ejemplo.es", "email_verified": true });
+        let ana = entra(&config, &mut lista, identidad).unwrap().unwrap();
+        assert_eq!(ana.id, 1);
+        assert_eq!(
+            ana.sujeto_oidc.as_deref(),
+            Some("https://proveedor.ejemplo|a1")
+        );
+        assert_eq!(ana.revisión, 2);
+
+        // Después la reconozco por su identidad, aunque cambie de correo en el proveedor
+        let identidad = json!({ "sub": "a1", "email": "otro@ejemplo.es", "email_verified": true });
+        let ana = entra(&config, &mut lista, identidad).unwrap().unwrap();
+        assert_eq!(ana.id, 1);
+        assert_eq!(ana.revisión, 2);
+    }
+
+    #[test]
+    fn no_se_fía_de_un_correo_sin_verificar() {
+        let config = configuración(EMISOR, false);
+        let mut lista = usuarios();
+        for verificado in [json!(false), json!("true"), Value::Null] {
+            let identidad =
+                json!({ "sub": "x", "email": "ana@ejemplo.es", "email_verified": verificado });
+            assert!(entra(&config, &mut lista, identidad).unwrap().is_none());
+        }
+        assert!(lista[1].sujeto_oidc.is_none());
+    }
+
+    #[test]
+    fn sin_crear_usuarios_sólo_entran_los_que_existen() {
+        let mut lista = usuarios();
+        let identidad = json!({ "sub": "nuevo", "preferred_username": "luis" });
+        let config = configuración(EMISOR, false);
+        assert!(entra(&config, &mut lista, identidad.clone())
+            .unwrap()
+            .is_none());
+        assert_eq!(lista.len(), 2);
+
+        let config = configuración(EMISOR, true);
+        let luis = entra(&config, &mut lista, identidad).unwrap().unwrap();
+        assert_eq!(luis.nombre, "luis");
+        assert_eq!(luis.revisión, 1);
+        assert!(luis.id > 1);
+        assert_eq!(lista.len(), 3);
+    }
+
+    #[test]
+    fn el_proveedor_concede_y_retira_los_roles_del_mapa() {
+        let config = configuración(EMISOR, false);
+        let mut lista = usuarios();
+        lista[1].roles = vec!["traductor".to_string()];
+        let identidad = json!({
+            "sub": "a1", "email": "ana@ejemplo.es", "email_verified": true,
+            "grupos": ["redactores", "desconocido"],
+        });
+        let ana = entra(&config, &mut lista, identidad).unwrap().unwrap();
+        assert_eq!(ana.roles, vec!["traductor", "editor"]);
+
+        // Los roles que no están en el mapa no los toca
+        let identidad = json!({ "sub": "a1", "grupos": [] });
+        let ana = entra(&config, &mut lista, identidad).unwrap().unwrap();
+        assert_eq!(ana.roles, vec!["traductor"]);
+        assert_eq!(ana.revisión, 3);
+    }
+
+    // Desafío S256 calculado aparte, con hashlib y base64 de Python
+    const VERIFICADOR: &str = "dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFzVG2k";
+
+    #[test]
+    fn el_desafío_pkce_es_el_sha256_en_base64url() {
+        assert_eq!(
+            desafío_pkce(VERIFICADOR),
+            "VVENkNUfpNN_sBIqgmOFXXYmEt4_qddvQdhTSF-hlOA"
+        );
+    }
+
+    // Proveedor simulado que responde al descubrimiento y al canje del código "abc" con un
+    // símbolo firmado con el secreto del cliente, siempre que reciba el verificador de las pruebas.
+    // Devuelve su dirección.
+    fn proveedor(nonce: &'static str, emisor_anunciado: Option<&'static str>) -> String {
+        let escucha = TcpListener::bind("127.0.0.1:0").unwrap();
+        let emisor = format!("http://{}", escucha.local_addr().unwrap());
+        let dirección = emisor.clone();
+        std::thread::spawn(move || {
+            for conexión in escucha.incoming() {
+                let mut conexión = match conexión {
+                    Ok(conexión) => conexión,
+                    Err(_) => break,
+                };
+                let mut lector = BufReader::new(conexión.try_clone().unwrap());
+                let mut petición = String::new();
+                lector.read_line(&mut petición).unwrap();
+                let ruta = petición.split_whitespace().nth(1).unwrap_or("").to_string();
+                let mut longitud = 0;
+                loop {
+                    let mut cabecera = String::new();
+                    lector.read_line(&mut cabecera).unwrap();
+                    if cabecera.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(valor) = cabecera.to_lowercase().strip_prefix("content-length:") {
+                        longitud = valor.trim().parse().unwrap();
+                    }
+                }
+                let mut cuerpo = vec![0; longitud];
+                lector.read_exact(&mut cuerpo).unwrap();
+                let cuerpo = String::from_utf8(cuerpo).unwrap();
+                let campos: Vec<&str> = cuerpo.split('&').collect();
+
+                let (estado, respuesta) = if ruta == "/.well-known/openid-configuration" {
+                    let respuesta = json!({
+                        "issuer": emisor_anunciado.map_or(emisor.clone(), |e| e.to_string()),
+                        "authorization_endpoint": format!("{}/autoriza", emisor),
+                        "token_endpoint": format!("{}/token", emisor),
+                        "jwks_uri": format!("{}/claves", emisor),
+                    });
+                    ("200 OK", respuesta)
+                } else if ruta == "/token"
+                    && campos.contains(&"code=abc")
+                    && campos.contains(&format!("code_verifier={}", VERIFICADOR).as_str())
+                {
+                    let caducidad = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs()
+                        + 60;
+                    let símbolo = encode(
+                        &Header::new(Algorithm::HS256),
+                        &json!({
+                            "iss": emisor, "aud": "cliente", "sub": "a1",
+                            "exp": caducidad, "nonce": nonce,
+                        }),
+                        &EncodingKey::from_secret(b"secreto"),
+                    )
+                    .unwrap();
+                    ("200 OK", json!({ "id_token": símbolo }))
+                } else {
+                    ("400 Bad Request", json!({ "error": "invalid_grant" }))
+                };
+                let respuesta = respuesta.to_string();
+                let _ = write!(
+                    conexión,
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n\r\n{}",
+                    estado,
+                    respuesta.len(),
+                    respuesta
+                );
+            }
+        });
+        dirección
+    }
+
+    fn oidc(emisor: &str) -> (Oidc, ConfigOidc) {
+        let oidc = Oidc {
+            config: None,
+            descubrimiento: Mutex::new(None),
+            claves: Mutex::new(None),
+            pendientes: Mutex::new(HashMap::new()),
+        };
+        (oidc, configuración(emisor, false))
+    }
+
+    fn pendiente(verificador: &str, nonce: &str) -> PeticiónPendiente {
+        PeticiónPendiente {
+            verificador: verificador.to_string(),
+            nonce: nonce.to_string(),
+            caducidad: SystemTime::now(),
+        }
+    }
+
+    #[rocket::async_test]
+    async fn canjea_el_código_con_el_verificador_y_el_nonce_de_la_petición() {
+        let (oidc, config) = oidc(&proveedor("n1", None));
+
+        let identidad = oidc
+            .canjea_código(&config, "abc", &pendiente(VERIFICADOR, "n1"))
+            .await
+            .unwrap();
+        assert_eq!(identidad["sub"], "a1");
+
+        // Un símbolo de otra petición no vale
+        let error = oidc
+            .canjea_código(&config, "abc", &pendiente(VERIFICADOR, "n2"))
+            .await
+            .unwrap_err();
+        assert!(error.contains("nonce"));
+
+        // Sin el verificador, el proveedor no entrega el símbolo
+        assert!(oidc
+            .canjea_código(&config, "abc", &pendiente("otro", "n1"))
+            .await
+            .is_err());
+    }
+
+    #[rocket::async_test]
+    async fn rechaza_el_descubrimiento_de_otro_emisor() {
+        let (oidc, config) = oidc(&proveedor("n1", Some("https://impostor.ejemplo")));
+        match oidc.descubre(&config).await {
+            Err(error) => assert!(error.contains("impostor")),
+            Ok(_) => panic!("aceptó el descubrimiento de otro emisor"),
+        }
+    }
+}
@@ -0,0 +1,129 @@
+use rocket::serde::Deserialize;
+use rocket::Config;
+
+use super::documentos::{self, Documento};
+use super::enlaces;
+use super::exportacion::{self, escapa, texto};
+use super::id::Id;
+use super::novedades;
+use super::traducciones;
+
+/*
+ * Páginas de los documentos generadas en el servidor
+ */
+
+// La aplicación del sitio es una sola página, sitio/index.html, que pide el documento a la API
+// al arrancar. Los buscadores y quienes leen sin JavaScript no verían nada, así que cuando una
+// ruta es la dirección de un documento, añado a esa página el título, la descripción, las
+// etiquetas de Open Graph y el contenido del documento. La aplicación arranca después encima.
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ConfigPáginas {
+    // Id del elemento de index.html en el que se monta la aplicación
+    contenedor: String,
+}
+
+fn lee_configuración() -> ConfigPáginas {
+    Config::figment()
+        .extract_inner::<ConfigPáginas>("paginas")
+        .unwrap_or(ConfigPáginas {
+            contenedor: "app".to_string(),
+        })
+}
+
+// Caracteres de la descripción de la página
+const LONGITUD_DESCRIPCIÓN: usize = 160;
+
+// Posición de "marca" en el HTML, sin distinguir mayúsculas
+fn busca(html: &str, marca: &str) -> Option<usize> {
+    html.to_ascii_lowercase().find(&marca.to_ascii_lowercase())
+}
+
+// Posición justo después de la etiqueta de apertura del elemento con id="<contenedor>", o de
+// <body> si no lo encuentro
+fn inicio_contenedor(html: &str, contenedor: &str) -> Option<usize> {
+    let atributo = busca(html, &format!("id=\"{}\"", contenedor))
+        .or_else(|| busca(html, &format!("id='{}'", contenedor)))
+        .or_else(|| busca(html, "<body"))?;
+    html[atributo..].find('>').map(|fin| atributo + fin + 1)
+}
+
+// Añade los datos del documento "id" a la página de la aplicación
+pub fn genera(base: &str, documentos: &[Documento], id: Id, idioma: &str) -> Option<String> {
+    let documento = documentos.iter().find(|d| d.id == id)?;
+    let origen = traducciones::lee_configuración().origen;
+    let traducción = (idioma != origen).then_some(idioma);
+    let (título, contenido) = texto(documento, traducción);
+    let título_sitio = exportacion::lee_configuración().título;
+    let url_sitio = Config::figment()
+        .extract_inner::<String>("url_sitio")
+        .unwrap_or_default();
+    let url = format!("{}{}", url_sitio, documentos::dirección(documentos, id));
+    let descripción = novedades::resumen(contenido, LONGITUD_DESCRIPCIÓN).replace('"', "&quot;");
+
+    let cabecera = format!(
+        "<title>{título} - {sitio}</title>\n\
+         <meta name=\"description\" content=\"{descripción}\">\n\
+         <link rel=\"canonical\" href=\"{url}\">\n\
+         <meta property=\"og:type\" content=\"article\">\n\
+         <meta property=\"og:site_name\" content=\"{sitio}\">\n\
+         <meta property=\"og:title\" content=\"{título}\">\n\
+         <meta property=\"og:description\" content=\"{descripción}\">\n\
+         <meta property=\"og:url\" content=\"{url}\">\n\
+         <meta property=\"og:locale\" content=\"{idioma}\">\n",
+        título = escapa(título),
+        sitio = escapa(&título_sitio),
+        descripción = descripción,
+        url = escapa(&url),
+        idioma = idioma,
+    );
+
+    // Los enlaces a otros documentos apuntan a sus páginas
+    let contenido = enlaces::redirige(contenido, |destino| {
+        documentos
+            .iter()
+            .any(|d| d.id == destino)
+            .then(|| documentos::dirección(documentos, destino))
+    });
+    // La aplicación puede leer el documento de aquí en lugar de volver a pedirlo a la API.
+    // Escapo "</" para que el contenido no pueda cerrar el <script>.
+    let datos = traducciones::en_idioma(documento, idioma)
+        .to_string()
+        .replace("</", "<\\/");
+    let cuerpo = format!(
+        "<article>\n<h1>{}</h1>\n{}\n</article>\n\
+         <script type=\"application/json\" id=\"documento-inicial\">{}</script>\n",
+        escapa(título),
+        contenido,
+        datos
+    );
+
+    let mut html = base.to_string();
+    // Sustituyo el título de la aplicación, si tiene
+    if let (Some(inicio), Some(fin)) = (busca(&html, "<title"), busca(&html, "</title>")) {
+        if inicio < fin {
+            html.replace_range(inicio..fin + "</title>".len(), "");
+        }
+    }
+    match busca(&html, "</head>") {
+        Some(posición) => html.insert_str(posición, &cabecera),
+        None => html.insert_str(0, &cabecera),
+    }
+    match inicio_contenedor(&html, &lee_configuración().contenedor) {
+        Some(posición) => html.insert_str(posición, &cuerpo),
+        None => html.push_str(&cuerpo),
+    }
+    // El idioma de la página
+    if let Some(posición) = busca(&html, "<html") {
+        let fin = posición + html[posición..].find('>').unwrap_or(0);
+        match html[posición..fin].find("lang=\"") {
+            Some(lang) => {
+                let valor = posición + lang + "lang=\"".len();
+                let fin_valor = valor + html[valor..].find('"').unwrap_or(0);
+                html.replace_range(valor..fin_valor, idioma);
+            }
+            None => html.insert_str(posición + "<html".len(), &format!(" lang=\"{}\"", idioma)),
+        }
+    }
+    Some(html)
+}
@@ -0,0 +1,304 @@
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use rocket::{Config, State};
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::documentos::{self, Documento, Documentos};
+use super::enlaces::ÍndiceEnlaces;
+use super::eventos::{Evento, Eventos};
+use super::id::Id;
+use super::integridad;
+use super::roles::{Administrador, Editor};
+use super::usuarios::Usuario;
+
+/*
+ * Papelera de documentos
+ */
+
+// Al borrar un documento, lo saco del árbol con todos sus descendientes y lo guardo en la
+// papelera, junto con el lugar que ocupaba. Desde ahí se puede restaurar, en su sitio o bajo
+// otro padre, hasta que pasan los días de "conservar" y se elimina definitivamente.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Eliminado {
+    // Id del documento borrado, que es la raíz del subárbol
+    pub id: Id,
+    pub padre: Id,
+    // Posición entre los hijos de su padre
+    pub posición: usize,
+    pub momento: String,
+    pub autor: String,
+    // El documento y sus descendientes, en el orden en que estaban en la lista
+    pub documentos: Vec<Documento>,
+}
+
+impl Eliminado {
+    fn resumen(&self) -> Value {
+        let título = self
+            .documentos
+            .iter()
+            .find(|d| d.id == self.id)
+            .map(|d| d.título.clone())
+            .unwrap_or_default();
+        json!({
+            "id": self.id,
+            "título": título,
+            "padre": self.padre,
+            "posición": self.posición,
+            "momento": self.momento,
+            "autor": self.autor,
+            "documentos": self.documentos.len(),
+        })
+    }
+}
+
+pub type Papelera = Mutex<Vec<Eliminado>>;
+
+// Días que se conserva cada documento en la papelera
+fn días_que_conservo() -> i64 {
+    Config::figment()
+        .extract_inner::<i64>("papelera.conservar")
+        .unwrap_or(30)
+}
+
+// Ids del subárbol que cuelga de un documento, incluido el propio documento
+pub fn subárbol(documentos: &[Documento], id: Id) -> Vec<Id> {
+    documentos
+        .iter()
+        .filter(|d| d.id == id || integridad::ancestros(documentos, d.id).contains(&id))
+        .map(|d| d.id)
+        .collect()
+}
+
+// Saca del árbol el subárbol de un documento, que no puede ser la raíz, y lo devuelve listo para
+// guardarlo en la papelera
+pub fn retira(documentos: &mut Vec<Documento>, id: Id, autor: &str) -> Eliminado {
+    let ids: HashSet<Id> = subárbol(documentos, id).into_iter().collect();
+    let padre = documentos.iter().find(|d| d.id == id).unwrap().padre;
+    let mut posición = 0;
+    if let Some(doc_padre) = documentos.iter_mut().find(|d| d.id == padre) {
+        posición = doc_padre
+            .hijos
+            .iter()
+            .position(|&h| h == id)
+            .unwrap_or(doc_padre.hijos.len());
+        doc_padre.hijos.retain(|&h| h != id);
+        doc_padre.revisión += 1;
+    }
+
+    let (retirados, quedan): (Vec<Documento>, Vec<Documento>) =
+        documentos.drain(..).partition(|d| ids.contains(&d.id));
+    *documentos = quedan;
+
+    Eliminado {
+        id,
+        padre,
+        posición,
+        momento: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        autor: autor.to_string(),
+        documentos: retirados,
+    }
+}
+
+// Elimina definitivamente lo que lleva en la papelera más de los días indicados. Devuelve
+// cuántos elementos ha eliminado.
+fn purga_caducados(papelera: &mut Vec<Eliminado>, días: i64) -> usize {
+    let límite = Utc::now() - Duration::days(días);
+    let antes = papelera.len();
+    papelera.retain(|e| match DateTime::parse_from_rfc3339(&e.momento) {
+        Ok(momento) => momento >= límite,
+        // Si no entiendo la fecha, prefiero conservarlo
+        Err(_) => true,
+    });
+    antes - papelera.len()
+}
+
+pub async fn guarda_copia_papelera(
+    almacén: &Almacenamiento,
+    papelera: &[Eliminado],
+) -> Result<(), Status> {
+    almacén.guarda_papelera(papelera).map_err(|e| {
+        println!("No he podido guardar la papelera: {}", e);
+        Status::InternalServerError
+    })
+}
+
+// Bloqueo la papelera, y de paso elimino lo que haya caducado
+async fn bloquea_y_purga<'a>(
+    papelera: &'a Papelera,
+    almacén: &Almacenamiento,
+) -> Result<rocket::tokio::sync::MutexGuard<'a, Vec<Eliminado>>, Status> {
+    let mut papelera = papelera.lock().await;
+    if purga_caducados(&mut papelera, días_que_conservo()) > 0 {
+        guarda_copia_papelera(almacén, &papelera).await?;
+    }
+    Ok(papelera)
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[get("/papelera", format = "json")]
+async fn lee_papelera(
+    papelera: &State<Papelera>,
+    almacén: &State<Almacenamiento>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    let papelera = bloquea_y_purga(papelera, almacén).await?;
+    let eliminados: Vec<Value> = papelera.iter().map(|e| e.resumen()).collect();
+
+    Ok(json!({ "estado": "ok", "papelera": eliminados }))
+}
+
+// Restaura un subárbol en su sitio, o bajo "padre" si se indica
+#[allow(clippy::too_many_arguments)]
+#[post("/papelera/<id>/restauración?<padre>")]
+async fn restaura_documento(
+    id: Id,
+    padre: Option<Id>,
+    lista: &State<Documentos>,
+    papelera: &State<Papelera>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut lista = documentos::bloquea_para_cambiar(lista, almacén).await?;
+    let mut papelera = bloquea_y_purga(papelera, almacén).await?;
+    let i = papelera
+        .iter()
+        .position(|e| e.id == id)
+        .ok_or(Status::NotFound)?;
+    let eliminado = papelera[i].clone();
+
+    // El padre debe seguir existiendo, y los ids no pueden estar ocupados, por ejemplo porque
+    // se haya restaurado una copia de seguridad que ya los incluía
+    let destino = padre.unwrap_or(eliminado.padre);
+    if !lista.iter().any(|d| d.id == destino) {
+        return Err(Status::Forbidden);
+    }
+    // Mientras estaba en la papelera, otro documento puede haber ocupado su slug
+    let raíz = eliminado.documentos.iter().find(|d| d.id == id);
+    if let Some(slug) = raíz.and_then(|d| d.slug.as_deref()) {
+        documentos::comprueba_slug(&lista, destino, slug)?;
+    }
+    if eliminado
+        .documentos
+        .iter()
+        .any(|r| lista.iter().any(|d| d.id == r.id))
+    {
+        return Err(Status::Conflict);
+    }
+
+    let mut nuevos = lista.clone();
+    for mut doc in eliminado.documentos.iter().cloned() {
+        if doc.id == id {
+            doc.padre = destino;
+        }
+        doc.revisión += 1;
+        nuevos.push(doc);
+    }
+    let doc_padre = nuevos.iter_mut().find(|d| d.id == destino).unwrap();
+    // En su sitio, vuelve a la posición que ocupaba; bajo otro padre, va al final
+    let posición = if destino == eliminado.padre {
+        eliminado.posición.min(doc_padre.hijos.len())
+    } else {
+        doc_padre.hijos.len()
+    };
+    doc_padre.hijos.insert(posición, id);
+    doc_padre.revisión += 1;
+
+    documentos::guarda_copia_documentos(almacén, índice, &nuevos).await?;
+    *lista = nuevos;
+    papelera.remove(i);
+    guarda_copia_papelera(almacén, &papelera).await?;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "restaura_documento",
+            Some(&usuario),
+            format!("documento/{}", id),
+            ip,
+        )
+        .después(json!({ "padre": destino, "documentos": eliminado.documentos.len() })),
+    );
+    let restaurado = lista.iter().find(|d| d.id == id).unwrap();
+    eventos.emite(Evento::nuevo("restaurado", restaurado, &lista, &usuario));
+
+    Ok(json!({ "estado": "ok", "id": id, "padre": destino }))
+}
+
+// Elimina definitivamente un elemento de la papelera, sin esperar a que caduque
+#[delete("/papelera/<id>")]
+async fn purga_documento(
+    id: Id,
+    papelera: &State<Papelera>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Status {
+    let mut papelera = match bloquea_y_purga(papelera, almacén).await {
+        Ok(papelera) => papelera,
+        Err(estado) => return estado,
+    };
+    let i = match papelera.iter().position(|e| e.id == id) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+
+    let mut nueva = papelera.clone();
+    let purgado = nueva.remove(i);
+    if let Err(estado) = guarda_copia_papelera(almacén, &nueva).await {
+        return estado;
+    }
+    *papelera = nueva;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "purga_documento",
+            Some(&usuario),
+            format!("documento/{}", id),
+            ip,
+        )
+        .antes(purgado.resumen()),
+    );
+
+    Status::Accepted
+}
+
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> Papelera {
+    let mut papelera = almacén
+        .carga_papelera()
+        .unwrap_or_else(|e| panic!("No he podido cargar la papelera: {}", e))
+        .unwrap_or_default();
+    let purgados = purga_caducados(&mut papelera, días_que_conservo());
+    if purgados > 0 {
+        almacén
+            .guarda_papelera(&papelera)
+            .unwrap_or_else(|e| panic!("No he podido guardar la papelera: {}", e));
+        println!(
+            "He eliminado {} documentos caducados de la papelera",
+            purgados
+        );
+    }
+    Mutex::new(papelera)
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![lee_papelera, restaura_documento, purga_documento]
+}
@@ -0,0 +1,241 @@
+use rand::thread_rng;
+use rand::Rng;
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use rocket::Config;
+use rocket::State;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, SystemTime};
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::correo::{self, Correo, Mensaje};
+use super::id::Id;
+use super::sesion::{self, SesionesActivas};
+use super::usuarios::{self, Usuarios};
+use super::webhooks::Webhooks;
+
+/*
+ * Recuperación de la clave
+ */
+
+// Tiempo durante el que se puede usar el símbolo que envío por correo
+const VIGENCIA_SÍMBOLO: u64 = 3600;
+
+// Cualquiera puede pedir que envíe un correo, así que limito cuántas solicitudes acepto de
+// cada dirección IP y cuántos correos envío a cada usuario en cada periodo
+const PERIODO_LÍMITE: u64 = 3600;
+const SOLICITUDES_POR_IP: usize = 10;
+const CORREOS_POR_USUARIO: usize = 3;
+
+pub struct Solicitud {
+    pub usuario: Id,
+    pub caducidad: SystemTime,
+}
+
+pub struct EstadoRecuperación {
+    // Guardo las solicitudes pendientes indexadas por el símbolo ofuscado, así no
+    // guardo en memoria ningún símbolo que se pueda usar directamente.
+    pendientes: HashMap<String, Solicitud>,
+    // Momentos de las últimas solicitudes de cada IP y de los últimos correos a cada usuario
+    por_ip: HashMap<IpAddr, Vec<SystemTime>>,
+    por_usuario: HashMap<Id, Vec<SystemTime>>,
+}
+
+pub type SolicitudesRecuperación = Mutex<EstadoRecuperación>;
+
+// Apunta una solicitud más en "momentos" si no supera el límite del periodo
+fn dentro_del_límite(momentos: &mut Vec<SystemTime>, límite: usize) -> bool {
+    let ahora = SystemTime::now();
+    let inicio = ahora
+        .checked_sub(Duration::from_secs(PERIODO_LÍMITE))
+        .unwrap_or(ahora);
+    momentos.retain(|m| *m > inicio);
+    if momentos.len() >= límite {
+        return false;
+    }
+    momentos.push(ahora);
+    true
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct PeticiónRecuperación {
+    // Nombre o correo del usuario
+    usuario: String,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct ConfirmaciónRecuperación {
+    símbolo: String,
+    clave: String,
+}
+
+fn crea_símbolo_recuperación() -> String {
+    let mut aleatorio = [0u8; 32];
+    thread_rng().fill(&mut aleatorio[..]);
+    // El símbolo viaja dentro de un enlace, así que uso la variante segura para URLs
+    base64::encode_config(aleatorio, base64::URL_SAFE_NO_PAD)
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[post("/recuperación", format = "json", data = "<petición>")]
+async fn solicita_recuperación(
+    petición: Json<PeticiónRecuperación>,
+    estado_solicitudes: &State<SolicitudesRecuperación>,
+    estado_usuarios: &State<Usuarios>,
+    correo: &State<Correo>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let respuesta = json!({
+        "mensaje": "Si el usuario existe, recibirá un correo con las instrucciones."
+    });
+
+    if let Some(ip) = ip {
+        let mut solicitudes = estado_solicitudes.lock().await;
+        let momentos = solicitudes.por_ip.entry(ip).or_default();
+        if !dentro_del_límite(momentos, SOLICITUDES_POR_IP) {
+            return Err(Status::TooManyRequests);
+        }
+    }
+
+    let lista = estado_usuarios.lock().await;
+    let usuario = lista.iter().find(|u| {
+        u.nombre == petición.usuario || (!u.correo.is_empty() && u.correo == petición.usuario)
+    });
+
+    // Respondo lo mismo tanto si el usuario existe como si no, para no desvelar quién está
+    // registrado. Por eso tampoco aviso cuando un usuario ha recibido demasiados correos, y envío
+    // el correo en segundo plano, para que la respuesta no tarde más cuando el usuario existe.
+    let usuario = match usuario {
+        Some(u) if !u.correo.is_empty() => u.clone(),
+        _ => return Ok(respuesta),
+    };
+    drop(lista);
+
+    let símbolo = crea_símbolo_recuperación();
+    let caducidad = SystemTime::now()
+        .checked_add(Duration::from_secs(VIGENCIA_SÍMBOLO))
+        .unwrap();
+
+    let mut solicitudes = estado_solicitudes.lock().await;
+    let momentos = solicitudes.por_usuario.entry(usuario.id).or_default();
+    if !dentro_del_límite(momentos, CORREOS_POR_USUARIO) {
+        return Ok(respuesta);
+    }
+    // Descarto las solicitudes caducadas y las anteriores de este mismo usuario
+    let ahora = SystemTime::now();
+    solicitudes
+        .pendientes
+        .retain(|_, s| s.caducidad > ahora && s.usuario != usuario.id);
+    solicitudes.pendientes.insert(
+        sesion::ofusca_clave(&símbolo),
+        Solicitud {
+            usuario: usuario.id,
+            caducidad,
+        },
+    );
+    drop(solicitudes);
+
+    let url_sitio: String = Config::figment()
+        .extract_inner::<String>("url_sitio")
+        .unwrap();
+    let mensaje = Mensaje {
+        destinatario: usuario.correo.clone(),
+        asunto: "Recuperación de la clave".to_string(),
+        cuerpo: format!(
+            "Hola, {}:\r\n\r\nHemos recibido una solicitud para cambiar tu clave. \
+             Si has sido tú, usa este enlace durante la próxima hora:\r\n\r\n\
+             {}/recupera?símbolo={}\r\n\r\nSi no has sido tú, ignora este mensaje.",
+            usuario.nombre, url_sitio, símbolo
+        ),
+    };
+    correo::envía_en_segundo_plano(correo, mensaje);
+
+    Ok(respuesta)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/recuperación/confirmación", format = "json", data = "<confirmación>")]
+async fn confirma_recuperación(
+    confirmación: Json<ConfirmaciónRecuperación>,
+    estado_solicitudes: &State<SolicitudesRecuperación>,
+    estado_sesiones: &State<SesionesActivas>,
+    estado_usuarios: &State<Usuarios>,
+    almacén: &State<Almacenamiento>,
+    webhooks: &State<Webhooks>,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let confirmación = confirmación.into_inner();
+
+    if confirmación.clave.is_empty() {
+        return Err(Status::BadRequest);
+    }
+
+    // El símbolo sólo se puede usar una vez, así que lo retiro en cuanto lo leo
+    let solicitud = estado_solicitudes
+        .lock()
+        .await
+        .pendientes
+        .remove(&sesion::ofusca_clave(&confirmación.símbolo))
+        .ok_or(Status::Unauthorized)?;
+
+    if solicitud.caducidad < SystemTime::now() {
+        return Err(Status::Unauthorized);
+    }
+
+    let mut lista = usuarios::bloquea_para_cambiar(estado_usuarios, almacén).await?;
+    let i = lista
+        .iter()
+        .position(|u| u.id == solicitud.usuario)
+        .ok_or(Status::Unauthorized)?;
+    let mut nuevos = lista.clone();
+    nuevos[i].clave = confirmación.clave;
+    nuevos[i].revisión += 1;
+    usuarios::guarda_copia_usuarios(almacén, &nuevos).await?;
+    *lista = nuevos;
+    let usuario = lista[i].clone();
+    // Suelto los usuarios antes de bloquear las sesiones: los guardas de acceso bloquean
+    // primero las sesiones y después los usuarios
+    drop(lista);
+
+    auditoría.registra(Entrada::nueva(
+        "recupera_clave",
+        Some(&usuario),
+        format!("usuario/{}", solicitud.usuario),
+        ip,
+    ));
+    webhooks.dispara(
+        "usuario.cambiado",
+        json!({ "id": usuario.id, "usuario": usuario.resumen(), "actor": usuario.nombre }),
+    );
+
+    // Cierro todas las sesiones abiertas del usuario
+    let mut sesiones = estado_sesiones.lock().await;
+    sesiones.retain(|_, s| s.usuario != solicitud.usuario);
+    sesion::guarda_copia_sesiones(almacén, &sesiones).await?;
+
+    Ok(json!({ "mensaje": "Clave cambiada." }))
+}
+
+pub fn prepara_estado_inicial() -> SolicitudesRecuperación {
+    Mutex::new(EstadoRecuperación {
+        pendientes: HashMap::new(),
+        por_ip: HashMap::new(),
+        por_usuario: HashMap::new(),
+    })
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![solicita_recuperación, confirma_recuperación]
+}
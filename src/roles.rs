@@ -31,7 +31,9 @@ impl<'r> FromRequest<'r> for Administrador {
         // Identifico la sesión guardada en la cookie privada
         let sesión_leída: String = cookie_sesión.value().to_string();
         // Leo el contenido de la sesión guardada en la cookie privada
-        let contenido_sesión = (*mutex_sesiones).get(&sesión_leída).unwrap();
+        let contenido_sesión = (*mutex_sesiones)
+            .get(&sesion::ofusca_clave(&sesión_leída))
+            .unwrap();
 
         // Accedo a la lista de usuarios
         let estado_usuarios = request.guard::<&State<Usuarios>>().await.unwrap();
@@ -84,7 +86,9 @@ impl<'r> FromRequest<'r> for Editor {
         // Identifico la sesión guardada en la cookie privada
         let sesión_leída: String = cookie_sesión.value().to_string();
         // Leo el contenido de la sesión guardada en la cookie privada
-        let contenido_sesión = (*mutex_sesiones).get(&sesión_leída).unwrap();
+        let contenido_sesión = (*mutex_sesiones)
+            .get(&sesion::ofusca_clave(&sesión_leída))
+            .unwrap();
 
         // Accedo a la lista de usuarios
         let estado_usuarios = request.guard::<&State<Usuarios>>().await.unwrap();
@@ -112,3 +116,47 @@ impl<'r> FromRequest<'r> for Editor {
         Some(Editor {}).or_forward(())
     }
 }
+
+// Quien hace la petición, con o sin sesión. A diferencia de Usuario, nunca falla: sirve para los
+// puntos de acceso abiertos a todos que muestran más o menos según los roles.
+pub struct Visitante {
+    pub usuario: Option<Usuario>,
+}
+
+impl Visitante {
+    pub fn tiene_rol(&self, rol: &str) -> bool {
+        self.usuario
+            .as_ref()
+            .is_some_and(|u| u.roles.iter().any(|r| r == rol))
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Visitante {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Visitante, Self::Error> {
+        let sesión_leída = match request.cookies().get_private("sesión") {
+            Some(cookie) => cookie.value().to_string(),
+            None => return request::Outcome::Success(Visitante { usuario: None }),
+        };
+
+        let id_usuario = match request.guard::<&State<sesion::SesionesActivas>>().await {
+            rocket::outcome::Outcome::Success(sesiones) => sesiones
+                .lock()
+                .await
+                .get(&sesion::ofusca_clave(&sesión_leída))
+                .filter(|s| s.caducidad > std::time::SystemTime::now())
+                .map(|s| s.usuario),
+            _ => None,
+        };
+        let usuario = match (id_usuario, request.guard::<&State<Usuarios>>().await) {
+            (Some(id), rocket::outcome::Outcome::Success(usuarios)) => {
+                usuarios.lock().await.iter().find(|u| u.id == id).cloned()
+            }
+            _ => None,
+        };
+
+        request::Outcome::Success(Visitante { usuario })
+    }
+}
@@ -15,8 +15,11 @@ use rocket::tokio::sync::Mutex;
 use rocket::State;
 
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::time::{Duration, SystemTime};
 
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
 use super::id::Id;
 use super::usuarios::{Usuario, Usuarios};
 
@@ -30,11 +33,15 @@ pub fn ofusca_clave(clave: &String) -> String {
     olla.result_str()
 }
 
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
 pub struct Sesión {
     pub usuario: Id,
     pub caducidad: std::time::SystemTime,
 }
 
+// Guardo las sesiones indexadas por el símbolo ofuscado, como las solicitudes de recuperación:
+// quien lea las sesiones guardadas no puede usarlas para entrar
 pub type SesionesActivas = Mutex<HashMap<String, Sesión>>;
 
 #[derive(Serialize, Deserialize)]
@@ -50,7 +57,12 @@ struct RespuestaJson {
     mensaje: String,
 }
 
-fn crea_sesión(usuario: Usuario) -> Sesión {
+// El resumen SHA3-512 de ofusca_clave son 128 cifras hexadecimales
+fn es_ofuscado(símbolo: &str) -> bool {
+    símbolo.len() == 128 && símbolo.bytes().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn crea_sesión(usuario: Usuario) -> Sesión {
     let ahora: std::time::SystemTime = SystemTime::now();
     let caducidad: std::time::SystemTime = ahora.checked_add(Duration::from_secs(3600)).unwrap();
     let sesión = Sesión {
@@ -60,7 +72,7 @@ fn crea_sesión(usuario: Usuario) -> Sesión {
     return sesión;
 }
 
-fn crea_símbolo_sesión() -> String {
+pub fn crea_símbolo_sesión() -> String {
     let mut aleatorio = [0u8; 64];
     thread_rng().try_fill(&mut aleatorio[..]);
     format!("{}", base64::encode(&aleatorio))
@@ -82,40 +94,91 @@ fn secreto_no_accesible() -> Status {
     Status::Unauthorized
 }
 
+pub async fn guarda_copia_sesiones(
+    almacén: &Almacenamiento,
+    sesiones: &HashMap<String, Sesión>,
+) -> Result<(), Status> {
+    almacén.guarda_sesiones(sesiones).map_err(|e| {
+        println!("No he podido guardar las sesiones: {}", e);
+        Status::InternalServerError
+    })
+}
+
 #[post("/sesión", data = "<acceso>")]
 async fn gestiona_acceso(
     caja: &CookieJar<'_>,
     acceso: Json<Acceso>,
     estado_sesiones: &State<SesionesActivas>,
     estado_usuarios: &State<Usuarios>,
+    almacén: &State<Almacenamiento>,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
 ) -> Result<Value, Status> {
     let mut mutex_sesiones = estado_sesiones.lock().await;
 
     // Accedo a la lista de usuarios
     let mut mutex_usuarios = estado_usuarios.lock().await;
     // Busco el usuario con el identificador que se corresponda con el de la sesión activa
-    let i = mutex_usuarios
+    let i = match mutex_usuarios
         .iter()
         .position(|u| u.nombre == acceso.usuario)
-        .unwrap();
+    {
+        Some(i) => i,
+        None => {
+            auditoría.registra(Entrada::nueva(
+                "acceso_fallido",
+                None,
+                format!("usuario/{}", acceso.usuario),
+                ip,
+            ));
+            return Err(Status::Unauthorized);
+        }
+    };
     let usuario: Usuario = mutex_usuarios[i].clone();
 
     if acceso.clave == usuario.clave {
+        auditoría.registra(Entrada::nueva(
+            "acceso",
+            Some(&usuario),
+            format!("usuario/{}", usuario.id),
+            ip,
+        ));
+
         let símbolo_sesión: String = crea_símbolo_sesión();
         let sesión: Sesión = crea_sesión(usuario);
-        (*mutex_sesiones).insert(símbolo_sesión.clone(), sesión);
+        (*mutex_sesiones).insert(ofusca_clave(&símbolo_sesión), sesión);
+        guarda_copia_sesiones(almacén, &mutex_sesiones).await?;
         caja.add_private(Cookie::new("sesión", símbolo_sesión));
 
         Ok(json!(RespuestaJson {
             mensaje: "Acceso concedido.".to_string()
         }))
     } else {
+        auditoría.registra(Entrada::nueva(
+            "acceso_fallido",
+            None,
+            format!("usuario/{}", usuario.id),
+            ip,
+        ));
         Err(Status::Unauthorized)
     }
 }
 
 #[delete("/sesión")]
-fn cierra_sesión(caja: &CookieJar<'_>) -> Value {
+async fn cierra_sesión(
+    caja: &CookieJar<'_>,
+    estado_sesiones: &State<SesionesActivas>,
+    almacén: &State<Almacenamiento>,
+) -> Value {
+    // Olvido la sesión, para que la cookie no sirva aunque alguien la haya guardado
+    if let Some(cookie_sesión) = caja.get_private("sesión") {
+        let mut mutex_sesiones = estado_sesiones.lock().await;
+        let símbolo = cookie_sesión.value().to_string();
+        if mutex_sesiones.remove(&ofusca_clave(&símbolo)).is_some() {
+            // Aunque no pueda guardar el cambio, cierro la sesión en memoria y en el navegador
+            let _ = guarda_copia_sesiones(almacén, &mutex_sesiones).await;
+        }
+    }
     caja.remove_private(Cookie::named("id_usuario"));
     caja.remove_private(Cookie::named("sesión"));
     json!(RespuestaJson {
@@ -123,9 +186,20 @@ fn cierra_sesión(caja: &CookieJar<'_>) -> Value {
     })
 }
 
-pub fn prepara_estado_inicial() -> SesionesActivas {
-    let dic_vacío = HashMap::new();
-    Mutex::new(dic_vacío)
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> SesionesActivas {
+    // Recupero las sesiones guardadas que todavía no han caducado
+    let ahora = SystemTime::now();
+    // Si no puedo leerlas, empiezo sin sesiones: sólo obliga a los usuarios a volver a entrar
+    let mut sesiones = almacén
+        .carga_sesiones()
+        .unwrap_or_else(|e| {
+            println!("No he podido cargar las sesiones: {}", e);
+            None
+        })
+        .unwrap_or_default();
+    // Las sesiones guardadas antes de ofuscar los símbolos los tienen en claro: las cierro
+    sesiones.retain(|símbolo, s| s.caducidad > ahora && es_ofuscado(símbolo));
+    Mutex::new(sesiones)
 }
 
 pub fn rutas() -> Vec<rocket::Route> {
@@ -0,0 +1,44 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/*
+ * Carpetas temporales para las pruebas
+ */
+
+static SIGUIENTE: AtomicUsize = AtomicUsize::new(0);
+
+// Carpeta vacía dentro de la carpeta temporal del sistema, que se borra al terminar aunque la
+// prueba falle. El nombre lleva el id del proceso y un número de orden, así que no chocan las
+// pruebas que se ejecutan a la vez ni dos ejecuciones seguidas.
+pub struct Carpeta(PathBuf);
+
+impl Carpeta {
+    pub fn nueva(nombre: &str) -> Carpeta {
+        let ruta = std::env::temp_dir().join(format!(
+            "{}-{}-{}",
+            nombre,
+            std::process::id(),
+            SIGUIENTE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&ruta);
+        std::fs::create_dir_all(&ruta).unwrap();
+        Carpeta(ruta)
+    }
+
+    pub fn ruta(&self) -> &Path {
+        &self.0
+    }
+
+    // Escribe un archivo dentro de la carpeta, creando las subcarpetas que falten
+    pub fn escribe(&self, archivo: &str, texto: &str) {
+        let ruta = self.0.join(archivo);
+        std::fs::create_dir_all(ruta.parent().unwrap()).unwrap();
+        std::fs::write(ruta, texto).unwrap();
+    }
+}
+
+impl Drop for Carpeta {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,377 @@
+use rocket::http::{Header, Status};
+use rocket::request::{self, FromRequest, Request};
+use rocket::response::{self, Responder, Response};
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::{Config, State};
+
+use std::net::IpAddr;
+
+use super::almacen::{self, Almacenamiento};
+use super::auditoria::{Auditoría, Entrada};
+use super::bloqueos::{self, Bloqueos};
+use super::condiciones::SiCoincide;
+use super::documentos::{self, Documento, Documentos};
+use super::enlaces::ÍndiceEnlaces;
+use super::eventos::{Evento, Eventos};
+use super::id::Id;
+use super::roles::Editor;
+use super::usuarios::Usuario;
+
+/*
+ * Traducciones de los documentos
+ */
+
+// Los documentos se escriben en el idioma de origen (español), y cada uno puede llevar la
+// traducción de su título y su contenido a otros idiomas. Cada traducción recuerda la huella del
+// original que se tradujo: si el original cambia, la traducción queda desactualizada hasta que
+// alguien la revise.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Traducción {
+    pub título: String,
+    pub contenido: String,
+    // Revisión del documento original que se tradujo
+    pub revisión: u64,
+    // Huella del título y el contenido originales que se tradujeron
+    pub huella: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigIdiomas {
+    pub origen: String,
+    // Idiomas a los que se traduce la documentación
+    pub traducciones: Vec<String>,
+}
+
+pub fn lee_configuración() -> ConfigIdiomas {
+    Config::figment()
+        .extract_inner::<ConfigIdiomas>("idiomas")
+        .unwrap_or(ConfigIdiomas {
+            origen: "es".to_string(),
+            traducciones: vec![],
+        })
+}
+
+// Huella del texto original de un documento. La revisión no sirve para esto, porque también
+// cambia cuando cambian sus hijos o sus traducciones.
+pub fn huella(documento: &Documento) -> String {
+    almacen::calcula_huella(format!("{}\n{}", documento.título, documento.contenido).as_bytes())
+}
+
+// "actualizada", "desactualizada" o "pendiente", si todavía no se ha traducido
+pub fn estado(documento: &Documento, idioma: &str) -> &'static str {
+    match documento.traducciones.get(idioma) {
+        None => "pendiente",
+        Some(traducción) if traducción.huella == huella(documento) => "actualizada",
+        Some(_) => "desactualizada",
+    }
+}
+
+// Idiomas de la cabecera Accept-Language, del preferido al menos preferido. Sólo me quedo con
+// el idioma principal: "en-US" cuenta como "en".
+pub struct IdiomasAceptados(Vec<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdiomasAceptados {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(
+        request: &'r Request<'_>,
+    ) -> request::Outcome<IdiomasAceptados, Self::Error> {
+        let cabecera = request.headers().get_one("Accept-Language").unwrap_or("");
+        let mut idiomas: Vec<(String, f32)> = cabecera
+            .split(',')
+            .filter_map(|parte| {
+                let mut trozos = parte.split(';');
+                let idioma = trozos.next()?.trim();
+                let idioma = idioma.split('-').next()?.to_lowercase();
+                if idioma.is_empty() || idioma == "*" {
+                    return None;
+                }
+                let peso = trozos
+                    .filter_map(|t| t.trim().strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((idioma, peso))
+            })
+            .filter(|(_, peso)| *peso > 0.0)
+            .collect();
+        // La ordenación es estable: con el mismo peso, respeto el orden de la cabecera
+        idiomas.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        request::Outcome::Success(IdiomasAceptados(
+            idiomas.into_iter().map(|(idioma, _)| idioma).collect(),
+        ))
+    }
+}
+
+// Elige el idioma en que sirvo un documento: el pedido con ?idioma=, si existe, o el primero de
+// Accept-Language que tenga. Si no tengo ninguno, sirvo el original.
+pub fn elige(documento: &Documento, pedido: Option<&str>, aceptados: &IdiomasAceptados) -> String {
+    let origen = lee_configuración().origen;
+    pedido
+        .into_iter()
+        .chain(aceptados.0.iter().map(|i| i.as_str()))
+        .find(|idioma| *idioma == origen || documento.traducciones.contains_key(*idioma))
+        .unwrap_or(&origen)
+        .to_string()
+}
+
+// El documento tal como lo leen en un idioma. No incluyo las demás traducciones.
+pub fn en_idioma(documento: &Documento, idioma: &str) -> Value {
+    let mut valor = json!(documento);
+    if let Some(objeto) = valor.as_object_mut() {
+        objeto.remove("traducciones");
+    }
+    valor["idioma"] = json!(idioma);
+    if let Some(traducción) = documento.traducciones.get(idioma) {
+        valor["título"] = json!(traducción.título);
+        valor["contenido"] = json!(traducción.contenido);
+        // Aviso al lector si el original ha cambiado desde que se tradujo
+        valor["traducción"] = json!(estado(documento, idioma));
+    }
+    valor
+}
+
+// Respuesta en un idioma: lo indico en Content-Language, y aviso a las cachés de que depende de
+// Accept-Language
+pub struct EnIdioma<R> {
+    pub idioma: String,
+    pub respuesta: R,
+}
+
+impl<'r, R: Responder<'r, 'static>> Responder<'r, 'static> for EnIdioma<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(self.respuesta.respond_to(request)?)
+            .header(Header::new("Content-Language", self.idioma))
+            .header(Header::new("Vary", "Accept-Language"))
+            .ok()
+    }
+}
+
+// Idiomas de los que informo: los configurados, y cualquier otro que ya tenga el documento
+fn idiomas_de(documento: &Documento, config: &ConfigIdiomas) -> Vec<String> {
+    let mut idiomas = config.traducciones.clone();
+    for idioma in documento.traducciones.keys() {
+        if !idiomas.contains(idioma) {
+            idiomas.push(idioma.clone());
+        }
+    }
+    idiomas
+}
+
+fn resumen(documento: &Documento, idioma: &str) -> Value {
+    let traducción = documento.traducciones.get(idioma);
+    json!({
+        "idioma": idioma,
+        "estado": estado(documento, idioma),
+        "título": traducción.map(|t| t.título.clone()),
+        "revisión": traducción.map(|t| t.revisión),
+    })
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NuevaTraducción {
+    título: String,
+    contenido: String,
+}
+
+// Estado de las traducciones de un documento
+#[get("/documento/<id>/traducciones", format = "json")]
+async fn lee_traducciones(
+    id: Id,
+    lista: &State<Documentos>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Result<Value, Status> {
+    let lista = lista.lock().await;
+    let doc = lista.iter().find(|d| d.id == id).ok_or(Status::NotFound)?;
+    let config = lee_configuración();
+    let traducciones: Vec<Value> = idiomas_de(doc, &config)
+        .iter()
+        .map(|idioma| resumen(doc, idioma))
+        .collect();
+
+    Ok(json!({
+        "estado": "ok",
+        "origen": config.origen,
+        "revisión": doc.revisión,
+        "traducciones": traducciones
+    }))
+}
+
+// Traducciones pendientes o desactualizadas de todos los documentos, o sólo las de ?idioma=
+#[get("/traducciones?<idioma>", format = "json")]
+async fn lee_traducciones_pendientes(
+    idioma: Option<&str>,
+    lista: &State<Documentos>,
+    _usuario: Usuario,
+    _editor: Editor,
+) -> Value {
+    let lista = lista.lock().await;
+    let config = lee_configuración();
+    let mut pendientes: Vec<Value> = vec![];
+    // La raíz no tiene texto que traducir
+    for doc in lista.iter().filter(|d| d.id != 0) {
+        for i in idiomas_de(doc, &config) {
+            if idioma.is_none_or(|idioma| idioma == i) && estado(doc, &i) != "actualizada" {
+                let mut pendiente = resumen(doc, &i);
+                pendiente["documento"] = json!(doc.id);
+                pendiente["original"] = json!(doc.título);
+                pendientes.push(pendiente);
+            }
+        }
+    }
+
+    json!({ "estado": "ok", "traducciones": pendientes })
+}
+
+// Crea o sustituye la traducción de un documento, que queda al día con el original actual
+#[allow(clippy::too_many_arguments)]
+#[put(
+    "/documento/<id>/traducción/<idioma>?<forzar>",
+    format = "json",
+    data = "<traducción>"
+)]
+async fn traduce_documento(
+    id: Id,
+    idioma: &str,
+    forzar: Option<bool>,
+    traducción: Json<NuevaTraducción>,
+    lista: &State<Documentos>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    bloqueos: &State<Bloqueos>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
+) -> Result<Value, Status> {
+    let config = lee_configuración();
+    if !config.traducciones.iter().any(|i| i == idioma) {
+        return Err(Status::BadRequest);
+    }
+    let mut lista = documentos::bloquea_para_cambiar(lista, almacén).await?;
+    let i = lista
+        .iter()
+        .position(|d| d.id == id)
+        .ok_or(Status::NotFound)?;
+    if id == 0 {
+        return Err(Status::Forbidden);
+    }
+    // Traducir cambia la revisión del documento, así que respeto las mismas condiciones que al
+    // cambiarlo
+    si_coincide.comprueba(lista[i].revisión)?;
+    bloqueos::comprueba(bloqueos, id, &usuario, forzar.unwrap_or(false)).await?;
+
+    let traducción = traducción.into_inner();
+    let mut nuevos = lista.clone();
+    let doc = &mut nuevos[i];
+    let antes = doc
+        .traducciones
+        .contains_key(idioma)
+        .then(|| resumen(doc, idioma));
+    let nueva = Traducción {
+        título: traducción.título,
+        contenido: traducción.contenido,
+        revisión: doc.revisión,
+        huella: huella(doc),
+    };
+    doc.traducciones.insert(idioma.to_string(), nueva);
+    doc.revisión += 1;
+    let después = resumen(doc, idioma);
+
+    documentos::guarda_copia_documentos(almacén, índice, &nuevos).await?;
+    *lista = nuevos;
+
+    let mut entrada = Entrada::nueva(
+        "traduce_documento",
+        Some(&usuario),
+        format!("documento/{}/traducción/{}", id, idioma),
+        ip,
+    )
+    .después(después.clone());
+    if let Some(antes) = antes {
+        entrada = entrada.antes(antes);
+    }
+    auditoría.registra(entrada);
+    eventos.emite(Evento::nuevo("cambiado", &lista[i], &lista, &usuario));
+
+    Ok(json!({ "estado": "ok", "traducción": después }))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[delete("/documento/<id>/traducción/<idioma>?<forzar>")]
+async fn borra_traducción(
+    id: Id,
+    idioma: &str,
+    forzar: Option<bool>,
+    lista: &State<Documentos>,
+    almacén: &State<Almacenamiento>,
+    índice: &State<ÍndiceEnlaces>,
+    bloqueos: &State<Bloqueos>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
+) -> Status {
+    let mut lista = match documentos::bloquea_para_cambiar(lista, almacén).await {
+        Ok(lista) => lista,
+        Err(estado) => return estado,
+    };
+    let i = match lista.iter().position(|d| d.id == id) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+    if !lista[i].traducciones.contains_key(idioma) {
+        return Status::NotFound;
+    }
+    if let Err(estado) = si_coincide.comprueba(lista[i].revisión) {
+        return estado;
+    }
+    if let Err(estado) = bloqueos::comprueba(bloqueos, id, &usuario, forzar.unwrap_or(false)).await
+    {
+        return estado;
+    }
+
+    let mut nuevos = lista.clone();
+    let antes = resumen(&nuevos[i], idioma);
+    nuevos[i].traducciones.remove(idioma);
+    nuevos[i].revisión += 1;
+    if let Err(estado) = documentos::guarda_copia_documentos(almacén, índice, &nuevos).await {
+        return estado;
+    }
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "borra_traducción",
+            Some(&usuario),
+            format!("documento/{}/traducción/{}", id, idioma),
+            ip,
+        )
+        .antes(antes),
+    );
+    eventos.emite(Evento::nuevo("cambiado", &lista[i], &lista, &usuario));
+
+    Status::Accepted
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![
+        lee_traducciones,
+        lee_traducciones_pendientes,
+        traduce_documento,
+        borra_traducción
+    ]
+}
@@ -3,13 +3,20 @@ use rocket::outcome::IntoOutcome;
 use rocket::request::{self, FromRequest, Request};
 use rocket::serde::json::{json, Json, Value};
 use rocket::serde::{Deserialize, Serialize};
-use rocket::tokio::sync::Mutex;
+use rocket::tokio::sync::{Mutex, MutexGuard};
 use rocket::Config;
 use rocket::State;
 
+use std::net::IpAddr;
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::condiciones::{Etiquetada, Revisado, SiCoincide, SiNoCoincide};
+use super::contadores::Contadores;
 use super::id::Id;
 use super::roles::Administrador;
 use super::sesion;
+use super::webhooks::Webhooks;
 
 /**
  * Usuarios
@@ -24,6 +31,15 @@ pub struct Usuario {
     pub nombre: String,
     pub clave: String,
     pub roles: Vec<String>,
+    // Dirección a la que envío los correos, como el de recuperación de la clave
+    #[serde(default)]
+    pub correo: String,
+    // Identidad del usuario en el proveedor externo, si entra a través de OpenID Connect
+    #[serde(default)]
+    pub sujeto_oidc: Option<String>,
+    // Aumenta con cada cambio; lo envío como ETag
+    #[serde(default)]
+    pub revisión: u64,
 }
 
 #[rocket::async_trait]
@@ -42,7 +58,9 @@ impl<'r> FromRequest<'r> for Usuario {
         // Identifico la sesión guardada en la cookie privada
         let sesión_leída: String = cookie_sesión.value().to_string();
         // Leo el contenido de la sesión guardada en la cookie privada
-        let contenido_sesión = (*mutex_sesiones).get(&sesión_leída).unwrap();
+        let contenido_sesión = (*mutex_sesiones)
+            .get(&sesion::ofusca_clave(&sesión_leída))
+            .unwrap();
 
         // Accedo a la lista de usuarios
         let estado_usuarios = request.guard::<&State<Usuarios>>().await.unwrap();
@@ -63,24 +81,40 @@ impl<'r> FromRequest<'r> for Usuario {
     }
 }
 
-// Inicio el contador en 1, porque el nodo 0 ya debería existir
-// El nodo 0 es el administrador
-static mut CONTADOR_IDS: Id = 1;
+// Guardaré los usuarios en este vector, respaldado por el almacén elegido en la
+// configuración: archivos JSON o una base de datos SQLite.
+pub type Usuarios = Mutex<Vec<Usuario>>;
 
-unsafe fn lee_nuevo_id() -> Id {
-    let id: Id = CONTADOR_IDS;
-    CONTADOR_IDS = CONTADOR_IDS + 1;
-    return id;
+// Bloqueo la lista para cambiarla, salvo que alguien haya cambiado los usuarios guardados fuera
+// del servidor y todavía no los haya recargado: en ese caso no cambio nada y respondo con un 409
+pub async fn bloquea_para_cambiar<'a>(
+    lista: &'a Usuarios,
+    almacén: &Almacenamiento,
+) -> Result<MutexGuard<'a, Vec<Usuario>>, Status> {
+    let lista = lista.lock().await;
+    if almacén.cambiado_fuera("usuarios").unwrap_or(false) {
+        println!("Los usuarios guardados han cambiado fuera del servidor; no los cambio");
+        return Err(Status::Conflict);
+    }
+    Ok(lista)
 }
 
-// Guardaré los usuarios en este vector, respaldado por un archivo en el disco duro:
-// "usuarios.json", de este modo no preciso usar una BBDD.
-pub type Usuarios = Mutex<Vec<Usuario>>;
-
-async fn guarda_copia_usuarios(usuarios: String) {
+pub async fn guarda_copia_usuarios(
+    almacén: &Almacenamiento,
+    usuarios: &[Usuario],
+) -> Result<(), Status> {
     println!("¡Guardando usuarios!");
-    println!("{}", usuarios);
-    std::fs::write("usuarios.json", usuarios).unwrap();
+    if almacén.cambiado_fuera("usuarios").unwrap_or(false) {
+        println!("Los usuarios guardados han cambiado fuera del servidor; no los sobrescribo");
+        return Err(Status::Conflict);
+    }
+    almacén
+        .guarda_usuarios(usuarios)
+        .and_then(|_| almacén.anota_huella("usuarios"))
+        .map_err(|e| {
+            println!("No he podido guardar los usuarios: {}", e);
+            Status::InternalServerError
+        })
 }
 
 // Estructuras con la lista de todos los usuarios.
@@ -99,10 +133,48 @@ impl Clone for Usuario {
             nombre: self.nombre.clone(),
             clave: self.clave.clone(),
             roles: self.roles.clone(),
+            correo: self.correo.clone(),
+            sujeto_oidc: self.sujeto_oidc.clone(),
+            revisión: self.revisión,
         }
     }
 }
 
+impl Revisado for Usuario {
+    fn id(&self) -> Id {
+        self.id
+    }
+    fn revisión(&self) -> u64 {
+        self.revisión
+    }
+    fn cambia_revisión(&mut self, revisión: u64) {
+        self.revisión = revisión;
+    }
+}
+
+impl Usuario {
+    // Resumen del usuario para el registro de auditoría, sin la clave
+    pub fn resumen(&self) -> Value {
+        json!({
+            "nombre": self.nombre,
+            "roles": self.roles,
+            "correo": self.correo
+        })
+    }
+
+    // Lo que ve un usuario de sí mismo: todo salvo la clave
+    pub fn sin_clave(&self) -> Value {
+        json!({
+            "id": self.id,
+            "nombre": self.nombre,
+            "roles": self.roles,
+            "correo": self.correo,
+            "sujeto_oidc": self.sujeto_oidc,
+            "revisión": self.revisión
+        })
+    }
+}
+
 // Puntos de entrada de la api de usuarios:
 
 #[get("/usuarios", format = "json")]
@@ -116,29 +188,49 @@ async fn lee_usuarios(
     json!(*lista)
 }
 
+#[allow(clippy::too_many_arguments)]
 #[post("/usuario", format = "json", data = "<usuario>")]
 async fn crea_usuario(
     usuario: Json<Usuario>,
     lista: &State<Usuarios>,
-    _usuario: Usuario,
+    almacén: &State<Almacenamiento>,
+    contadores: &State<Contadores>,
+    webhooks: &State<Webhooks>,
+    actor: Usuario,
     _administrador: Administrador,
-) -> Value {
-    let mut lista = lista.lock().await;
-    let identificador: Id;
-
-    unsafe {
-        identificador = lee_nuevo_id();
-    }
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut lista = bloquea_para_cambiar(lista, almacén).await?;
+    // El nodo 0 es el administrador
+    let identificador: Id = contadores.nuevo_id(almacén, "usuarios")?;
 
     let mut usu = usuario.into_inner();
     usu.id = identificador;
-
-    lista.push(usu);
-
-    let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-    guarda_copia_usuarios(j).await;
-
-    json!({ "estado": "ok", "id": Some(identificador) })
+    usu.revisión = 1;
+
+    let resumen = usu.resumen();
+    let mut nuevos = lista.clone();
+    nuevos.push(usu);
+
+    guarda_copia_usuarios(almacén, &nuevos).await?;
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "crea_usuario",
+            Some(&actor),
+            format!("usuario/{}", identificador),
+            ip,
+        )
+        .después(resumen.clone()),
+    );
+    webhooks.dispara(
+        "usuario.creado",
+        json!({ "id": identificador, "usuario": resumen, "actor": actor.nombre }),
+    );
+
+    Ok(json!({ "estado": "ok", "id": Some(identificador) }))
 }
 
 #[get("/usuario/<id>", format = "json")]
@@ -147,74 +239,135 @@ async fn lee_cualquier_usuario(
     lista: &State<Usuarios>,
     _usuario: Usuario,
     _administrador: Administrador,
-) -> Option<Json<Usuario>> {
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<Json<Usuario>>, Status> {
     let lista = lista.lock().await;
-    let i = lista.iter().position(|u| u.id == id).unwrap();
-    let usu: Usuario = lista[i].clone();
-    Some(Json(Usuario {
-        id: usu.id,
-        nombre: usu.nombre.clone(),
-        clave: usu.clave.clone(),
-        roles: usu.roles.clone(),
-    }))
+    let usu = lista.iter().find(|u| u.id == id).ok_or(Status::NotFound)?;
+    Ok(Etiquetada::nueva(
+        usu.revisión,
+        &si_no_coincide,
+        Json(usu.clone()),
+    ))
 }
 
 #[get("/usuario/<id>", format = "json", rank = 2)]
-async fn lee_usuario(id: Id, lista: &State<Usuarios>, _usuario: Usuario) -> Option<Json<Usuario>> {
-    let lista = lista.lock().await;
-    let i = lista.iter().position(|u| u.id == id).unwrap();
-    let usu: Usuario = lista[i].clone();
-
-    if (usu.id == id) {
-        // Solo acepto petición si el usuario está pidiendo su propia información
-        Some(Json(Usuario {
-            id: usu.id,
-            nombre: usu.nombre.clone(),
-            clave: usu.clave.clone(),
-            roles: usu.roles.clone(),
-        }))
-    } else {
-        None
+async fn lee_usuario(
+    id: Id,
+    lista: &State<Usuarios>,
+    usuario: Usuario,
+    si_no_coincide: SiNoCoincide,
+) -> Result<Etiquetada<Value>, Status> {
+    // Los administradores leen cualquier usuario en lee_cualquier_usuario. El resto sólo puede
+    // pedir su propia información.
+    if usuario.id != id {
+        return Err(Status::Unauthorized);
     }
+    let lista = lista.lock().await;
+    let usu = lista.iter().find(|u| u.id == id).ok_or(Status::NotFound)?;
+
+    Ok(Etiquetada::nueva(
+        usu.revisión,
+        &si_no_coincide,
+        usu.sin_clave(),
+    ))
 }
 
+#[allow(clippy::too_many_arguments)]
 #[patch("/usuario/<id>", format = "json", data = "<usuario>")]
 async fn cambia_usuario(
     id: Id,
     usuario: Json<Usuario>,
     lista: &State<Usuarios>,
-    _usuario: Usuario,
+    almacén: &State<Almacenamiento>,
+    webhooks: &State<Webhooks>,
+    actor: Usuario,
     _administrador: Administrador,
-) -> Option<Json<Usuario>> {
-    let mut lista = lista.lock().await;
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
+) -> Result<Etiquetada<Json<Usuario>>, Status> {
+    let mut lista = bloquea_para_cambiar(lista, almacén).await?;
     let usu = usuario.into_inner();
-    let i = lista.iter().position(|d| d.id == id).unwrap();
-    (*lista)[i].nombre = usu.nombre;
-    (*lista)[i].clave = usu.clave;
+    let i = lista
+        .iter()
+        .position(|d| d.id == id)
+        .ok_or(Status::NotFound)?;
+    si_coincide.comprueba((*lista)[i].revisión)?;
+    let antes = (*lista)[i].resumen();
+    let mut nuevos = lista.clone();
+    nuevos[i].nombre = usu.nombre;
+    nuevos[i].clave = usu.clave;
+    nuevos[i].correo = usu.correo;
+    nuevos[i].revisión += 1;
     //No modifico la lista de roles
-    //(*lista)[i].roles = usu.roles;
-
-    let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-    guarda_copia_usuarios(j).await;
-
-    return Some(Json((*lista)[i].clone()));
+    //nuevos[i].roles = usu.roles;
+
+    guarda_copia_usuarios(almacén, &nuevos).await?;
+    *lista = nuevos;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "cambia_usuario",
+            Some(&actor),
+            format!("usuario/{}", id),
+            ip,
+        )
+        .antes(antes)
+        .después((*lista)[i].resumen()),
+    );
+    webhooks.dispara(
+        "usuario.cambiado",
+        json!({ "id": id, "usuario": (*lista)[i].resumen(), "actor": actor.nombre }),
+    );
+
+    return Ok(Etiquetada::completa(
+        (*lista)[i].revisión,
+        Json((*lista)[i].clone()),
+    ));
 }
 
+#[allow(clippy::too_many_arguments)]
 #[delete("/usuario/<id>")]
 async fn borra_usuario(
     id: Id,
     lista: &State<Usuarios>,
-    _usuario: Usuario,
+    almacén: &State<Almacenamiento>,
+    webhooks: &State<Webhooks>,
+    actor: Usuario,
     _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
 ) -> Status {
-    let mut lista = lista.lock().await;
-    let i = lista.iter().position(|u| u.id == id).unwrap();
+    let mut lista = match bloquea_para_cambiar(lista, almacén).await {
+        Ok(lista) => lista,
+        Err(estado) => return estado,
+    };
+    let i = match lista.iter().position(|u| u.id == id) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+    if let Err(estado) = si_coincide.comprueba(lista[i].revisión) {
+        return estado;
+    }
 
     if (i != 0) {
-        lista.remove(i);
+        let mut nuevos = lista.clone();
+        let usu = nuevos.remove(i);
+
+        if let Err(estado) = guarda_copia_usuarios(almacén, &nuevos).await {
+            return estado;
+        }
+        *lista = nuevos;
 
-        let j: String = serde_json::to_string_pretty(&(*lista)).unwrap();
-        guarda_copia_usuarios(j).await;
+        auditoría.registra(
+            Entrada::nueva("borra_usuario", Some(&actor), format!("usuario/{}", id), ip)
+                .antes(usu.resumen()),
+        );
+        webhooks.dispara(
+            "usuario.borrado",
+            json!({ "id": id, "usuario": usu.resumen(), "actor": actor.nombre }),
+        );
 
         return Status::Accepted;
     } else {
@@ -229,7 +382,7 @@ pub struct ConfigAdmin {
     clave: String,
 }
 
-pub fn prepara_estado_inicial() -> Usuarios {
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> Usuarios {
     let config_admin: ConfigAdmin = Config::figment().extract::<ConfigAdmin>().unwrap();
     // Usuario raíz, nodo 0
     let usu_raíz: Usuario = Usuario {
@@ -238,45 +391,21 @@ pub fn prepara_estado_inicial() -> Usuarios {
         nombre: "Administrador".to_string(),
         clave: "1234".to_string(),
         roles: vec!["Administrador".to_string(), "Editor".to_string()],
+        correo: String::new(),
+        sujeto_oidc: None,
+        revisión: 1,
     };
 
     // Intento cargar usuarios previos
-    let archivo = std::fs::read_to_string("usuarios.json");
-
-    let usuarios: Usuarios = match archivo {
-        Ok(contenido) => {
-            // Si he podido leer el archivo, intento procesarlo como JSON
-            let v: Vec<Usuario> = serde_json::from_str::<Vec<Usuario>>(&contenido).unwrap();
-            // Si me ha dejado procesarlo como JSON, intento encontrar el ID más grande
-            let max_id_usu = v.iter().max_by_key(|usu| usu.id);
-            match max_id_usu {
-                // Si encuentra el ID más grande...
-                Some(usu) => {
-                    // establezco el contador de IDs...
-                    unsafe {
-                        CONTADOR_IDS = usu.id + 1;
-                    }
-                    // y devuelvo el vector de usuarios
-                    Mutex::new(v)
-                }
-                None => {
-                    // El vector estaba vacío o no era válido...
-                    unsafe {
-                        // inicio el contador de IDs...
-                        CONTADOR_IDS = 1;
-                    }
-                    // y cargo el nodo raíz
-                    Mutex::new(vec![usu_raíz])
-                }
-            }
-        }
-        Err(_e) => {
-            // Si hay error al abrir los usuarios, creo un nodo 0 inicial y establezco el contador de IDs a 0
-            unsafe {
-                CONTADOR_IDS = 1;
-            }
-            Mutex::new(vec![usu_raíz])
-        }
+    let guardados = almacén
+        .carga_usuarios()
+        .unwrap_or_else(|e| panic!("No he podido cargar los usuarios: {}", e));
+    almacén.anota_huella("usuarios").unwrap();
+
+    // Si no hay usuarios guardados, o la lista está vacía, creo el administrador inicial
+    let usuarios: Usuarios = match guardados {
+        Some(v) if !v.is_empty() => Mutex::new(v),
+        _ => Mutex::new(vec![usu_raíz]),
     };
 
     return usuarios;
@@ -0,0 +1,350 @@
+use chrono::{SecondsFormat, Utc};
+
+use rocket::http::Status;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::Mutex;
+use rocket::{Config, State};
+
+use std::net::IpAddr;
+
+use super::almacen::Almacenamiento;
+use super::auditoria::{Auditoría, Entrada};
+use super::condiciones::{Etiquetada, SiCoincide};
+use super::documentos::{Documento, Documentos};
+use super::eventos::{Evento, Eventos};
+use super::id::Id;
+use super::roles::{Administrador, Editor};
+use super::usuarios::Usuario;
+
+/*
+ * Versiones de la documentación
+ */
+
+// La documentación describe una versión del Lenguaje Ñ, pero cada usuario usa la suya. Una
+// versión es una copia completa del árbol de documentos con un nombre, como "0.1" o "0.2". Se
+// crea a partir de los documentos en desarrollo, que son los que se editan normalmente, o a
+// partir de otra versión. Mientras no se publica se puede seguir corrigiendo; una vez publicada
+// queda congelada.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Versión {
+    pub nombre: String,
+    // Versión de la que se ha sacado la copia
+    pub origen: String,
+    pub momento: String,
+    // Momento en que se publicó, si ya está publicada
+    pub publicación: Option<String>,
+    pub documentos: Vec<Documento>,
+}
+
+impl Versión {
+    fn publicada(&self) -> bool {
+        self.publicación.is_some()
+    }
+
+    fn resumen(&self) -> Value {
+        json!({
+            "nombre": self.nombre,
+            "origen": self.origen,
+            "momento": self.momento,
+            "publicada": self.publicada(),
+            "publicación": self.publicación,
+            "documentos": self.documentos.len(),
+        })
+    }
+}
+
+pub type Versiones = Mutex<Vec<Versión>>;
+
+// Alias de la última versión publicada
+pub const ESTABLE: &str = "estable";
+
+// Nombre con el que me refiero a los documentos en desarrollo
+pub fn desarrollo() -> String {
+    Config::figment()
+        .extract_inner::<String>("versiones.desarrollo")
+        .unwrap_or_else(|_| "en desarrollo".to_string())
+}
+
+// ¿Pide este selector los documentos en desarrollo?
+pub fn es_desarrollo(selector: Option<&str>) -> bool {
+    match selector {
+        None => true,
+        Some(nombre) => nombre == desarrollo(),
+    }
+}
+
+// Busca una versión guardada por su nombre, o la última publicada si piden la estable
+pub fn busca<'a>(versiones: &'a [Versión], selector: &str) -> Option<&'a Versión> {
+    if selector == ESTABLE {
+        return versiones
+            .iter()
+            .filter(|v| v.publicada())
+            .max_by(|a, b| a.publicación.cmp(&b.publicación));
+    }
+    versiones.iter().find(|v| v.nombre == selector)
+}
+
+pub async fn guarda_copia_versiones(
+    almacén: &Almacenamiento,
+    versiones: &[Versión],
+) -> Result<(), Status> {
+    almacén.guarda_versiones(versiones).map_err(|e| {
+        println!("No he podido guardar las versiones: {}", e);
+        Status::InternalServerError
+    })
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NuevaVersión {
+    nombre: String,
+    // Por defecto, los documentos en desarrollo
+    origen: Option<String>,
+}
+
+#[get("/versiones", format = "json")]
+async fn lee_versiones(versiones: &State<Versiones>) -> Value {
+    let versiones = versiones.lock().await;
+    let estable = busca(&versiones, ESTABLE).map(|v| v.nombre.clone());
+    let lista: Vec<Value> = versiones.iter().map(|v| v.resumen()).collect();
+
+    json!({
+        "estado": "ok",
+        "desarrollo": desarrollo(),
+        "estable": estable,
+        "versiones": lista
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/versiones", format = "json", data = "<nueva>")]
+async fn crea_versión(
+    nueva: Json<NuevaVersión>,
+    lista: &State<Documentos>,
+    versiones: &State<Versiones>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let nueva = nueva.into_inner();
+    let nombre = nueva.nombre.trim().to_string();
+    // Los nombres reservados se confundirían con los alias
+    if nombre.is_empty() || nombre == ESTABLE || nombre == desarrollo() {
+        return Err(Status::BadRequest);
+    }
+    let origen = nueva.origen.unwrap_or_else(desarrollo);
+    // La copia de desarrollo se toma antes de bloquear las versiones:
+    // restaura_copia bloquea los documentos y después las versiones
+    let copia = if es_desarrollo(Some(&origen)) {
+        Some(lista.lock().await.clone())
+    } else {
+        None
+    };
+
+    let mut versiones = versiones.lock().await;
+    if versiones.iter().any(|v| v.nombre == nombre) {
+        return Err(Status::Conflict);
+    }
+    let (origen, documentos) = if let Some(documentos) = copia {
+        (origen, documentos)
+    } else {
+        let versión = busca(&versiones, &origen).ok_or(Status::NotFound)?;
+        (versión.nombre.clone(), versión.documentos.clone())
+    };
+
+    let versión = Versión {
+        nombre: nombre.clone(),
+        origen,
+        momento: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        publicación: None,
+        documentos,
+    };
+    let resumen = versión.resumen();
+    versiones.push(versión);
+    if let Err(estado) = guarda_copia_versiones(almacén, &versiones).await {
+        versiones.pop();
+        return Err(estado);
+    }
+
+    auditoría.registra(
+        Entrada::nueva(
+            "crea_versión",
+            Some(&usuario),
+            format!("versiones/{}", nombre),
+            ip,
+        )
+        .después(resumen.clone()),
+    );
+
+    Ok(json!({ "estado": "ok", "versión": resumen }))
+}
+
+// Congela una versión: desde ese momento no se puede cambiar
+#[allow(clippy::too_many_arguments)]
+#[post("/versiones/<nombre>/publicación")]
+async fn publica_versión(
+    nombre: &str,
+    versiones: &State<Versiones>,
+    almacén: &State<Almacenamiento>,
+    eventos: &State<Eventos>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let mut versiones = versiones.lock().await;
+    let i = versiones
+        .iter()
+        .position(|v| v.nombre == nombre)
+        .ok_or(Status::NotFound)?;
+    if versiones[i].publicada() {
+        return Err(Status::Forbidden);
+    }
+    versiones[i].publicación = Some(Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    if let Err(estado) = guarda_copia_versiones(almacén, &versiones).await {
+        versiones[i].publicación = None;
+        return Err(estado);
+    }
+
+    auditoría.registra(
+        Entrada::nueva(
+            "publica_versión",
+            Some(&usuario),
+            format!("versiones/{}", nombre),
+            ip,
+        )
+        .después(versiones[i].resumen()),
+    );
+    // Los webhooks lo reciben como "versiones.publicada"
+    eventos.emite(Evento::publicación(nombre, &usuario));
+
+    Ok(json!({ "estado": "ok", "versión": versiones[i].resumen() }))
+}
+
+// Corrige el título o el contenido de un documento en una versión todavía sin publicar. La
+// estructura del árbol de una versión no cambia.
+#[allow(clippy::too_many_arguments)]
+#[patch(
+    "/versiones/<nombre>/documento/<id>",
+    format = "json",
+    data = "<documento>"
+)]
+async fn cambia_documento_de_versión(
+    nombre: &str,
+    id: Id,
+    documento: Json<Documento>,
+    versiones: &State<Versiones>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _editor: Editor,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+    si_coincide: SiCoincide,
+) -> Result<Etiquetada<Json<Documento>>, Status> {
+    let doc = documento.into_inner();
+    let mut versiones = versiones.lock().await;
+    let versión = versiones
+        .iter_mut()
+        .find(|v| v.nombre == nombre)
+        .ok_or(Status::NotFound)?;
+    if versión.publicada() {
+        return Err(Status::Forbidden);
+    }
+    let i = versión
+        .documentos
+        .iter()
+        .position(|d| d.id == id)
+        .ok_or(Status::NotFound)?;
+    si_coincide.comprueba(versión.documentos[i].revisión)?;
+    if doc.padre != versión.documentos[i].padre {
+        return Err(Status::Forbidden);
+    }
+
+    let anterior = versión.documentos[i].clone();
+    versión.documentos[i].título = doc.título;
+    versión.documentos[i].contenido = doc.contenido;
+    versión.documentos[i].revisión += 1;
+    let cambiado = versión.documentos[i].clone();
+    if let Err(estado) = guarda_copia_versiones(almacén, &versiones).await {
+        let versión = versiones.iter_mut().find(|v| v.nombre == nombre).unwrap();
+        versión.documentos[i] = anterior;
+        return Err(estado);
+    }
+
+    auditoría.registra(
+        Entrada::nueva(
+            "cambia_documento",
+            Some(&usuario),
+            format!("versiones/{}/documento/{}", nombre, id),
+            ip,
+        )
+        .antes(anterior.resumen())
+        .después(cambiado.resumen()),
+    );
+
+    Ok(Etiquetada::completa(cambiado.revisión, Json(cambiado)))
+}
+
+#[delete("/versiones/<nombre>")]
+async fn borra_versión(
+    nombre: &str,
+    versiones: &State<Versiones>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Status {
+    let mut versiones = versiones.lock().await;
+    let i = match versiones.iter().position(|v| v.nombre == nombre) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+    // Borrar una versión publicada cambiaría en silencio la que sirvo como estable
+    if versiones[i].publicada() {
+        return Status::Forbidden;
+    }
+    let borrada = versiones.remove(i);
+    if let Err(estado) = guarda_copia_versiones(almacén, &versiones).await {
+        versiones.insert(i, borrada);
+        return estado;
+    }
+
+    auditoría.registra(
+        Entrada::nueva(
+            "borra_versión",
+            Some(&usuario),
+            format!("versiones/{}", nombre),
+            ip,
+        )
+        .antes(borrada.resumen()),
+    );
+
+    Status::Accepted
+}
+
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> Versiones {
+    let versiones = almacén
+        .carga_versiones()
+        .unwrap_or_else(|e| panic!("No he podido cargar las versiones: {}", e))
+        .unwrap_or_default();
+    Mutex::new(versiones)
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![
+        lee_versiones,
+        crea_versión,
+        publica_versión,
+        cambia_documento_de_versión,
+        borra_versión
+    ]
+}
@@ -0,0 +1,298 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Config, Data, Request};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::almacen::Almacenamiento;
+use super::condiciones::avanza_revisiones;
+use super::contadores::Contadores;
+use super::documentos::{Documento, Documentos};
+use super::enlaces::ÍndiceEnlaces;
+use super::eventos::{Evento, Eventos};
+use super::integridad;
+use super::usuarios::{Usuario, Usuarios};
+
+/*
+ * Vigilancia de los datos guardados
+ */
+
+// Si alguien cambia los documentos o los usuarios guardados fuera del servidor, por ejemplo
+// editando "documentos.json" a mano, los recargo antes de atender la siguiente petición. Si los
+// datos nuevos no son válidos, los dejo como están y aviso: hasta que se corrijan, el servidor
+// se niega a sobrescribirlos y responde a los cambios con un 409.
+pub struct Vigilancia {
+    intervalo: Duration,
+    última_revisión: Mutex<Option<Instant>>,
+    // Huellas de los datos no válidos de los que ya he avisado, para no repetir el aviso
+    rechazadas: Mutex<HashMap<String, Option<String>>>,
+}
+
+impl Vigilancia {
+    pub fn nueva() -> Vigilancia {
+        // Segundos entre revisiones; 0 desactiva la vigilancia
+        let segundos = Config::figment()
+            .extract_inner::<u64>("almacen.vigilancia")
+            .unwrap_or(2);
+        Vigilancia {
+            intervalo: Duration::from_secs(segundos),
+            última_revisión: Mutex::new(None),
+            rechazadas: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn toca_revisar(&self) -> bool {
+        if self.intervalo.is_zero() {
+            return false;
+        }
+        let mut última = self.última_revisión.lock().unwrap();
+        let ahora = Instant::now();
+        if última.is_some_and(|ú| ahora.duration_since(ú) < self.intervalo) {
+            return false;
+        }
+        *última = Some(ahora);
+        true
+    }
+
+    // Aviso de unos datos no válidos sólo la primera vez que veo cada versión
+    fn avisa(&self, colección: &str, huella: &Option<String>, error: &str) {
+        let mut rechazadas = self.rechazadas.lock().unwrap();
+        if rechazadas.get(colección) != Some(huella) {
+            println!(
+                "Los {} guardados han cambiado fuera del servidor, pero no los recargo: {}",
+                colección, error
+            );
+            rechazadas.insert(colección.to_string(), huella.clone());
+        }
+    }
+}
+
+pub fn valida_documentos(documentos: &[Documento]) -> Result<(), String> {
+    if !documentos.iter().any(|d| d.id == 0) {
+        return Err("falta el documento raíz, con id 0".to_string());
+    }
+    let mut ids = HashSet::new();
+    for doc in documentos.iter() {
+        if !ids.insert(doc.id) {
+            return Err(format!("el id {} está repetido", doc.id));
+        }
+    }
+    Ok(())
+}
+
+pub fn valida_usuarios(usuarios: &[Usuario]) -> Result<(), String> {
+    let mut ids = HashSet::new();
+    let mut nombres = HashSet::new();
+    for usu in usuarios.iter() {
+        if !ids.insert(usu.id) {
+            return Err(format!("el id {} está repetido", usu.id));
+        }
+        if !nombres.insert(usu.nombre.as_str()) {
+            return Err(format!("el nombre \"{}\" está repetido", usu.nombre));
+        }
+    }
+    if usuarios.is_empty() {
+        return Err("no hay ningún usuario".to_string());
+    }
+    Ok(())
+}
+
+async fn recarga_documentos(
+    request: &Request<'_>,
+    almacén: &Almacenamiento,
+    vigilancia: &Vigilancia,
+) -> Result<(), String> {
+    // Tomo la huella antes de leer: si el archivo vuelve a cambiar mientras lo leo, lo
+    // notaré en la siguiente revisión
+    let huella = almacén.huella("documentos")?;
+    let nuevos = almacén
+        .carga_documentos()
+        .and_then(|d| d.ok_or_else(|| "no hay documentos guardados".to_string()))
+        .and_then(|d| valida_documentos(&d).map(|_| d));
+    let mut nuevos = match nuevos {
+        Ok(nuevos) => nuevos,
+        Err(e) => {
+            vigilancia.avisa("documentos", &huella, &e);
+            return Ok(());
+        }
+    };
+
+    let mut lista = request.rocket().state::<Documentos>().unwrap().lock().await;
+    avanza_revisiones(&lista, &mut nuevos);
+    *lista = nuevos;
+    almacén.huellas().anota("documentos", huella);
+    if let Some(contadores) = request.rocket().state::<Contadores>() {
+        contadores.respeta("documentos", lista.iter().map(|d| d.id));
+    }
+    if let Some(índice) = request.rocket().state::<ÍndiceEnlaces>() {
+        índice.actualiza(&lista);
+    }
+    if let Some(eventos) = request.rocket().state::<Eventos>() {
+        eventos.emite(Evento::recarga());
+    }
+    println!("He recargado los documentos, que han cambiado fuera del servidor");
+    integridad::informa(&lista);
+    Ok(())
+}
+
+async fn recarga_usuarios(
+    request: &Request<'_>,
+    almacén: &Almacenamiento,
+    vigilancia: &Vigilancia,
+) -> Result<(), String> {
+    let huella = almacén.huella("usuarios")?;
+    let nuevos = almacén
+        .carga_usuarios()
+        .and_then(|u| u.ok_or_else(|| "no hay usuarios guardados".to_string()))
+        .and_then(|u| valida_usuarios(&u).map(|_| u));
+    let mut nuevos = match nuevos {
+        Ok(nuevos) => nuevos,
+        Err(e) => {
+            vigilancia.avisa("usuarios", &huella, &e);
+            return Ok(());
+        }
+    };
+
+    let mut lista = request.rocket().state::<Usuarios>().unwrap().lock().await;
+    avanza_revisiones(&lista, &mut nuevos);
+    *lista = nuevos;
+    almacén.huellas().anota("usuarios", huella);
+    if let Some(contadores) = request.rocket().state::<Contadores>() {
+        contadores.respeta("usuarios", lista.iter().map(|u| u.id));
+    }
+    println!("He recargado los usuarios, que han cambiado fuera del servidor");
+    Ok(())
+}
+
+#[rocket::async_trait]
+impl Fairing for Vigilancia {
+    fn info(&self) -> Info {
+        Info {
+            name: "Recarga de los datos cambiados fuera del servidor",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _datos: &mut Data<'_>) {
+        if !self.toca_revisar() {
+            return;
+        }
+        let almacén = match request.rocket().state::<Almacenamiento>() {
+            Some(almacén) => almacén,
+            None => return,
+        };
+
+        if almacén.cambiado_fuera("documentos").unwrap_or(false) {
+            if let Err(e) = recarga_documentos(request, almacén, self).await {
+                println!("No he podido revisar los documentos: {}", e);
+            }
+        }
+        if almacén.cambiado_fuera("usuarios").unwrap_or(false) {
+            if let Err(e) = recarga_usuarios(request, almacén, self).await {
+                println!("No he podido revisar los usuarios: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+    use crate::almacen::{Almacén, AlmacénJson};
+    use crate::documentos;
+    use crate::enlaces;
+    use crate::id::Id;
+    use crate::temporal::Carpeta;
+    use rocket::http::Status;
+    use rocket::local::asynchronous::Client;
+
+    fn documento(id: Id, padre: Id, título: &str) -> Documento {
+        let mut documento = documentos::raíz();
+        documento.id = id;
+        documento.padre = padre;
+        documento.título = título.to_string();
+        documento
+    }
+
+    fn árbol(título: &str) -> Vec<Documento> {
+        let mut raíz = documentos::raíz();
+        raíz.hijos = vec![1];
+        vec![raíz, documento(1, 0, título)]
+    }
+
+    // Un servidor con los documentos guardados en una carpeta temporal, y un segundo almacén
+    // sobre la misma carpeta con el que los cambio "a mano"
+    async fn servidor(nombre: &str) -> (Client, AlmacénJson, Carpeta) {
+        let carpeta = Carpeta::nueva(&format!("vigilancia-{}", nombre));
+        let ruta = carpeta.ruta().to_str().unwrap();
+
+        let almacén: Almacenamiento = Box::new(AlmacénJson::nuevo(ruta, 1));
+        almacén.guarda_documentos(&árbol("Original")).unwrap();
+        let lista = documentos::prepara_estado_inicial(&almacén);
+        let rocket = rocket::build()
+            .manage(lista)
+            .manage(enlaces::prepara_estado_inicial(&[]))
+            .manage(almacén)
+            .attach(Vigilancia::nueva());
+        let cliente = Client::tracked(rocket).await.unwrap();
+        (cliente, AlmacénJson::nuevo(ruta, 1), carpeta)
+    }
+
+    fn lista(cliente: &Client) -> &Documentos {
+        cliente.rocket().state::<Documentos>().unwrap()
+    }
+
+    fn almacén(cliente: &Client) -> &Almacenamiento {
+        cliente.rocket().state::<Almacenamiento>().unwrap()
+    }
+
+    #[rocket::async_test]
+    async fn recarga_los_documentos_cambiados_fuera() {
+        let (cliente, fuera, _carpeta) = servidor("recarga").await;
+        fuera.guarda_documentos(&árbol("Cambiado a mano")).unwrap();
+
+        // La vigilancia revisa los datos antes de atender cualquier petición
+        cliente.get("/").dispatch().await;
+        {
+            let lista = lista(&cliente).lock().await;
+            assert_eq!(lista[1].título, "Cambiado a mano");
+            // La revisión avanza, para que las copias anteriores dejen de coincidir
+            assert_eq!(lista[1].revisión, 2);
+        }
+        // Ya he anotado lo que he recargado, así que puedo volver a guardar
+        assert!(
+            documentos::bloquea_para_cambiar(lista(&cliente), almacén(&cliente))
+                .await
+                .is_ok()
+        );
+    }
+
+    #[rocket::async_test]
+    async fn no_sobrescribe_los_cambios_no_válidos() {
+        let (cliente, fuera, _carpeta) = servidor("rechazo").await;
+        // Sin la raíz, los documentos no son válidos y no los recargo
+        fuera
+            .guarda_documentos(&[documento(1, 0, "Sin raíz")])
+            .unwrap();
+
+        cliente.get("/").dispatch().await;
+        assert_eq!(lista(&cliente).lock().await[1].título, "Original");
+
+        // Mientras tanto, cualquier cambio responde con un 409 y el archivo no se toca
+        let resultado = documentos::bloquea_para_cambiar(lista(&cliente), almacén(&cliente)).await;
+        assert_eq!(resultado.err(), Some(Status::Conflict));
+        let índice = cliente.rocket().state::<ÍndiceEnlaces>().unwrap();
+        let guardado = documentos::guarda_copia_documentos(
+            almacén(&cliente),
+            índice,
+            &árbol("Desde el servidor"),
+        )
+        .await;
+        assert_eq!(guardado, Err(Status::Conflict));
+        assert_eq!(
+            fuera.carga_documentos().unwrap().unwrap()[0].título,
+            "Sin raíz"
+        );
+    }
+}
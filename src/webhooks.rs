@@ -0,0 +1,674 @@
+use chrono::{SecondsFormat, Utc};
+
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha2::Sha256;
+
+use rand::thread_rng;
+use rand::Rng;
+
+use reqwest::Url;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Status;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::{Deserialize, Serialize};
+use rocket::tokio::sync::{broadcast::error::RecvError, mpsc, Mutex, Notify};
+use rocket::{Config, Orbit, Rocket, State};
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use super::almacen::{self, Almacenamiento};
+use super::auditoria::{Auditoría, Entrada};
+use super::contadores::Contadores;
+use super::eventos::Eventos;
+use super::id::Id;
+use super::roles::Administrador;
+use super::usuarios::Usuario;
+
+/*
+ * Webhooks
+ */
+
+// Los administradores suscriben direcciones a los cambios de documentos y usuarios. Cada
+// cambio se envía con un POST a las suscripciones interesadas, firmado con HMAC-SHA256 y el
+// secreto de la suscripción. Los envíos se hacen en segundo plano, y los fallidos se reintentan
+// esperando cada vez el doble. Cada suscripción guarda el registro de sus últimas entregas.
+
+// Entregas que conservo en el registro de cada suscripción
+const MÁXIMO_ENTREGAS: usize = 50;
+
+// Espera máxima entre dos intentos, en segundos, por mucho que se doble
+const ESPERA_MÁXIMA: u64 = 3600;
+
+// El registro de entregas cambia con cada intento, así que no lo guardo cada vez: agrupo los
+// cambios de este intervalo, en segundos, en una sola escritura
+const INTERVALO_REGISTRO: u64 = 5;
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Entrega {
+    pub id: String,
+    pub evento: String,
+    pub momento: String,
+    // "pendiente", "reintentando", "entregada" o "fallida"
+    pub estado: String,
+    pub intentos: u32,
+    pub código: Option<u16>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Suscripción {
+    pub id: Id,
+    pub url: String,
+    // Eventos que interesan, como "documento.creado". Admite "usuario.*" y "*".
+    pub eventos: Vec<String>,
+    pub secreto: String,
+    #[serde(default = "verdadero")]
+    pub activa: bool,
+    #[serde(default)]
+    pub entregas: Vec<Entrega>,
+}
+
+fn verdadero() -> bool {
+    true
+}
+
+impl Suscripción {
+    fn interesa(&self, evento: &str) -> bool {
+        self.eventos.iter().any(|e| {
+            e == "*"
+                || e == evento
+                || e.strip_suffix(".*")
+                    .is_some_and(|prefijo| evento.split('.').next() == Some(prefijo))
+        })
+    }
+
+    // La suscripción tal como la ve la API, sin el secreto
+    fn resumen(&self, con_entregas: bool) -> Value {
+        let mut resumen = json!({
+            "id": self.id,
+            "url": self.url,
+            "eventos": self.eventos,
+            "activa": self.activa,
+        });
+        if con_entregas {
+            resumen["entregas"] = json!(self.entregas);
+        }
+        resumen
+    }
+
+    fn anota(&mut self, entrega: Entrega) {
+        match self.entregas.iter_mut().find(|e| e.id == entrega.id) {
+            Some(anterior) => *anterior = entrega,
+            None => self.entregas.push(entrega),
+        }
+        if self.entregas.len() > MÁXIMO_ENTREGAS {
+            let sobran = self.entregas.len() - MÁXIMO_ENTREGAS;
+            self.entregas.drain(..sobran);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ConfigWebhooks {
+    // Intentos de cada entrega, contando el primero
+    intentos: u32,
+    // Segundos antes del primer reintento; luego se dobla en cada uno, hasta ESPERA_MÁXIMA
+    espera: u64,
+}
+
+// Segundos que espero después del intento número "intentos" antes del siguiente
+fn espera_tras(config: &ConfigWebhooks, intentos: u32) -> u64 {
+    2u64.checked_pow(intentos.saturating_sub(1))
+        .and_then(|factor| config.espera.checked_mul(factor))
+        .map_or(ESPERA_MÁXIMA, |espera| espera.min(ESPERA_MÁXIMA))
+}
+
+fn lee_configuración() -> ConfigWebhooks {
+    Config::figment()
+        .extract_inner::<ConfigWebhooks>("webhooks")
+        .unwrap_or(ConfigWebhooks {
+            intentos: 5,
+            espera: 2,
+        })
+}
+
+// Un cambio pendiente de repartir entre las suscripciones
+struct Aviso {
+    evento: String,
+    datos: Value,
+    // Para las entregas de prueba, que sólo van a una suscripción
+    sólo_a: Option<Id>,
+}
+
+pub struct Webhooks {
+    suscripciones: Arc<Mutex<Vec<Suscripción>>>,
+    canal: mpsc::UnboundedSender<Aviso>,
+    receptor: std::sync::Mutex<Option<mpsc::UnboundedReceiver<Aviso>>>,
+}
+
+impl Webhooks {
+    // Avisa de un cambio, por ejemplo "usuario.creado". Las entregas se hacen en segundo plano.
+    pub fn dispara(&self, evento: &str, datos: Value) {
+        self.envía(Aviso {
+            evento: evento.to_string(),
+            datos,
+            sólo_a: None,
+        });
+    }
+
+    pub async fn ids(&self) -> Vec<Id> {
+        self.suscripciones
+            .lock()
+            .await
+            .iter()
+            .map(|s| s.id)
+            .collect()
+    }
+
+    // Las suscripciones, con sus secretos, para las copias de seguridad
+    pub async fn suscripciones(&self) -> Vec<Suscripción> {
+        self.suscripciones.lock().await.clone()
+    }
+
+    // Sustituye las suscripciones por las de una copia de seguridad, después de guardarlas
+    pub async fn restaura(
+        &self,
+        almacén: &Almacenamiento,
+        suscripciones: Vec<Suscripción>,
+    ) -> Result<(), Status> {
+        let mut actuales = self.suscripciones.lock().await;
+        guarda(almacén, &suscripciones)?;
+        *actuales = suscripciones;
+        Ok(())
+    }
+
+    fn envía(&self, aviso: Aviso) {
+        // Sólo falla si el repartidor no está en marcha, y entonces no hay nada que hacer
+        let _ = self.canal.send(aviso);
+    }
+}
+
+fn símbolo_aleatorio(bytes: usize) -> String {
+    let mut aleatorio = vec![0u8; bytes];
+    thread_rng().fill(&mut aleatorio[..]);
+    hexadecimal(&aleatorio)
+}
+
+fn hexadecimal(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn firma(secreto: &str, cuerpo: &str) -> String {
+    let mut hmac = Hmac::new(Sha256::new(), secreto.as_bytes());
+    hmac.input(cuerpo.as_bytes());
+    hexadecimal(hmac.result().code())
+}
+
+fn guarda(almacén: &Almacenamiento, suscripciones: &[Suscripción]) -> Result<(), Status> {
+    almacén.guarda_suscripciones(suscripciones).map_err(|e| {
+        println!("No he podido guardar las suscripciones: {}", e);
+        Status::InternalServerError
+    })
+}
+
+/*
+ * Reparto y entrega
+ */
+
+// Reparte cada aviso entre las suscripciones interesadas
+async fn reparte(
+    mut receptor: mpsc::UnboundedReceiver<Aviso>,
+    suscripciones: Arc<Mutex<Vec<Suscripción>>>,
+    registro: Arc<Notify>,
+) {
+    while let Some(aviso) = receptor.recv().await {
+        let momento = Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut lista = suscripciones.lock().await;
+        for suscripción in lista.iter_mut() {
+            let destinada = match aviso.sólo_a {
+                Some(id) => suscripción.id == id,
+                None => suscripción.activa && suscripción.interesa(&aviso.evento),
+            };
+            if !destinada {
+                continue;
+            }
+            let entrega = Entrega {
+                id: símbolo_aleatorio(16),
+                evento: aviso.evento.clone(),
+                momento: momento.clone(),
+                estado: "pendiente".to_string(),
+                intentos: 0,
+                código: None,
+                error: None,
+            };
+            let cuerpo = json!({
+                "id": entrega.id,
+                "evento": aviso.evento,
+                "momento": momento,
+                "datos": aviso.datos,
+            })
+            .to_string();
+            suscripción.anota(entrega.clone());
+            registro.notify_one();
+            rocket::tokio::spawn(entrega_con_reintentos(
+                suscripción.clone(),
+                entrega,
+                cuerpo,
+                suscripciones.clone(),
+                registro.clone(),
+            ));
+        }
+    }
+}
+
+// Guarda el registro de entregas cuando cambia, como mucho una vez cada INTERVALO_REGISTRO.
+// Los avisos que llegan mientras espero se quedan en uno solo, así que una ráfaga de entregas
+// se guarda de una vez.
+async fn guarda_registro(
+    suscripciones: Arc<Mutex<Vec<Suscripción>>>,
+    almacén: Almacenamiento,
+    registro: Arc<Notify>,
+) {
+    loop {
+        registro.notified().await;
+        rocket::tokio::time::sleep(Duration::from_secs(INTERVALO_REGISTRO)).await;
+        let lista = suscripciones.lock().await;
+        if let Err(e) = almacén.guarda_suscripciones(&lista) {
+            println!("No he podido guardar el registro de entregas: {}", e);
+        }
+    }
+}
+
+async fn entrega_con_reintentos(
+    suscripción: Suscripción,
+    mut entrega: Entrega,
+    cuerpo: String,
+    suscripciones: Arc<Mutex<Vec<Suscripción>>>,
+    registro: Arc<Notify>,
+) {
+    let config = lee_configuración();
+    let cliente = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()
+        .unwrap();
+    let firma = firma(&suscripción.secreto, &cuerpo);
+
+    loop {
+        entrega.intentos += 1;
+        let resultado = cliente
+            .post(&suscripción.url)
+            .header("Content-Type", "application/json")
+            .header("X-Evento", &entrega.evento)
+            .header("X-Entrega", &entrega.id)
+            .header("X-Firma", format!("sha256={}", firma))
+            .body(cuerpo.clone())
+            .send()
+            .await;
+        let entregada = match resultado {
+            Ok(respuesta) => {
+                entrega.código = Some(respuesta.status().as_u16());
+                entrega.error = None;
+                respuesta.status().is_success()
+            }
+            Err(e) => {
+                entrega.código = None;
+                entrega.error = Some(e.to_string());
+                false
+            }
+        };
+        let último = entregada || entrega.intentos >= config.intentos;
+        entrega.estado = match (entregada, último) {
+            (true, _) => "entregada",
+            (false, true) => "fallida",
+            (false, false) => "reintentando",
+        }
+        .to_string();
+
+        {
+            let mut lista = suscripciones.lock().await;
+            // Si han borrado la suscripción mientras tanto, dejo de intentarlo
+            let actual = match lista.iter_mut().find(|s| s.id == suscripción.id) {
+                Some(actual) => actual,
+                None => return,
+            };
+            actual.anota(entrega.clone());
+            registro.notify_one();
+        }
+
+        if último {
+            return;
+        }
+        let espera = espera_tras(&config, entrega.intentos);
+        rocket::tokio::time::sleep(Duration::from_secs(espera)).await;
+    }
+}
+
+// Al despegar, pongo en marcha el repartidor y le paso los cambios de documentos, que ya se
+// anuncian por el canal de eventos
+pub struct Repartidor;
+
+#[rocket::async_trait]
+impl Fairing for Repartidor {
+    fn info(&self) -> Info {
+        Info {
+            name: "Entrega de webhooks",
+            kind: Kind::Liftoff,
+        }
+    }
+
+    async fn on_liftoff(&self, rocket: &Rocket<Orbit>) {
+        let webhooks = match rocket.state::<Webhooks>() {
+            Some(webhooks) => webhooks,
+            None => return,
+        };
+        let receptor = match webhooks.receptor.lock().unwrap().take() {
+            Some(receptor) => receptor,
+            None => return,
+        };
+        let almacén = match almacen::abre(&almacen::lee_configuración()) {
+            Ok(almacén) => almacén,
+            Err(e) => {
+                println!("No puedo entregar los webhooks: {}", e);
+                return;
+            }
+        };
+        let registro = Arc::new(Notify::new());
+        rocket::tokio::spawn(guarda_registro(
+            webhooks.suscripciones.clone(),
+            almacén,
+            registro.clone(),
+        ));
+        rocket::tokio::spawn(reparte(receptor, webhooks.suscripciones.clone(), registro));
+
+        if let Some(eventos) = rocket.state::<Eventos>() {
+            let mut cambios = eventos.suscribe();
+            let canal = webhooks.canal.clone();
+            rocket::tokio::spawn(async move {
+                loop {
+                    let evento = match cambios.recv().await {
+                        Ok(evento) => json!(evento),
+                        Err(RecvError::Lagged(_)) => json!({ "tipo": "recargado" }),
+                        Err(RecvError::Closed) => return,
+                    };
+                    // Las publicaciones son de una versión entera, no de un documento
+                    let nombre = match evento["tipo"].as_str().unwrap() {
+                        "publicada" => "versiones.publicada".to_string(),
+                        tipo => format!("documento.{}", tipo),
+                    };
+                    let aviso = Aviso {
+                        evento: nombre,
+                        datos: evento,
+                        sólo_a: None,
+                    };
+                    if canal.send(aviso).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+}
+
+pub fn prepara_estado_inicial(almacén: &Almacenamiento) -> Webhooks {
+    let guardadas = almacén
+        .carga_suscripciones()
+        .unwrap_or_else(|e| panic!("No he podido cargar las suscripciones: {}", e));
+    let (canal, receptor) = mpsc::unbounded_channel();
+    Webhooks {
+        suscripciones: Arc::new(Mutex::new(guardadas.unwrap_or_default())),
+        canal,
+        receptor: std::sync::Mutex::new(Some(receptor)),
+    }
+}
+
+/*
+ * Puntos de acceso de la API
+ */
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct DatosSuscripción {
+    url: String,
+    eventos: Vec<String>,
+    // Si no lo indico, genero uno
+    secreto: Option<String>,
+    activa: Option<bool>,
+}
+
+impl DatosSuscripción {
+    fn valida(&self) -> Result<(), Status> {
+        match Url::parse(&self.url) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err(Status::BadRequest),
+        }
+        if self.eventos.is_empty() {
+            return Err(Status::BadRequest);
+        }
+        Ok(())
+    }
+}
+
+#[get("/webhooks", format = "json")]
+async fn lee_suscripciones(
+    webhooks: &State<Webhooks>,
+    _usuario: Usuario,
+    _administrador: Administrador,
+) -> Value {
+    let lista = webhooks.suscripciones.lock().await;
+    let suscripciones: Vec<Value> = lista.iter().map(|s| s.resumen(false)).collect();
+
+    json!({ "estado": "ok", "suscripciones": suscripciones })
+}
+
+#[allow(clippy::too_many_arguments)]
+#[post("/webhooks", format = "json", data = "<datos>")]
+async fn crea_suscripción(
+    datos: Json<DatosSuscripción>,
+    webhooks: &State<Webhooks>,
+    almacén: &State<Almacenamiento>,
+    contadores: &State<Contadores>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let datos = datos.into_inner();
+    datos.valida()?;
+    let mut lista = webhooks.suscripciones.lock().await;
+    let suscripción = Suscripción {
+        id: contadores.nuevo_id(almacén, "suscripciones")?,
+        url: datos.url,
+        eventos: datos.eventos,
+        secreto: datos
+            .secreto
+            .filter(|s| !s.is_empty())
+            .unwrap_or_else(|| símbolo_aleatorio(32)),
+        activa: datos.activa.unwrap_or(true),
+        entregas: vec![],
+    };
+    let id = suscripción.id;
+    let resumen = suscripción.resumen(false);
+    // El secreto sólo se muestra al crear la suscripción
+    let respuesta = json!({
+        "estado": "ok",
+        "id": id,
+        "secreto": suscripción.secreto,
+    });
+    let mut nuevas = lista.clone();
+    nuevas.push(suscripción);
+    guarda(almacén, &nuevas)?;
+    *lista = nuevas;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "crea_webhook",
+            Some(&usuario),
+            format!("webhook/{}", id),
+            ip,
+        )
+        .después(resumen),
+    );
+
+    Ok(respuesta)
+}
+
+// Incluye el registro de las últimas entregas
+#[get("/webhooks/<id>", format = "json")]
+async fn lee_suscripción(
+    id: Id,
+    webhooks: &State<Webhooks>,
+    _usuario: Usuario,
+    _administrador: Administrador,
+) -> Result<Value, Status> {
+    let lista = webhooks.suscripciones.lock().await;
+    let suscripción = lista.iter().find(|s| s.id == id).ok_or(Status::NotFound)?;
+
+    Ok(suscripción.resumen(true))
+}
+
+#[allow(clippy::too_many_arguments)]
+#[patch("/webhooks/<id>", format = "json", data = "<datos>")]
+async fn cambia_suscripción(
+    id: Id,
+    datos: Json<DatosSuscripción>,
+    webhooks: &State<Webhooks>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Result<Value, Status> {
+    let datos = datos.into_inner();
+    datos.valida()?;
+    let mut lista = webhooks.suscripciones.lock().await;
+    let mut nuevas = lista.clone();
+    let suscripción = nuevas
+        .iter_mut()
+        .find(|s| s.id == id)
+        .ok_or(Status::NotFound)?;
+    let antes = suscripción.resumen(false);
+    suscripción.url = datos.url;
+    suscripción.eventos = datos.eventos;
+    if let Some(secreto) = datos.secreto.filter(|s| !s.is_empty()) {
+        suscripción.secreto = secreto;
+    }
+    if let Some(activa) = datos.activa {
+        suscripción.activa = activa;
+    }
+    let después = suscripción.resumen(false);
+    guarda(almacén, &nuevas)?;
+    *lista = nuevas;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "cambia_webhook",
+            Some(&usuario),
+            format!("webhook/{}", id),
+            ip,
+        )
+        .antes(antes)
+        .después(después.clone()),
+    );
+
+    Ok(después)
+}
+
+#[delete("/webhooks/<id>")]
+async fn borra_suscripción(
+    id: Id,
+    webhooks: &State<Webhooks>,
+    almacén: &State<Almacenamiento>,
+    usuario: Usuario,
+    _administrador: Administrador,
+    auditoría: &State<Auditoría>,
+    ip: Option<IpAddr>,
+) -> Status {
+    let mut lista = webhooks.suscripciones.lock().await;
+    let i = match lista.iter().position(|s| s.id == id) {
+        Some(i) => i,
+        None => return Status::NotFound,
+    };
+
+    let mut nuevas = lista.clone();
+    let borrada = nuevas.remove(i);
+    if let Err(estado) = guarda(almacén, &nuevas) {
+        return estado;
+    }
+    *lista = nuevas;
+
+    auditoría.registra(
+        Entrada::nueva(
+            "borra_webhook",
+            Some(&usuario),
+            format!("webhook/{}", id),
+            ip,
+        )
+        .antes(borrada.resumen(false)),
+    );
+
+    Status::Accepted
+}
+
+// Envía un evento "prueba" a la suscripción, aunque esté desactivada o no le interese. El
+// resultado aparece en su registro de entregas.
+#[post("/webhooks/<id>/prueba")]
+async fn prueba_suscripción(
+    id: Id,
+    webhooks: &State<Webhooks>,
+    usuario: Usuario,
+    _administrador: Administrador,
+) -> Result<Value, Status> {
+    if !webhooks.ids().await.contains(&id) {
+        return Err(Status::NotFound);
+    }
+    webhooks.envía(Aviso {
+        evento: "prueba".to_string(),
+        datos: json!({ "mensaje": "Entrega de prueba", "actor": usuario.nombre }),
+        sólo_a: Some(id),
+    });
+
+    Ok(json!({ "estado": "ok" }))
+}
+
+pub fn rutas() -> Vec<rocket::Route> {
+    routes![
+        lee_suscripciones,
+        crea_suscripción,
+        lee_suscripción,
+        cambia_suscripción,
+        borra_suscripción,
+        prueba_suscripción
+    ]
+}
+
+#[cfg(test)]
+mod pruebas {
+    use super::*;
+
+    #[test]
+    fn la_espera_se_dobla_sin_pasar_del_máximo() {
+        let config = ConfigWebhooks {
+            intentos: 100,
+            espera: 2,
+        };
+        let esperas: Vec<u64> = (1..=5).map(|i| espera_tras(&config, i)).collect();
+        assert_eq!(esperas, vec![2, 4, 8, 16, 32]);
+        assert_eq!(espera_tras(&config, 12), ESPERA_MÁXIMA);
+        // Con muchos intentos, la potencia no cabe en un u64
+        assert_eq!(espera_tras(&config, 64), ESPERA_MÁXIMA);
+        assert_eq!(espera_tras(&config, u32::MAX), ESPERA_MÁXIMA);
+
+        let config = ConfigWebhooks {
+            intentos: 100,
+            espera: u64::MAX,
+        };
+        assert_eq!(espera_tras(&config, 2), ESPERA_MÁXIMA);
+    }
+}